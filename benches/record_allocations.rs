@@ -0,0 +1,80 @@
+//! Compares how many allocations parsing a page of sync records takes with the hand written
+//! `ClassDataSync` deserialization against what serde derives
+//!
+//! run with `cargo bench --bench record_allocations`
+
+use classy_sync::data_stores::sync_requests::{AllSyncResult, SyncAction, TableName};
+use serde::Deserialize;
+use serde_json::Value;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::fs;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct DerivedSync {
+    table_name: TableName,
+    sync_action: SyncAction,
+    pk_fields: HashMap<String, Value>,
+    relevant_fields: Option<HashMap<String, Value>>,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct DerivedPage {
+    sync_data: Vec<DerivedSync>,
+}
+
+const ITERATIONS: usize = 20;
+
+fn measure<T>(name: &str, json: &str, parse: impl Fn(&str) -> T) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(parse(black_box(json)));
+    }
+    let elapsed = start.elapsed() / ITERATIONS as u32;
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / ITERATIONS;
+    let bytes = (ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes) / ITERATIONS;
+    println!("{name:<8} {allocations:>9} allocations {bytes:>11} bytes {elapsed:>10.2?} per page");
+}
+
+fn main() {
+    let json = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+    measure("derived", &json, |json| {
+        serde_json::from_str::<DerivedPage>(json).unwrap()
+    });
+    measure("visitor", &json, |json| {
+        serde_json::from_str::<AllSyncResult>(json).unwrap()
+    });
+}
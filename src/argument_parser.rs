@@ -1,4 +1,9 @@
-use std::collections::{HashMap, HashSet};
+use crate::data_stores::sync_requests::{SelectSync, TableName};
+use crate::errors::Error;
+use indexmap::{IndexMap, IndexSet};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::collections::HashSet;
+use std::str::FromStr;
 
 ///
 /// Sets the schools/ terms relevant for the action
@@ -7,68 +12,502 @@ use std::collections::{HashMap, HashSet};
 ///   or just the school itself
 /// ex: "marist;temple,202422"
 ///
+/// A school given with terms more than once syncs the terms of every entry while giving a school
+/// that is synced whole again or a term twice is an error
+///
+/// A run of consecutive terms is given by its first and last term joined by `..`
+/// ex: "marist,202440..202520" which is expanded by a `TermIdScheme`
+///
+/// Everything is synced with "all" which can be limited to some tables
+/// ex: "all:courses,professors"
+///
+/// As JSON these are `{"everything": true}`, `{"everything": ["courses", "professors"]}` or
+/// `{"select": {"marist": "all", "temple": ["202422"]}}`
 ///
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SyncResources {
-    Everything,
+    /// every school and term optionally only keeping the given tables
+    Everything(#[serde(with = "everything_tables")] Option<HashSet<TableName>>),
     Select(SelectSyncOptions),
 }
 
+/// `true` for every table or the tables to keep
+mod everything_tables {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Tables {
+        All(bool),
+        Only(HashSet<TableName>),
+    }
+
+    pub fn serialize<S: Serializer>(
+        tables: &Option<HashSet<TableName>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match tables {
+            None => serializer.serialize_bool(true),
+            Some(tables) => tables.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<HashSet<TableName>>, D::Error> {
+        match Tables::deserialize(deserializer)? {
+            Tables::All(true) => Ok(None),
+            Tables::All(false) => Err(de::Error::custom(
+                "`everything` is `true` or the tables to sync",
+            )),
+            Tables::Only(tables)
+                if tables
+                    .iter()
+                    .any(|table| matches!(table, TableName::Unknown(_))) =>
+            {
+                Err(de::Error::custom(
+                    "`everything` has a table that does not exist",
+                ))
+            }
+            Tables::Only(tables) => Ok(Some(tables)),
+        }
+    }
+}
+
+/// Parses a table given by a user which has to be one this version syncs
+pub fn parse_table(table: &str) -> Result<TableName, Error> {
+    match TableName::from_str(table) {
+        Ok(TableName::Unknown(_)) | Err(_) => Err(Error::InputParseError {
+            message: format!("Unknown table `{table}`"),
+        }),
+        Ok(table) => Ok(table),
+    }
+}
+
 impl SyncResources {
-    pub fn from_input(input: &str) -> SyncResources {
+    pub fn from_input(input: &str) -> Result<SyncResources, Error> {
         if input == "all" {
-            return SyncResources::Everything;
+            return Ok(SyncResources::Everything(None));
+        }
+        if let Some(tables) = input.strip_prefix("all:") {
+            let tables = tables
+                .split(",")
+                .map(|t| t.trim())
+                .map(parse_table)
+                .collect::<Result<_, _>>()?;
+            return Ok(SyncResources::Everything(Some(tables)));
         }
-        SyncResources::Select(SelectSyncOptions::from_input(input))
+        Ok(SyncResources::Select(SelectSyncOptions::from_input(input)?))
     }
 }
 
-#[derive(Debug)]
+/// `"all"` for the whole school or the list of its terms as JSON
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "Collection", try_from = "Collection")]
 pub enum CollectionType {
     AllSchoolData,
-    SelectTermData(HashSet<String>),
+    /// the terms in the order they were given
+    SelectTermData(IndexSet<String>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Collection {
+    All(String),
+    Terms(IndexSet<String>),
+}
+
+impl From<CollectionType> for Collection {
+    fn from(collection: CollectionType) -> Self {
+        match collection {
+            CollectionType::AllSchoolData => Collection::All("all".to_string()),
+            CollectionType::SelectTermData(terms) => Collection::Terms(terms),
+        }
+    }
 }
 
-#[derive(Debug)]
+impl TryFrom<Collection> for CollectionType {
+    type Error = String;
+
+    fn try_from(collection: Collection) -> Result<Self, Self::Error> {
+        match collection {
+            Collection::All(all) if all == "all" => Ok(CollectionType::AllSchoolData),
+            Collection::All(other) => Err(format!(
+                "a school syncs `\"all\"` or a list of terms but got `{other}`"
+            )),
+            Collection::Terms(terms) => Ok(CollectionType::SelectTermData(terms)),
+        }
+    }
+}
+
+/// ```
+/// use classy_sync::argument_parser::SelectSyncOptions;
+///
+/// let mut options = SelectSyncOptions::new();
+/// options
+///     .add_school("temple")
+///     .unwrap()
+///     .add_terms("marist", ["202440".to_string(), "202510".to_string()])
+///     .unwrap();
+/// assert!(options.add_school("marist").is_err());
+/// assert_eq!(
+///     options,
+///     SelectSyncOptions::from_input("temple;marist,202440,202510").unwrap()
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct SelectSyncOptions {
-    school_to_collection: HashMap<String, CollectionType>,
+    /// the schools in the order they were given
+    school_to_collection: IndexMap<String, CollectionType>,
 }
 
 impl SelectSyncOptions {
-    pub fn from_input(input: &str) -> SelectSyncOptions {
+    /// Options without any schools
+    pub fn new() -> SelectSyncOptions {
+        Self::default()
+    }
+
+    /// Syncs the whole school which fails when the school or any of its terms was already added
+    pub fn add_school(&mut self, school_id: &str) -> Result<&mut Self, Error> {
+        if self.school_to_collection.contains_key(school_id) {
+            return Err(Error::DuplicateSyncAddition {
+                message: format!("school_id `{school_id}` is already set"),
+            });
+        }
+        self.school_to_collection
+            .insert(school_id.to_string(), CollectionType::AllSchoolData);
+        Ok(self)
+    }
+
+    /// Syncs the terms of the school along with those already added for it
+    ///
+    /// Fails without adding any of them when the school is synced whole or one of the terms was
+    /// already added like `SelectSync::add_term_sync`
+    pub fn add_terms(
+        &mut self,
+        school_id: &str,
+        terms: impl IntoIterator<Item = String>,
+    ) -> Result<&mut Self, Error> {
+        let mut added = match self.school_to_collection.get(school_id) {
+            Some(CollectionType::AllSchoolData) => {
+                return Err(Error::DuplicateSyncAddition {
+                    message: format!("school_id `{school_id}` is already synced whole"),
+                });
+            }
+            Some(CollectionType::SelectTermData(existing)) => existing.clone(),
+            None => IndexSet::new(),
+        };
+        for term in terms {
+            if added.contains(&term) {
+                return Err(Error::DuplicateSyncAddition {
+                    message: format!("The term `{term}` of `{school_id}` already was set to sync"),
+                });
+            }
+            added.insert(term);
+        }
+        self.school_to_collection
+            .insert(school_id.to_string(), CollectionType::SelectTermData(added));
+        Ok(self)
+    }
+
+    /// Parses the input expanding term ranges by year and season
+    pub fn from_input(input: &str) -> Result<SelectSyncOptions, Error> {
+        Self::from_input_with(input, &YearSeasonTermIds::default())
+    }
+
+    /// Parses the input expanding term ranges with the given scheme
+    pub fn from_input_with(
+        input: &str,
+        term_ids: &dyn TermIdScheme,
+    ) -> Result<SelectSyncOptions, Error> {
         let schools_or_terms: Vec<String> = input.split(";").map(|s| s.to_string()).collect();
-        let mut school_to_collection: HashMap<String, CollectionType> = HashMap::new();
+        let mut options = SelectSyncOptions::new();
 
         for schoool_or_term in schools_or_terms.into_iter() {
             let school_and_maybe_term: Vec<&str> =
                 schoool_or_term.split(",").map(|s| s.trim()).collect();
             assert_ne!(school_and_maybe_term.len(), 0, "No school given?");
-            let school = school_and_maybe_term[0].to_string();
+            let school = school_and_maybe_term[0];
 
             // it is only the school
             if school_and_maybe_term.len() == 1 {
-                school_to_collection.insert(school.to_string(), CollectionType::AllSchoolData);
+                options.add_school(school)?;
                 continue;
             }
 
             // the rest of the comma separated entry are terms
-            school_to_collection.insert(
-                school.to_string(),
-                CollectionType::SelectTermData(
-                    school_and_maybe_term[1..]
-                        .iter()
-                        .map(|t| t.to_string())
-                        .collect(),
-                ),
-            );
-        }
-        SelectSyncOptions {
-            school_to_collection,
+            let mut terms = IndexSet::new();
+            for term in &school_and_maybe_term[1..] {
+                match term.split_once("..") {
+                    Some((start, end)) => terms.extend(term_ids.expand(start, end)?),
+                    None => {
+                        terms.insert(term.to_string());
+                    }
+                }
+            }
+            options.add_terms(school, terms)?;
         }
+        Ok(options)
     }
 
-    pub fn get_collections(&self) -> &HashMap<String, CollectionType> {
+    pub fn get_collections(&self) -> &IndexMap<String, CollectionType> {
         &self.school_to_collection
     }
+
+    /// The select sync of a store that has never synced these options with every sequence at 0
+    pub fn to_select_sync(&self) -> SelectSync {
+        let mut select_sync = SelectSync::with_capacity(self.school_to_collection.len());
+        for (school_id, collection) in &self.school_to_collection {
+            match collection {
+                CollectionType::AllSchoolData => select_sync
+                    .add_school_sync(school_id.clone(), 0)
+                    .expect("schools are unique in the options"),
+                CollectionType::SelectTermData(terms) => {
+                    for term in terms {
+                        select_sync
+                            .add_term_sync(school_id.clone(), term.clone(), 0)
+                            .expect("terms are unique in the options");
+                    }
+                }
+            }
+        }
+        select_sync
+    }
+}
+
+/// How a school's term ids are laid out so a range of them can be expanded
+///
+/// Term ids are chosen by each school so this is not something classy can know
+pub trait TermIdScheme {
+    /// Every term id from `start` to `end` inclusive in order
+    fn expand(&self, start: &str, end: &str) -> Result<Vec<String>, Error>;
+}
+
+/// Marist style `YYYYSS` term ids such as `202440` which are a year followed by a season code
+#[derive(Debug, Clone)]
+pub struct YearSeasonTermIds {
+    /// the codes of the seasons in the order they happen within a year
+    pub season_codes: Vec<String>,
+}
+
+impl Default for YearSeasonTermIds {
+    /// winter `10`, spring `20`, summer `30` and fall `40`
+    fn default() -> Self {
+        YearSeasonTermIds {
+            season_codes: ["10", "20", "30", "40"].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl YearSeasonTermIds {
+    /// The year and index of the season code of a term id
+    fn parse(&self, term_id: &str) -> Result<(u32, usize), Error> {
+        let malformed = || Error::InputParseError {
+            message: format!(
+                "term `{term_id}` is not a year followed by one of the season codes {:?}",
+                self.season_codes
+            ),
+        };
+        let (year, code) = term_id.split_at_checked(4).ok_or_else(malformed)?;
+        let year = year.parse().map_err(|_| malformed())?;
+        let season = self
+            .season_codes
+            .iter()
+            .position(|c| c == code)
+            .ok_or_else(malformed)?;
+        Ok((year, season))
+    }
+}
+
+impl TermIdScheme for YearSeasonTermIds {
+    fn expand(&self, start: &str, end: &str) -> Result<Vec<String>, Error> {
+        let (start, end) = (start.trim(), end.trim());
+        let first = self.parse(start)?;
+        let last = self.parse(end)?;
+        if first > last {
+            return Err(Error::InputParseError {
+                message: format!("term range `{start}..{end}` ends before it starts"),
+            });
+        }
+        let (mut year, mut season) = first;
+        let mut term_ids = vec![];
+        while (year, season) <= last {
+            term_ids.push(format!("{year}{}", self.season_codes[season]));
+            season += 1;
+            if season == self.season_codes.len() {
+                season = 0;
+                year += 1;
+            }
+        }
+        Ok(term_ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms_of(options: &SelectSyncOptions, school_id: &str) -> Vec<String> {
+        let Some(CollectionType::SelectTermData(terms)) = options.get_collections().get(school_id)
+        else {
+            panic!("expected terms of `{school_id}`");
+        };
+        terms.iter().cloned().collect()
+    }
+
+    #[test]
+    fn term_ranges() {
+        let options = SelectSyncOptions::from_input("marist,202440..202510;temple,202422").unwrap();
+        assert_eq!(terms_of(&options, "marist"), vec!["202440", "202510"]);
+        assert_eq!(terms_of(&options, "temple"), vec!["202422"]);
+
+        let scheme = YearSeasonTermIds::default();
+        assert_eq!(scheme.expand("202440", "202440").unwrap(), vec!["202440"]);
+        assert_eq!(scheme.expand("202420", "202520").unwrap().len(), 5);
+
+        // malformed ranges are input errors rather than panics
+        for input in ["marist,202510..202440", "marist,2024..fall"] {
+            assert!(SelectSyncOptions::from_input(input).is_err(), "{input}");
+            assert!(SyncResources::from_input(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn terms_keep_their_order() {
+        let options =
+            SelectSyncOptions::from_input("temple,202540,202422;marist,202540,202440,202540")
+                .unwrap();
+        assert_eq!(terms_of(&options, "temple"), vec!["202540", "202422"]);
+        assert_eq!(terms_of(&options, "marist"), vec!["202540", "202440"]);
+        assert_eq!(
+            serde_json::to_string(options.to_select_sync().get_schools()).unwrap(),
+            r#"{"temple":{"202540":0,"202422":0},"marist":{"202540":0,"202440":0}}"#
+        );
+    }
+
+    #[test]
+    fn built_matches_parsed() {
+        let mut built = SelectSyncOptions::new();
+        built
+            .add_school("yale")
+            .unwrap()
+            .add_terms("marist", ["202440".to_string()])
+            .unwrap()
+            .add_terms("marist", ["202510".to_string()])
+            .unwrap()
+            .add_school("temple")
+            .unwrap();
+        assert_eq!(
+            built,
+            SelectSyncOptions::from_input("yale;marist,202440,202510;temple").unwrap()
+        );
+        assert_eq!(
+            SelectSyncOptions::from_input("marist,202440;marist,202510").unwrap(),
+            SelectSyncOptions::from_input("marist,202440,202510").unwrap()
+        );
+        assert_ne!(built, SelectSyncOptions::new());
+        assert_eq!(SelectSyncOptions::new(), SelectSyncOptions::default());
+    }
+
+    #[test]
+    fn repeated_schools_are_errors() {
+        let mut built = SelectSyncOptions::new();
+        built
+            .add_school("yale")
+            .unwrap()
+            .add_terms("marist", ["202440".to_string()])
+            .unwrap();
+        let before = built.clone();
+        for result in [
+            built.add_school("yale").map(|_| ()),
+            built.add_school("marist").map(|_| ()),
+            built.add_terms("yale", ["202440".to_string()]).map(|_| ()),
+            built
+                .add_terms("marist", ["202510".to_string(), "202440".to_string()])
+                .map(|_| ()),
+        ] {
+            assert!(matches!(result, Err(Error::DuplicateSyncAddition { .. })));
+        }
+        // nothing is added by a failed addition
+        assert_eq!(built, before);
+
+        for input in [
+            "marist;marist",
+            "marist;marist,202440",
+            "marist,202440;marist,202440",
+        ] {
+            assert!(matches!(
+                SelectSyncOptions::from_input(input),
+                Err(Error::DuplicateSyncAddition { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn malformed_term_ranges() {
+        let scheme = YearSeasonTermIds::default();
+        for input in [
+            "marist,202540..202440",
+            "marist,202440..2025",
+            "marist,202445..202540",
+            "marist,fall..202540",
+        ] {
+            assert!(matches!(
+                SelectSyncOptions::from_input_with(input, &scheme),
+                Err(Error::InputParseError { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn sync_resources_json_round_trip() {
+        use serde_json::{from_value, json, to_value};
+
+        let cases = [
+            (
+                SyncResources::from_input("all").unwrap(),
+                json!({ "everything": true }),
+            ),
+            (
+                SyncResources::from_input("all:courses").unwrap(),
+                json!({ "everything": ["courses"] }),
+            ),
+            (
+                SyncResources::from_input("marist;temple,202422,202510").unwrap(),
+                json!({ "select": { "marist": "all", "temple": ["202422", "202510"] } }),
+            ),
+        ];
+        for (resources, value) in cases {
+            assert_eq!(to_value(&resources).unwrap(), value);
+            assert_eq!(from_value::<SyncResources>(value).unwrap(), resources);
+        }
+        let tables = SyncResources::from_input("all:courses,professors,sections").unwrap();
+        assert_eq!(
+            from_value::<SyncResources>(to_value(&tables).unwrap()).unwrap(),
+            tables
+        );
+
+        for invalid in [
+            json!({ "everything": false }),
+            json!({ "everything": ["not_a_table"] }),
+            json!({ "select": { "marist": "everything" } }),
+            json!({ "nothing": true }),
+        ] {
+            assert!(from_value::<SyncResources>(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_tables_are_input_errors() {
+        for input in ["all:coursez", "all:courses,unknown"] {
+            let err = SyncResources::from_input(input).unwrap_err();
+            assert!(
+                matches!(err, Error::InputParseError { .. }),
+                "{input}: {err}"
+            );
+        }
+    }
 }
@@ -0,0 +1,271 @@
+use crate::data_stores::replicate_datastore::{Datastore, SyncMode};
+use crate::data_stores::sync_report::{SyncReport, Warning};
+use crate::data_stores::sync_requests::{AllSyncResult, SelectSync, SyncOptions, TermSyncResult};
+use crate::errors::Error;
+use log::warn;
+use strum_macros::{Display, EnumString};
+
+/// What applying one page of a sync did and what is left to fetch
+#[derive(Debug)]
+pub struct AppliedPage {
+    pub report: SyncReport,
+    /// the options to request the next page with when the server has more
+    pub next: Option<SyncOptions>,
+}
+
+/// A page of a sync fetched by the caller for the options it was given
+#[derive(Debug)]
+pub enum FetchedResult {
+    All(AllSyncResult),
+    Select(TermSyncResult),
+}
+
+/// How a server that is behind the datastore is handled
+///
+/// This happens when the server was rolled back or restored from a backup which leaves the
+/// datastore asking for changes after a point the server has not reached
+#[derive(Debug, Default, Display, EnumString, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum SkewPolicy {
+    /// apply the page and add a warning to the report
+    #[default]
+    Warn,
+    /// fail the sync before applying the page
+    Error,
+}
+
+impl SkewPolicy {
+    /// Adds a warning for the skew to the report or fails with it
+    pub fn check(self, skew: Option<String>, report: &mut SyncReport) -> Result<(), Error> {
+        let Some(message) = skew else {
+            return Ok(());
+        };
+        match self {
+            SkewPolicy::Warn => {
+                warn!("The server is behind the datastore: {message}");
+                report.warnings.push(Warning::SequenceSkew { message });
+                Ok(())
+            }
+            SkewPolicy::Error => Err(Error::SequenceSkew { message }),
+        }
+    }
+}
+
+/// How sequences a select sync response has for schools that were not requested are handled
+///
+/// Either way they are left out of the bookkeeping so a server bug cannot move where later syncs
+/// of those schools start
+#[derive(Debug, Default, Display, EnumString, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum UnrequestedPolicy {
+    /// apply the page without them and add a warning to the report
+    #[default]
+    Warn,
+    /// fail the sync before applying the page
+    Error,
+}
+
+impl UnrequestedPolicy {
+    /// Adds a warning for the unrequested schools to the report or fails with them
+    pub fn check(self, unrequested: Option<String>, report: &mut SyncReport) -> Result<(), Error> {
+        let Some(message) = unrequested else {
+            return Ok(());
+        };
+        match self {
+            UnrequestedPolicy::Warn => {
+                warn!("The server sent sequences that were not requested: {message}");
+                report
+                    .warnings
+                    .push(Warning::UnrequestedSchools { message });
+                Ok(())
+            }
+            UnrequestedPolicy::Error => Err(Error::UnrequestedSchools { message }),
+        }
+    }
+}
+
+/// Applies an all sync result that was fetched without this crate's client
+///
+/// A server behind the datastore is handled with the default `SkewPolicy`
+pub fn apply_all_result(
+    data_store: &mut dyn Datastore,
+    result: AllSyncResult,
+) -> Result<AppliedPage, Error> {
+    expect_mode(data_store, SyncMode::All)?;
+    let mut skew = None;
+    if let SyncOptions::All(request) = data_store.generate_sync_options()? {
+        result.check_progress(request.last_sync)?;
+        skew = result.sequence_skew(request.last_sync);
+    }
+    let has_more = result.has_more;
+    let mut report = SyncReport::default();
+    SkewPolicy::default().check(skew, &mut report)?;
+    report.merge(data_store.execute_all_request_sync(result)?);
+    next_page(data_store, report, has_more)
+}
+
+/// Applies a select sync result that was fetched without this crate's client
+///
+/// `request` must be the options the result was requested with, skew and unrequested schools are
+/// handled with the default policies
+pub fn apply_select_result(
+    data_store: &mut dyn Datastore,
+    request: SelectSync,
+    result: TermSyncResult,
+) -> Result<AppliedPage, Error> {
+    expect_mode(data_store, SyncMode::Select)?;
+    result.check_progress(&request)?;
+    let has_more = result.any_has_more;
+    let mut report = SyncReport::default();
+    SkewPolicy::default().check(result.sequence_skew(&request), &mut report)?;
+    UnrequestedPolicy::default().check(result.unrequested_schools(&request), &mut report)?;
+    report.merge(data_store.execute_select_request_sync(request, result)?);
+    next_page(data_store, report, has_more)
+}
+
+/// Applies every page `fetch` returns for the options it is given until the server has no more
+///
+/// This is the whole sync for callers with their own transport or authentication
+pub fn apply_with<F>(data_store: &mut dyn Datastore, mut fetch: F) -> Result<SyncReport, Error>
+where
+    F: FnMut(&SyncOptions) -> Result<FetchedResult, Error>,
+{
+    let mut report = SyncReport::default();
+    let mut options = Some(data_store.generate_sync_options()?);
+    while let Some(current) = options {
+        let applied = match (fetch(&current)?, current) {
+            (FetchedResult::All(result), SyncOptions::All(_)) => {
+                apply_all_result(data_store, result)?
+            }
+            (FetchedResult::Select(result), SyncOptions::Select(request)) => {
+                apply_select_result(data_store, request, result)?
+            }
+            (_, current) => {
+                return Err(Error::ConflictingSyncMode {
+                    message: format!("fetched a result of another kind of sync for {current:?}"),
+                });
+            }
+        };
+        report.merge(applied.report);
+        options = applied.next;
+    }
+    Ok(report)
+}
+
+fn expect_mode(data_store: &mut dyn Datastore, expected: SyncMode) -> Result<(), Error> {
+    let mode = data_store.sync_mode()?;
+    if mode != expected {
+        return Err(Error::ConflictingSyncMode {
+            message: format!("cannot apply a {expected} sync result to a {mode} datastore"),
+        });
+    }
+    Ok(())
+}
+
+fn next_page(
+    data_store: &mut dyn Datastore,
+    report: SyncReport,
+    has_more: bool,
+) -> Result<AppliedPage, Error> {
+    let next = if has_more {
+        Some(data_store.generate_sync_options()?)
+    } else {
+        None
+    };
+    Ok(AppliedPage { report, next })
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::argument_parser::SyncResources;
+    use crate::data_stores::sqlite::{Sqlite, storage::SqliteConfig};
+    use serde_json::from_str;
+    use std::fs;
+
+    fn fixture<T: serde::de::DeserializeOwned>(path: &str) -> T {
+        from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn apply_fetched_all_pages() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let pages = ["01", "02", "03", "04", "05"];
+        let mut record_count = 0;
+        let mut requested = vec![];
+        let report = apply_with(&mut sqlite, |options| {
+            let SyncOptions::All(all_sync) = options else {
+                panic!("expected all sync options but got {options:?}");
+            };
+            requested.push(all_sync.last_sync);
+            let page = pages[requested.len() - 1];
+            let mut result: AllSyncResult =
+                fixture(&format!("test-syncs/maristfall2024/{page}.json"));
+            result.has_more = requested.len() < pages.len();
+            record_count += result.len();
+            Ok(FetchedResult::All(result))
+        })
+        .unwrap();
+
+        assert_eq!(requested, vec![0, 6303, 6698, 7530, 8221]);
+        assert_eq!(report.applied, record_count);
+        assert!(report.skipped.is_empty());
+    }
+
+    #[test]
+    fn policies_warn_or_fail() {
+        let mut report = SyncReport::default();
+        SkewPolicy::Warn.check(None, &mut report).unwrap();
+        UnrequestedPolicy::Warn.check(None, &mut report).unwrap();
+        assert!(report.warnings.is_empty());
+
+        SkewPolicy::Warn
+            .check(Some("behind".to_string()), &mut report)
+            .unwrap();
+        UnrequestedPolicy::Warn
+            .check(Some("`vassar`".to_string()), &mut report)
+            .unwrap();
+        assert!(matches!(
+            report.warnings.as_slice(),
+            [
+                Warning::SequenceSkew { .. },
+                Warning::UnrequestedSchools { .. }
+            ]
+        ));
+        assert!(matches!(
+            SkewPolicy::Error.check(Some("behind".to_string()), &mut report),
+            Err(Error::SequenceSkew { .. })
+        ));
+        assert!(matches!(
+            UnrequestedPolicy::Error.check(Some("`vassar`".to_string()), &mut report),
+            Err(Error::UnrequestedSchools { .. })
+        ));
+        assert_eq!(report.warnings.len(), 2);
+    }
+
+    #[test]
+    fn apply_fetched_select_page() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        let SyncOptions::Select(request) = sqlite.generate_sync_options().unwrap() else {
+            panic!("expected select sync options");
+        };
+        let result: TermSyncResult = fixture("test-syncs/maristterms/202440.json");
+        let record_count = result.len();
+
+        let applied = apply_select_result(&mut sqlite, request, result).unwrap();
+        assert_eq!(applied.report.applied, record_count);
+        assert!(applied.next.is_none());
+
+        let result: AllSyncResult = fixture("test-syncs/maristfall2024/01.json");
+        assert!(matches!(
+            apply_all_result(&mut sqlite, result),
+            Err(Error::ConflictingSyncMode { .. })
+        ));
+    }
+}
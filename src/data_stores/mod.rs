@@ -1,5 +1,8 @@
+pub mod apply;
 pub mod replicate_datastore;
+pub mod sync_report;
 pub mod sync_requests;
+pub mod tee;
 
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
@@ -3,16 +3,42 @@ use crate::{
     data_stores::sync_requests::{School, Term},
 };
 
-use super::sync_requests::{AllSyncResult, SelectSync, SyncOptions, TermSyncResult};
+use super::sync_report::{SyncReport, Warning};
+use super::sync_requests::{
+    AllSyncResult, ClassDataSync, SelectSync, SyncOptions, TableName, TermSyncResult,
+};
 use crate::errors::DataStoreError;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use strum_macros::{Display, EnumString};
+
+/// Which kind of sync a datastore has been set up for
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum SyncMode {
+    /// no sync resources have been set yet
+    Unset,
+    All,
+    Select,
+    /// the datastore is somehow set up for both all and select syncs
+    Dirty,
+}
+
+/// How many rows applying a record changed
+pub type AffectedRows = usize;
+
+/// Called with each record a datastore applied once it is committed
+pub type RecordHook = Box<dyn FnMut(&ClassDataSync, AffectedRows)>;
 
 /// Datastores may choose to make it possible to have all syncs / schools syncs /term syncs work
 /// with each other, but they may also choose to make some of them mutaully exclusive
 pub trait Datastore {
+    /// Sets what later syncs request returning what was already set
     fn set_request_sync_resources(
         &mut self,
         resources: SyncResources,
-    ) -> Result<(), DataStoreError>;
+    ) -> Result<Vec<Warning>, DataStoreError>;
 
     fn unset_request_sync_resources(
         &mut self,
@@ -21,47 +47,328 @@ pub trait Datastore {
 
     fn generate_sync_options(&mut self) -> Result<SyncOptions, DataStoreError>;
 
+    fn sync_mode(&mut self) -> Result<SyncMode, DataStoreError>;
+
+    /// How many schools and terms are set to be synced by select syncs
+    fn count_pending_strategies(&mut self) -> Result<usize, DataStoreError>;
+
+    /// Starts holding every page applied until `finish_sync` so a sync can be kept or discarded
+    /// as a whole while a page that fails is still rolled back on its own
+    ///
+    /// Backends that cannot hold pages apply each of them as they arrive
+    fn begin_sync(&mut self) -> Result<(), DataStoreError> {
+        Ok(())
+    }
+
+    /// Keeps or discards every page applied since `begin_sync`
+    fn finish_sync(&mut self, commit: bool) -> Result<(), DataStoreError> {
+        let _ = commit;
+        Ok(())
+    }
+
     fn execute_all_request_sync(
         &mut self,
         all_sync_response: AllSyncResult,
-    ) -> Result<(), DataStoreError>;
+    ) -> Result<SyncReport, DataStoreError>;
 
     fn execute_select_request_sync(
         &mut self,
         select_sync_request: SelectSync,
         select_sync_response: TermSyncResult,
-    ) -> Result<(), DataStoreError>;
+    ) -> Result<SyncReport, DataStoreError>;
+
+    /// Adds the schools that do not exist yet returning the ids of those that already existed
+    fn add_schools(&mut self, schools: Vec<School>) -> Result<Vec<String>, DataStoreError>;
+
+    /// Adds the terms that do not exist yet returning the `(school_id, id)` of those that already
+    /// existed
+    fn add_terms(&mut self, terms: Vec<Term>) -> Result<Vec<(String, String)>, DataStoreError>;
+
+    /// Removes sync bookkeeping from before the given sequence returning how much was removed
+    ///
+    /// The latest bookkeeping for each school and term is always kept so later syncs continue
+    /// from the same place
+    fn prune_before(&mut self, sequence: u64) -> Result<usize, DataStoreError> {
+        let _ = sequence;
+        Ok(0)
+    }
+
+    /// Sets what is called with every record once it is applied and committed or removes it
+    ///
+    /// Records that are rolled back such as those of a failed page never reach the hook
+    fn set_record_hook(&mut self, hook: Option<RecordHook>) -> Result<(), DataStoreError> {
+        let _ = hook;
+        Err(DataStoreError::other(
+            "this datastore does not support record hooks",
+        ))
+    }
+
+    /// Clears a table so the next syncs fetch it again from the start
+    ///
+    /// Only the given table is brought back from the syncs before it was cleared, every other
+    /// table keeps what it already has
+    fn resync_table(&mut self, table: TableName) -> Result<(), DataStoreError>;
+
+    /// The row of a table with the given primary key as column to value
+    fn get_row(
+        &mut self,
+        table: TableName,
+        pk: &HashMap<String, Value>,
+    ) -> Result<Option<HashMap<String, Value>>, DataStoreError>;
+
+    /// Runs a single statement that cannot change the datastore returning its rows
+    ///
+    /// This is for looking at the synced data by hand so statements that would write fail
+    fn read_only_query(&mut self, sql: &str) -> Result<QueryRows, DataStoreError> {
+        let _ = sql;
+        Err(DataStoreError::other(
+            "this datastore does not support queries",
+        ))
+    }
+
+    /// How the backing store would run the statement applying each distinct shape of the records
+    /// without applying them
+    ///
+    /// This is for finding out whether slow syncs look rows up by an index
+    fn explain(&mut self, records: Vec<ClassDataSync>) -> Result<Vec<QueryPlan>, DataStoreError> {
+        let _ = records;
+        Err(DataStoreError::other(
+            "this datastore does not support explaining its statements",
+        ))
+    }
+
+    /// Every row of the bookkeeping behind the sequences syncs request by the name of its table
+    ///
+    /// This is for finding out why a sync requests what it does
+    fn bookkeeping(&mut self) -> Result<Vec<(String, QueryRows)>, DataStoreError> {
+        Err(DataStoreError::other(
+            "this datastore does not support listing its bookkeeping",
+        ))
+    }
+
+    /// The latest schema migration applied to the backing store or 0 before they were versioned
+    fn schema_version(&mut self) -> Result<u32, DataStoreError>;
+
+    /// The schema version this build migrates new backing stores to
+    ///
+    /// A `schema_version` behind this is missing migrations
+    fn expected_schema_version(&mut self) -> Result<u32, DataStoreError> {
+        self.schema_version()
+    }
+
+    /// Whether what is stored outlives this datastore such as a database file unlike an in memory
+    /// database
+    fn is_persistent(&mut self) -> Result<bool, DataStoreError> {
+        Ok(true)
+    }
+
+    /// What the datastore is and where it keeps its data
+    fn describe(&mut self) -> Result<DatastoreInfo, DataStoreError>;
 
-    fn add_schools(&mut self, schools: Vec<School>) -> Result<(), DataStoreError>;
+    /// Checks the backing store for corruption returning whether it is sound
+    ///
+    /// Backends without such a concept are always considered sound
+    fn integrity_check(&mut self) -> Result<bool, DataStoreError> {
+        Ok(true)
+    }
+
+    /// Makes every applied sync durable for backends that buffer their writes
+    fn flush(&mut self) -> Result<(), DataStoreError> {
+        Ok(())
+    }
+}
+
+/// What a read only query returned
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryRows {
+    pub columns: Vec<String>,
+    /// the values of each row in the order of the columns
+    pub rows: Vec<Vec<Value>>,
+}
+
+/// How a statement applying records would be run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryPlan {
+    pub statement: String,
+    /// each step of the plan in the order it is run
+    pub steps: Vec<String>,
+}
+
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.statement)?;
+        for step in &self.steps {
+            write!(f, "\n  {step}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A description of a datastore for status output and bug reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatastoreInfo {
+    pub backend: &'static str,
+    /// where the data is kept when it is kept in a file
+    pub path: Option<String>,
+    pub in_memory: bool,
+    pub schema_version: u32,
+}
+
+impl fmt::Display for DatastoreInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Backend: {}", self.backend)?;
+        match (&self.path, self.in_memory) {
+            (_, true) => write!(f, "\nLocation: in memory")?,
+            (Some(path), false) => write!(f, "\nLocation: {path}")?,
+            (None, false) => {}
+        }
+        write!(f, "\nSchema version: {}", self.schema_version)
+    }
+}
 
-    fn add_terms(&mut self, terms: Vec<Term>) -> Result<(), DataStoreError>;
+/// The kinds of datastore that can be chosen at runtime
+///
+/// Each is only available when its feature was enabled at compile time
+#[derive(Debug, Default, Display, EnumString, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Sqlite,
+    /// a sqlite database that is only kept in memory for the run ignoring `db_path`
+    Memory,
 }
 
-/// gets the datastore that is selected as per the first feature
-pub fn get_datastore() -> Result<Box<dyn Datastore>, DataStoreError> {
+impl Backend {
+    /// Whether the backend's feature was enabled at compile time
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            Backend::Sqlite | Backend::Memory => cfg!(feature = "sqlite"),
+        }
+    }
+}
+
+/// Backend agnostic options for the datastore created by `get_datastore`
+#[derive(Debug, Default)]
+pub struct DatastoreOptions {
+    /// which kind of datastore to create
+    pub backend: Backend,
+    /// skip records that fail to apply (reporting them) instead of aborting the whole sync
+    pub continue_on_error: bool,
+    /// ignore delete records so rows the server removed are kept
+    pub skip_deletes: bool,
+    /// prepended to the name of every table the datastore creates
+    pub table_prefix: String,
+    /// roll back applied sync responses instead of committing them
+    pub no_commit: bool,
+    /// where the sqlite database is kept which takes precedence over `SQLITE_DB_PATH`
+    pub db_path: Option<String>,
+    /// fail fast when another run is using the same database file
+    pub lock: bool,
+    /// stop syncing terms once they are no longer collecting
+    pub unset_finished_terms: bool,
+    /// build lookup indexes once an initial all sync into an empty datastore is applied
+    pub defer_indexes: bool,
+    /// open an existing datastore without changing it for commands that only read
+    pub read_only: bool,
+    /// fail records that change an unexpected number of rows instead of warning about them
+    pub is_strict: bool,
+    /// fail on records of tables this version does not know instead of skipping them
+    pub strict_tables: bool,
+}
+
+/// gets the datastore of the backend chosen in the options
+///
+/// building without any backend feature is a compile error
+pub fn get_datastore(options: DatastoreOptions) -> Result<Box<dyn Datastore>, DataStoreError> {
+    if !options.backend.is_enabled() {
+        return Err(DataStoreError::other(format!(
+            "the `{}` backend was not enabled at compile time",
+            options.backend
+        )));
+    }
+    match options.backend {
+        Backend::Sqlite => sqlite_datastore(options),
+        // nothing else can use the database so there is nothing to lock
+        Backend::Memory => sqlite_datastore(DatastoreOptions {
+            db_path: Some(":memory:".to_string()),
+            lock: false,
+            ..options
+        }),
+    }
+}
+
+fn sqlite_datastore(options: DatastoreOptions) -> Result<Box<dyn Datastore>, DataStoreError> {
     #[cfg(feature = "sqlite")]
     {
         use log::warn;
         use std::env;
 
-        let db_path = env::var("SQLITE_DB_PATH").ok();
+        let db_path = options.db_path.or_else(|| env::var("SQLITE_DB_PATH").ok());
 
         if db_path.is_none() {
-            warn!("Using an in memory database because env varible SQLITE_DB_PATH is not found")
+            warn!(
+                "Using an in memory database because env varible SQLITE_DB_PATH is not found, set it to `:memory:` to silence this"
+            )
         }
 
         let config = super::sqlite::storage::SqliteConfig {
             db_path,
-            // TODO: add this to config
-            is_strict: false,
+            is_strict: options.is_strict,
+            continue_on_error: options.continue_on_error,
+            skip_deletes: options.skip_deletes,
+            table_prefix: options.table_prefix,
+            no_commit: options.no_commit,
+            lock: options.lock,
+            unset_finished_terms: options.unset_finished_terms,
+            defer_indexes: options.defer_indexes,
+            read_only: options.read_only,
+            strict_tables: options.strict_tables,
             ..Default::default()
         };
 
-        return Ok(Box::new(super::sqlite::Sqlite::new(config)?));
+        Ok(Box::new(super::sqlite::Sqlite::new(config)?))
     }
 
-    #[allow(unreachable_code)]
+    #[cfg(not(feature = "sqlite"))]
     {
-        unreachable!("A data store backend feature must be enabled at compile time.")
+        let _ = options;
+        unreachable!("only enabled backends are created")
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_chosen_at_runtime() {
+        let db_path = std::env::temp_dir().join(format!(
+            "classy-sync-backends-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&db_path);
+        let describe = |backend: &str| {
+            let backend: Backend = backend.parse().unwrap();
+            assert!(backend.is_enabled());
+            get_datastore(DatastoreOptions {
+                backend,
+                db_path: Some(db_path.to_string_lossy().into_owned()),
+                ..Default::default()
+            })
+            .unwrap()
+            .describe()
+            .unwrap()
+        };
+
+        let memory = describe("memory");
+        assert!(memory.in_memory);
+        assert!(!db_path.exists());
+
+        let sqlite = describe("sqlite");
+        assert_eq!(sqlite.backend, "sqlite");
+        assert!(!sqlite.in_memory);
+        assert!(db_path.exists());
+        assert!("postgres".parse::<Backend>().is_err());
+        let _ = std::fs::remove_file(&db_path);
     }
 }
@@ -31,6 +31,28 @@ pub enum SqliteError {
     #[error("Unsupported sync operation: {0}")]
     UnsupportedSyncOperation(String),
 
+    #[error("Database is in use by another run (process {holder}) holding `{lock_path}`")]
+    DatabaseLocked { lock_path: String, holder: String },
+
+    #[error("Applying the sync took longer than {timeout:?} so it was rolled back")]
+    ApplyTimeout { timeout: std::time::Duration },
+
+    #[error("Nothing is set to sync, run `set <sync instructions>` or `sync --init` first")]
+    NoSyncResources,
+
+    #[error(
+        "Database is missing the tables {} even though `{migration}` is recorded as applied, restore them or sync into a new database",
+        .tables.join(", ")
+    )]
+    MissingTables {
+        tables: Vec<String>,
+        migration: String,
+    },
+
     #[error("Data Integrity Error: {0}")]
     DataIntegrityError(String),
+
+    /// every conflict of a set of sync resources so they can all be fixed at once
+    #[error("Conflicting sync resources: {}", .0.join("; "))]
+    ConflictingSyncResources(Vec<String>),
 }
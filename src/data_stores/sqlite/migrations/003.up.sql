@@ -0,0 +1,4 @@
+CREATE TABLE _sync_meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
@@ -0,0 +1,4 @@
+CREATE TABLE _schema_migrations (
+    version INTEGER PRIMARY KEY,
+    applied_at TEXT DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
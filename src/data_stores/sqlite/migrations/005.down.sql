@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS meeting_times_by_section;
+DROP INDEX IF EXISTS sections_by_professor;
+DROP INDEX IF EXISTS sections_by_course;
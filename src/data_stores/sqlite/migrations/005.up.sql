@@ -0,0 +1,6 @@
+-- the primary keys of sections and meeting times start with their sequence so looking them up
+-- by what they reference such as for cascading deletes would scan the whole table
+CREATE INDEX IF NOT EXISTS sections_by_course ON sections (school_id, subject_code, course_number);
+CREATE INDEX IF NOT EXISTS sections_by_professor ON sections (primary_professor_id, school_id);
+CREATE INDEX IF NOT EXISTS meeting_times_by_section
+    ON meeting_times (section_sequence, term_collection_id, school_id, subject_code, course_number);
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS previous_term_collections_by_sequence;
+DROP INDEX IF EXISTS previous_school_collections_by_sequence;
+DROP INDEX IF EXISTS previous_all_collections_by_sequence;
+DROP INDEX IF EXISTS previous_term_collections_by_term;
+DROP INDEX IF EXISTS previous_school_collections_by_school;
@@ -0,0 +1,13 @@
+-- the bookkeeping grows with every sync and is joined against the strategies to generate each
+-- select sync, _school_strategies is already indexed by its unique constraint
+CREATE INDEX IF NOT EXISTS previous_school_collections_by_school
+    ON _previous_school_collections (school_id, synced_at);
+CREATE INDEX IF NOT EXISTS previous_term_collections_by_term
+    ON _previous_term_collections (school_id, term_collection_id, synced_at);
+-- pruning and the latest all sync look the bookkeeping up by its sequence
+CREATE INDEX IF NOT EXISTS previous_all_collections_by_sequence
+    ON _previous_all_collections (synced_at);
+CREATE INDEX IF NOT EXISTS previous_school_collections_by_sequence
+    ON _previous_school_collections (synced_at);
+CREATE INDEX IF NOT EXISTS previous_term_collections_by_sequence
+    ON _previous_term_collections (synced_at);
@@ -1,213 +1,1087 @@
 use crate::argument_parser::{CollectionType, SyncResources};
-use crate::data_stores::replicate_datastore::Datastore;
+use crate::data_stores::replicate_datastore::{
+    AffectedRows, Datastore, DatastoreInfo, QueryPlan, QueryRows, RecordHook, SyncMode,
+};
 use crate::data_stores::sqlite::errors::SqliteError;
+use crate::data_stores::sync_report::{ExpectedRows, SkippedRecord, SyncReport, Warning};
 use crate::data_stores::sync_requests::{
-    self, AllSync, AllSyncResult, ClassDataSync, SelectSync, SyncAction, SyncOptions,
-    TermSyncResult,
+    self, AllSync, AllSyncResult, ClassDataSync, IdentifierQuoting, SelectSync, SyncAction,
+    SyncOptions, TableName, TermSyncResult,
 };
 use crate::errors::DataStoreError; // Keep this import for the Datastore trait
-use log::{trace, warn};
-use rusqlite::{Connection, Transaction, params_from_iter};
+use lazy_static::lazy_static;
+use log::{info, trace, warn};
+use regex::Regex;
+use rusqlite::{
+    Connection, DropBehavior, OpenFlags, OptionalExtension, Savepoint, params_from_iter,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::Value;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::result::Result;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const DEFAULT_MAX_RECORDS: u16 = 10_000;
+const ALL_SYNC_TABLES_KEY: &str = "all_sync_tables";
+const RESYNC_KEY: &str = "resync";
+/// Set while the indexes of `INDEX_MIGRATION` are dropped for an initial all sync
+const DEFERRED_INDEXES_KEY: &str = "deferred_indexes";
+/// About how many virtual machine instructions run between checks of the apply timeout
+const PROGRESS_HANDLER_OPS: i32 = 1_000;
+/// The conventional `db_path` for explicitly asking for an in memory database
+pub const IN_MEMORY_DB_PATH: &str = ":memory:";
+/// Every up migration with the schema version it brings the database to
+const UP_MIGRATIONS: [(u32, &str); 6] = [
+    (1, "src/data_stores/sqlite/migrations/001.up.sql"),
+    (2, "src/data_stores/sqlite/migrations/002.up.sql"),
+    (3, "src/data_stores/sqlite/migrations/003.up.sql"),
+    (4, "src/data_stores/sqlite/migrations/004.up.sql"),
+    (5, INDEX_MIGRATION),
+    (6, "src/data_stores/sqlite/migrations/006.up.sql"),
+];
+/// Every table syncing reads from with the schema version of the migration creating it
+///
+/// `_schema_migrations` is left out since databases created before versioning lack it
+const EXPECTED_TABLES: [(u32, &str); 11] = [
+    (1, "schools"),
+    (1, "term_collections"),
+    (1, "professors"),
+    (1, "courses"),
+    (1, "sections"),
+    (1, "meeting_times"),
+    (2, "_previous_all_collections"),
+    (2, "_previous_term_collections"),
+    (2, "_previous_school_collections"),
+    (2, "_school_strategies"),
+    (3, "_sync_meta"),
+];
+/// The migration creating the indexes that only speed up lookups which can be created again at
+/// any time
+const INDEX_MIGRATION: &str = "src/data_stores/sqlite/migrations/005.up.sql";
+/// The latest sequence of each school synced as a whole
+const SCHOOL_SEQUENCES_QUERY: &str = r#"
+    SELECT s.school_id, COALESCE(MAX(p.synced_at), 0) AS sequence
+    FROM _school_strategies s
+    LEFT JOIN _previous_school_collections p ON s.school_id = p.school_id
+    WHERE s.term_collection_id IS NULL
+    GROUP BY s.school_id
+    ;
+"#;
+/// The latest sequence of each term synced on its own
+const TERM_SEQUENCES_QUERY: &str = r#"
+    SELECT s.school_id, s.term_collection_id, COALESCE(MAX(p.synced_at), 0) AS sequence
+    FROM _school_strategies s
+    LEFT JOIN _previous_term_collections p
+        ON s.school_id = p.school_id AND s.term_collection_id = p.term_collection_id
+    WHERE s.term_collection_id IS NOT NULL
+    GROUP BY s.school_id, s.term_collection_id
+    ;
+"#;
+
+lazy_static! {
+    /// Every table and index created by the migrations
+    static ref MIGRATED_TABLE: Regex = Regex::new(
+        r"\b((?:REFERENCES|ON)\s+)?\b(schools|term_collections|professors|courses|sections|meeting_times|_previous_all_collections|_previous_term_collections|_previous_school_collections|_school_strategies|_sync_meta|_schema_migrations|sections_by_course|sections_by_professor|meeting_times_by_section|previous_school_collections_by_school|previous_term_collections_by_term|previous_all_collections_by_sequence|previous_school_collections_by_sequence|previous_term_collections_by_sequence)\b"
+    )
+    .unwrap();
+    static ref CREATED_INDEX: Regex =
+        Regex::new(r"CREATE INDEX IF NOT EXISTS (\w+)").unwrap();
+    static ref IS_TABLE_PREFIX: Regex = Regex::new(r"^[a-zA-Z0-9_]*$").unwrap();
+    /// The numbered parameters of the generated statements
+    static ref PLACEHOLDER: Regex = Regex::new(r"\?(\d+)").unwrap();
+}
 
+/// A datastore backed by a single sqlite connection
+///
+/// It is used from one thread at a time. The connection cannot be shared between threads and a
+/// record hook can hold what cannot be sent to another thread so the datastore is neither `Sync`
+/// nor `Send`. Threads that each need a datastore open their own with the same `db_path` and
+/// `lock` turned off, sqlite then serializes their writes to the file. There is no connection
+/// pool.
+///
+/// ```compile_fail,E0277
+/// use classy_sync::data_stores::sqlite::{Sqlite, storage::SqliteConfig};
+///
+/// let sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+/// std::thread::scope(|scope| {
+///     scope.spawn(|| sqlite.optimize());
+/// });
+/// ```
+///
+/// ```compile_fail,E0277
+/// use classy_sync::data_stores::sqlite::{Sqlite, storage::SqliteConfig};
+///
+/// let sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+/// std::thread::spawn(move || drop(sqlite));
+/// ```
 pub struct Sqlite {
     conn: Connection,
+    apply: ApplyOptions,
+    commit_every: Option<usize>,
+    no_commit: bool,
+    apply_timeout: Option<Duration>,
+    unset_finished_terms: bool,
+    defer_indexes: bool,
+    optimize_on_drop: bool,
+    record_hook: RecordHookState,
+    /// released only after the connection is closed
+    _lock: Option<LockFile>,
+}
+
+/// The record hook with the records it is called with once they are committed
+#[derive(Default)]
+struct RecordHookState {
+    hook: Option<RecordHook>,
+    /// records of pages kept in a sync begun with `begin_sync` that may still be rolled back
+    held: Vec<(ClassDataSync, AffectedRows)>,
+}
+
+impl RecordHookState {
+    /// Where the applied records are collected which is nowhere without a hook
+    fn collector(&self) -> Option<Vec<(ClassDataSync, AffectedRows)>> {
+        self.hook.is_some().then(Vec::new)
+    }
+
+    /// Calls the hook with records whose transaction was committed or holds them until the
+    /// sync they are part of is finished
+    fn committed(&mut self, records: Option<Vec<(ClassDataSync, AffectedRows)>>, held: bool) {
+        let Some(records) = records else {
+            return;
+        };
+        if held {
+            self.held.extend(records);
+        } else if let Some(hook) = &mut self.hook {
+            for (record, affected) in &records {
+                hook(record, *affected);
+            }
+        }
+    }
+
+    fn finish_sync(&mut self, commit: bool) {
+        let held = std::mem::take(&mut self.held);
+        if commit {
+            self.committed(Some(held), false);
+        }
+    }
+}
+
+/// A file next to the database locked by the run using it which names the process holding it
+///
+/// The lock is advisory so it only keeps out other runs that also take it. The operating system
+/// releases it when the run exits however it ends so the file itself is left in place
+struct LockFile {
+    _file: fs::File,
+}
+
+impl LockFile {
+    fn acquire(db_path: &str) -> Result<LockFile, SqliteError> {
+        let path = PathBuf::from(format!("{db_path}.lock"));
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => {
+                let holder = fs::read_to_string(&path).unwrap_or_default();
+                return Err(SqliteError::DatabaseLocked {
+                    lock_path: path.display().to_string(),
+                    holder: holder.trim().to_string(),
+                });
+            }
+            Err(fs::TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(LockFile { _file: file })
+    }
+}
+
+/// What to do when a page inserts the same primary key more than once
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// the later insert fails like any other constraint violation
+    #[default]
+    Fail,
+    /// the later insert is applied as an update of the earlier one
+    LastWriteWins,
+    /// an insert of a row already in the database, from this page or an earlier one, is applied
+    /// as an update of the columns it has so the columns it leaves out keep their values instead
+    /// of becoming NULL
+    Upsert,
+}
+
+/// The rows strict mode expects each action to change
+///
+/// Updates and deletes may change nothing by default so records applied again such as those of
+/// a replayed page do not fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StrictRowCounts {
+    pub insert: ExpectedRows,
+    pub update: ExpectedRows,
+    pub delete: ExpectedRows,
+}
+
+impl Default for StrictRowCounts {
+    fn default() -> Self {
+        Self {
+            insert: ExpectedRows::exactly(1),
+            update: ExpectedRows::at_most(1),
+            delete: ExpectedRows::at_most(1),
+        }
+    }
+}
+
+impl StrictRowCounts {
+    fn of(&self, action: SyncAction) -> ExpectedRows {
+        match action {
+            SyncAction::Insert => self.insert,
+            SyncAction::Update => self.update,
+            SyncAction::Delete => self.delete,
+        }
+    }
+}
+
+/// What to do with a string value over the `max_value_length`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverLengthPolicy {
+    /// the record fails like any other invalid record
+    #[default]
+    Reject,
+    /// the value is cut down to the limit with a warning
+    Truncate,
+}
+
+/// How the JSON values of records are stored in columns
+pub trait ValueCodec: std::fmt::Debug + Send + Sync {
+    fn to_sql(&self, value: &Value) -> Result<rusqlite::types::Value, SqliteError>;
+}
+
+/// Stores strings as text, booleans as 0 or 1 and numbers as integers when they fit or reals
+///
+/// Arrays and objects are rejected
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultValueCodec;
+
+impl ValueCodec for DefaultValueCodec {
+    fn to_sql(&self, value: &Value) -> Result<rusqlite::types::Value, SqliteError> {
+        convert_to_sql_value(value)
+    }
+}
+
+/// How records are applied which is taken from the `SqliteConfig`
+#[derive(Debug, Default, Clone)]
+struct ApplyOptions {
     is_strict: bool,
+    strict_row_counts: StrictRowCounts,
+    quoting: IdentifierQuoting,
+    continue_on_error: bool,
+    skip_deletes: bool,
+    conflict_policy: ConflictPolicy,
+    /// the schema and prefix every table name is written with such as `classy.app_`
+    table_qualifier: String,
+    max_value_length: Option<usize>,
+    over_length_policy: OverLengthPolicy,
+    strict_tables: bool,
+    /// the `DefaultValueCodec` when unset
+    value_codec: Option<Arc<dyn ValueCodec>>,
+    /// tables whose records may have been applied already so inserts of existing rows are skipped
+    replayed_tables: HashSet<TableName>,
+    /// when the apply in progress has taken too long
+    deadline: Option<Deadline>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    at: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    fn check(&self) -> Result<(), SqliteError> {
+        if Instant::now() >= self.at {
+            return Err(SqliteError::ApplyTimeout {
+                timeout: self.timeout,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Tables being fetched again from the start of the all sync
+///
+/// Pages up to `until` only apply records of these tables since every other table already has
+/// them
+#[derive(Debug, Serialize, Deserialize)]
+struct Resync {
+    tables: HashSet<TableName>,
+    /// the all sync sequence the tables were cleared at
+    until: u64,
+}
+
+impl ApplyOptions {
+    fn from_config(config: &SqliteConfig) -> Result<ApplyOptions, SqliteError> {
+        if !IS_TABLE_PREFIX.is_match(&config.table_prefix) {
+            return Err(SqliteError::ValueConversionError(format!(
+                "table prefix `{}` can only contain letters, digits and underscores",
+                config.table_prefix
+            )));
+        }
+        let table_qualifier = match &config.attached {
+            Some(attached) => {
+                if attached.schema.is_empty() || !IS_TABLE_PREFIX.is_match(&attached.schema) {
+                    return Err(SqliteError::ValueConversionError(format!(
+                        "attached schema `{}` must be letters, digits and underscores",
+                        attached.schema
+                    )));
+                }
+                format!("{}.{}", attached.schema, config.table_prefix)
+            }
+            None => config.table_prefix.clone(),
+        };
+        Ok(ApplyOptions {
+            is_strict: config.is_strict,
+            strict_row_counts: config.strict_row_counts,
+            quoting: config.identifier_quoting,
+            continue_on_error: config.continue_on_error,
+            skip_deletes: config.skip_deletes,
+            conflict_policy: config.conflict_policy,
+            table_qualifier,
+            max_value_length: config.max_value_length,
+            over_length_policy: config.over_length_policy,
+            strict_tables: config.strict_tables,
+            value_codec: config.value_codec.clone(),
+            replayed_tables: HashSet::new(),
+            deadline: None,
+        })
+    }
+
+    /// Converts a column's value with the configured codec enforcing the `max_value_length`
+    fn to_sql(
+        &self,
+        column: &str,
+        value: &Value,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<rusqlite::types::Value, SqliteError> {
+        self.limit_length(column, self.codec().to_sql(value)?, warnings)
+    }
+
+    fn codec(&self) -> &dyn ValueCodec {
+        self.value_codec.as_deref().unwrap_or(&DefaultValueCodec)
+    }
+
+    /// The quoted name of a table including its schema when the tables are kept in an attached
+    /// database
+    fn quoted_table(&self, table: impl std::fmt::Display) -> String {
+        match self.table_qualifier.split_once('.') {
+            Some((schema, prefix)) => format!(
+                "{}.{}",
+                self.quoting.quote(schema),
+                self.quoting.quote(&format!("{prefix}{table}"))
+            ),
+            None => self
+                .quoting
+                .quote(&format!("{}{table}", self.table_qualifier)),
+        }
+    }
+
+    /// Enforces the `max_value_length` on a column's text value
+    fn limit_length(
+        &self,
+        column: &str,
+        value: rusqlite::types::Value,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<rusqlite::types::Value, SqliteError> {
+        let (Some(max_length), rusqlite::types::Value::Text(text)) =
+            (self.max_value_length, &value)
+        else {
+            return Ok(value);
+        };
+        let length = text.chars().count();
+        if length <= max_length {
+            return Ok(value);
+        }
+        match self.over_length_policy {
+            OverLengthPolicy::Reject => Err(SqliteError::ValueConversionError(format!(
+                "`{column}` has {length} characters which is over the limit of {max_length}"
+            ))),
+            OverLengthPolicy::Truncate => {
+                warn!("Truncating `{column}` from {length} to {max_length} characters");
+                warnings.push(Warning::Truncated {
+                    column: column.to_string(),
+                    length,
+                    max_length,
+                });
+                Ok(rusqlite::types::Value::Text(
+                    text.chars().take(max_length).collect(),
+                ))
+            }
+        }
+    }
+}
+
+/// A database file attached to the connection with `ATTACH DATABASE`
+#[derive(Debug, Clone)]
+pub struct AttachedDatabase {
+    /// the database file which is created when it does not exist or `:memory:`
+    pub path: String,
+    /// the name the synced tables are qualified with such as `classy` in `classy.schools`
+    pub schema: String,
 }
 
 pub struct SqliteConfig {
+    /// the database file which is created and migrated when it does not exist
+    ///
+    /// without a path or with `:memory:` a fresh in memory database is used that is gone once
+    /// the `Sqlite` is dropped
     pub db_path: Option<String>,
+    /// fail records changing a number of rows outside `strict_row_counts` instead of warning
+    /// which is off by default
     pub is_strict: bool,
+    /// the rows each action is expected to change in strict mode
+    pub strict_row_counts: StrictRowCounts,
     pub max_records_for_syncs: u16,
+    /// collect records that fail to apply into the `SyncReport` instead of aborting the sync
+    pub continue_on_error: bool,
+    /// ignore delete records counting them in the `SyncReport` while the sequences still advance
+    ///
+    /// this intentionally keeps rows the server no longer has so the datastore stops being a
+    /// copy of the server for an append and update only history of it
+    pub skip_deletes: bool,
+    /// commit an all sync's records every N records to bound the lock duration and journal size
+    ///
+    /// the new sequence is only recorded with the last chunk so if a sync fails part way through
+    /// the already committed records are sent again by the next sync which can fail strict
+    /// inserts; keeping this unset applies the whole response in one transaction
+    pub commit_every: Option<usize>,
+    pub identifier_quoting: IdentifierQuoting,
+    pub conflict_policy: ConflictPolicy,
+    /// prepended to every table name so several data sources can share one database file
+    pub table_prefix: String,
+    /// keep the synced tables in another database file attached to the connection instead of
+    /// the `db_path` database which is left for the application's own tables
+    pub attached: Option<AttachedDatabase>,
+    /// roll back applied sync responses instead of committing them to see what they would change
+    ///
+    /// the bookkeeping is rolled back too so the same response is requested by the next sync
+    pub no_commit: bool,
+    /// the most characters a string value can have to keep a misbehaving server from bloating the
+    /// database
+    pub max_value_length: Option<usize>,
+    pub over_length_policy: OverLengthPolicy,
+    /// fail on records of tables this version does not know instead of skipping them as filtered
+    pub strict_tables: bool,
+    /// how the values of records are stored which is the `DefaultValueCodec` when unset
+    pub value_codec: Option<Arc<dyn ValueCodec>>,
+    /// hold a lock file next to the database file with the synced tables so a run fails fast
+    /// instead of interleaving with another run using it
+    pub lock: bool,
+    /// roll back a sync response that takes longer than this to apply such as one stuck behind
+    /// another process holding the database
+    ///
+    /// chunks committed by `commit_every` are kept and waiting on a lock is still only ended by
+    /// sqlite's busy timeout
+    pub apply_timeout: Option<Duration>,
+    /// stop syncing terms that are no longer collecting once a select sync has no more pages
+    pub unset_finished_terms: bool,
+    /// drop the indexes that only speed up lookups when an all sync starts into empty tables and
+    /// create them once its last page is applied since building them once is faster than keeping
+    /// them up to date through every insert
+    ///
+    /// the dropped indexes are committed right away so an all sync that stops part way leaves
+    /// them dropped until a later sync applies its last page
+    pub defer_indexes: bool,
+    /// run `PRAGMA optimize` when the `Sqlite` is dropped to keep query plans good over a long
+    /// lived database, failures are only logged so call `optimize` to see them
+    pub optimize_on_drop: bool,
+    /// open existing database files without creating, migrating or optimizing them for commands
+    /// that only read, every write fails
+    pub read_only: bool,
+}
+
+impl Drop for Sqlite {
+    fn drop(&mut self) {
+        if self.optimize_on_drop
+            && let Err(e) = self.optimize()
+        {
+            warn!("Could not optimize the database: {e}");
+        }
+    }
 }
 
 impl Default for SqliteConfig {
     fn default() -> Self {
         Self {
             db_path: None,
-            is_strict: true,
+            is_strict: false,
+            strict_row_counts: StrictRowCounts::default(),
             max_records_for_syncs: DEFAULT_MAX_RECORDS,
+            continue_on_error: false,
+            skip_deletes: false,
+            commit_every: None,
+            identifier_quoting: IdentifierQuoting::default(),
+            conflict_policy: ConflictPolicy::default(),
+            table_prefix: String::new(),
+            attached: None,
+            no_commit: false,
+            max_value_length: None,
+            over_length_policy: OverLengthPolicy::default(),
+            strict_tables: false,
+            value_codec: None,
+            lock: false,
+            apply_timeout: None,
+            unset_finished_terms: false,
+            defer_indexes: false,
+            optimize_on_drop: true,
+            read_only: false,
         }
     }
 }
 
 impl Sqlite {
     pub fn new(config: SqliteConfig) -> Result<Sqlite, SqliteError> {
-        let conn = if let Some(db_path) = config.db_path {
-            let file_path = Path::new(&db_path);
-            Sqlite::get_db_connection(file_path)?
-        } else {
-            let conn = Connection::open_in_memory()?;
-            Sqlite::run_migrations(&conn)?;
-            conn
+        let apply = ApplyOptions::from_config(&config)?;
+        let locked_path = match &config.attached {
+            Some(attached) => Some(attached.path.as_str()),
+            None => config.db_path.as_deref(),
         };
+        let lock = match locked_path.filter(|path| *path != IN_MEMORY_DB_PATH) {
+            Some(path) if config.lock => Some(LockFile::acquire(path)?),
+            _ => None,
+        };
+        let conn = Sqlite::get_db_connection(
+            config.db_path.as_deref(),
+            config.attached.as_ref(),
+            &apply.table_qualifier,
+            config.read_only,
+        )?;
         Ok(Sqlite {
             conn,
-            is_strict: false,
+            apply,
+            commit_every: config.commit_every,
+            no_commit: config.no_commit,
+            apply_timeout: config.apply_timeout,
+            unset_finished_terms: config.unset_finished_terms,
+            defer_indexes: config.defer_indexes,
+            optimize_on_drop: config.optimize_on_drop && !config.read_only,
+            record_hook: RecordHookState::default(),
+            _lock: lock,
         })
     }
 
-    fn get_db_connection(file_path: &Path) -> Result<Connection, SqliteError> {
-        // Return SqliteError
-        if !file_path.exists() {
+    /// Lets sqlite gather the statistics its query planner is missing for every attached database
+    pub fn optimize(&self) -> Result<(), SqliteError> {
+        self.conn
+            .execute_batch("PRAGMA optimize;")
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "optimizing the database".to_string(),
+                source: e,
+            })
+    }
+
+    /// The file of the database with the synced tables which in memory databases do not have
+    fn database_file(&self) -> Result<Option<String>, SqliteError> {
+        let schema = self
+            .apply
+            .table_qualifier
+            .split_once('.')
+            .map_or("main", |(schema, _)| schema);
+        let file: String = self
+            .conn
+            .query_row(
+                "SELECT file FROM pragma_database_list WHERE name = ?1;",
+                [schema],
+                |row| row.get(0),
+            )
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: format!("getting the file of `{schema}`"),
+                source: e,
+            })?;
+        Ok(Some(file).filter(|file| !file.is_empty()))
+    }
+
+    fn get_db_connection(
+        db_path: Option<&str>,
+        attached: Option<&AttachedDatabase>,
+        prefix: &str,
+        read_only: bool,
+    ) -> Result<Connection, SqliteError> {
+        let conn = match db_path.filter(|path| *path != IN_MEMORY_DB_PATH) {
+            // a mistyped path is an error instead of a new empty database
+            Some(db_path) if read_only => Connection::open_with_flags(
+                db_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: format!("opening `{db_path}` read only"),
+                source: e,
+            })?,
+            Some(db_path) => Connection::open(Sqlite::create_db_file(db_path)?)?,
+            None => Connection::open_in_memory()?,
+        };
+        // an in memory database is empty either way so it is migrated like any other
+        let read_only = read_only && db_path.is_some_and(|path| path != IN_MEMORY_DB_PATH);
+        if let Some(attached) = attached {
+            let path = if read_only {
+                Path::new(&attached.path)
+            } else {
+                Sqlite::create_db_file(&attached.path)?
+            };
+            conn.execute(
+                &format!("ATTACH DATABASE ?1 AS \"{}\";", attached.schema),
+                [path.to_string_lossy()],
+            )
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: format!("attaching `{}` as `{}`", attached.path, attached.schema),
+                source: e,
+            })?;
+        }
+        // the file can be shared with other data sources which have not created these tables
+        if !read_only {
+            Sqlite::run_migrations(&conn, prefix)?;
+        }
+        Sqlite::check_expected_tables(&conn, prefix)?;
+        Ok(conn)
+    }
+
+    /// A read only connection to the database files of this one attached under the same names
+    ///
+    /// In memory databases cannot be opened a second time so there is none when one is used
+    fn read_only_connection(&self) -> Result<Option<Connection>, SqliteError> {
+        let mut databases_query = self
+            .conn
+            .prepare("SELECT name, file FROM pragma_database_list WHERE name != 'temp';")?;
+        let databases = databases_query
+            .query_map((), |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "listing databases".to_string(),
+                source: e,
+            })?;
+        let mut read_only: Option<Connection> = None;
+        // the main database is always listed first
+        for (name, file) in databases {
+            if file.is_empty() {
+                return Ok(None);
+            }
+            match &read_only {
+                None => {
+                    read_only = Some(Connection::open_with_flags(
+                        &file,
+                        OpenFlags::SQLITE_OPEN_READ_ONLY,
+                    )?)
+                }
+                Some(conn) => {
+                    conn.execute(&format!("ATTACH DATABASE ?1 AS \"{name}\";"), [&file])
+                        .map_err(|e| SqliteError::FailedSqliteQuery {
+                            query_info: format!("attaching `{file}` read only as `{name}`"),
+                            source: e,
+                        })?;
+                }
+            }
+        }
+        Ok(read_only)
+    }
+
+    /// Makes sure the database file and its directory exist
+    fn create_db_file(db_path: &str) -> Result<&Path, SqliteError> {
+        let file_path = Path::new(db_path);
+        if db_path != IN_MEMORY_DB_PATH && !file_path.exists() {
             if let Some(parent_dir) = file_path.parent() {
                 fs::create_dir_all(parent_dir)?;
             }
             fs::File::create(file_path)?;
-            let conn = Connection::open(file_path)?;
-            Sqlite::run_migrations(&conn)?;
-            Ok(conn)
-        } else {
-            // TODO: check to see if the migrations are up to date
-            Ok(Connection::open(file_path)?)
-        }
-    }
-
-    fn run_migrations(conn: &Connection) -> Result<(), SqliteError> {
-        // TODO: embed the migrations into the build process and run up migrations
-        let up_migration_classy =
-            fs::read_to_string("src/data_stores/sqlite/migrations/001.up.sql")?;
-        let up_migration_sync = fs::read_to_string("src/data_stores/sqlite/migrations/002.up.sql")?;
-        conn.execute_batch(&up_migration_classy)?;
-        conn.execute_batch(&up_migration_sync)?;
+        }
+        Ok(file_path)
+    }
+
+    /// Fails on a database missing tables of its applied migrations which cannot be repaired
+    ///
+    /// This happens when the tables are dropped by hand since the migrations are applied again
+    fn check_expected_tables(conn: &Connection, prefix: &str) -> Result<(), SqliteError> {
+        let mut missing = Vec::new();
+        for (version, table) in EXPECTED_TABLES {
+            if !table_exists(conn, prefix, table)? {
+                missing.push((version, table));
+            }
+        }
+        let Some(&(first_missing, _)) = missing.iter().min() else {
+            return Ok(());
+        };
+        Err(SqliteError::MissingTables {
+            tables: missing.iter().map(|(_, table)| table.to_string()).collect(),
+            migration: UP_MIGRATIONS
+                .iter()
+                .find(|(version, _)| *version == first_missing)
+                .map_or_else(|| first_missing.to_string(), |(_, path)| path.to_string()),
+        })
+    }
+
+    /// Applies every up migration newer than the schema of the database in one transaction
+    fn run_migrations(conn: &Connection, prefix: &str) -> Result<(), SqliteError> {
+        // TODO: embed the migrations into the build process
+        let is_versioned = table_exists(conn, prefix, "_schema_migrations")?;
+        let applied = applied_schema_version(conn, prefix, is_versioned)?;
+        if applied >= UP_MIGRATIONS[UP_MIGRATIONS.len() - 1].0 {
+            return Ok(());
+        }
+        let tx = conn.unchecked_transaction()?;
+        for (version, path) in UP_MIGRATIONS
+            .iter()
+            .filter(|(version, _)| *version > applied)
+        {
+            info!("Applying schema migration {version}");
+            let up_migration = fs::read_to_string(path)?;
+            tx.execute_batch(&prefix_tables(&up_migration, prefix))
+                .map_err(|e| SqliteError::FailedSqliteQuery {
+                    query_info: format!("applying schema migration {version}"),
+                    source: e,
+                })?;
+        }
+        // the versions can only be recorded once the migration creating their table has run so
+        // a file from before versioning records the ones it already had along with the new ones
+        let recorded = if is_versioned { applied } else { 0 };
+        for (version, _) in UP_MIGRATIONS
+            .iter()
+            .filter(|(version, _)| *version > recorded)
+        {
+            tx.execute(
+                &prefix_tables(
+                    "INSERT INTO _schema_migrations (version) VALUES (?1);",
+                    prefix,
+                ),
+                [version],
+            )
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: format!("recording schema migration {version}"),
+                source: e,
+            })?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn read_meta(
+        conn: &Connection,
+        prefix: &str,
+        key: &str,
+    ) -> Result<Option<String>, SqliteError> {
+        conn.query_row(
+            &prefix_tables("SELECT value FROM _sync_meta WHERE key = ?1;", prefix),
+            [key],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(|e| SqliteError::FailedSqliteQuery {
+            query_info: format!("getting `{key}` sync meta"),
+            source: e,
+        })
+    }
+
+    /// Sets the value of a `_sync_meta` key removing it when there is no value
+    fn write_meta(
+        conn: &Connection,
+        prefix: &str,
+        key: &str,
+        value: Option<&str>,
+    ) -> Result<(), SqliteError> {
+        let result = match value {
+            Some(value) => conn.execute(
+                &prefix_tables(
+                    r#"
+                INSERT INTO _sync_meta (key, value)
+                VALUES (?1, ?2)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value;
+                "#,
+                    prefix,
+                ),
+                (key, value),
+            ),
+            None => conn.execute(
+                &prefix_tables("DELETE FROM _sync_meta WHERE key = ?1;", prefix),
+                [key],
+            ),
+        };
+        result.map_err(|e| SqliteError::FailedSqliteQuery {
+            query_info: format!("saving `{key}` sync meta"),
+            source: e,
+        })?;
         Ok(())
     }
 
+    fn read_json_meta<T: DeserializeOwned>(
+        conn: &Connection,
+        prefix: &str,
+        key: &str,
+    ) -> Result<Option<T>, SqliteError> {
+        Self::read_meta(conn, prefix, key)?
+            .map(|value| {
+                serde_json::from_str(&value).map_err(|e| {
+                    SqliteError::DataIntegrityError(format!("invalid `{key}` value `{value}`: {e}"))
+                })
+            })
+            .transpose()
+    }
+
+    fn write_json_meta<T: Serialize>(
+        conn: &Connection,
+        prefix: &str,
+        key: &str,
+        value: Option<&T>,
+    ) -> Result<(), SqliteError> {
+        let value = value
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| SqliteError::ValueConversionError(e.to_string()))?;
+        Self::write_meta(conn, prefix, key, value.as_deref())
+    }
+
+    /// The only tables an all sync applies records to when the sync was set with a filter
+    fn read_all_sync_tables(
+        conn: &Connection,
+        prefix: &str,
+    ) -> Result<Option<HashSet<TableName>>, SqliteError> {
+        Self::read_json_meta(conn, prefix, ALL_SYNC_TABLES_KEY)
+    }
+
+    fn read_resync(conn: &Connection, prefix: &str) -> Result<Option<Resync>, SqliteError> {
+        Self::read_json_meta(conn, prefix, RESYNC_KEY)
+    }
+
     // This is the crux of the sqlite data store... being able to convert a `ClassDataSync` into a
     // sqlite query
-    fn execute_sync(
-        conn: &Transaction,
-        sync: ClassDataSync,
-        is_strict: bool,
-    ) -> Result<(), SqliteError> {
+    /// The statement applying a record with its parameters or nothing for an update that changes
+    /// nothing
+    fn into_sql(
+        sync: &ClassDataSync,
+        options: &ApplyOptions,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Option<(String, Vec<rusqlite::types::Value>)>, SqliteError> {
+        if let TableName::Unknown(table) = &sync.table_name {
+            return Err(SqliteError::UnsupportedSyncOperation(format!(
+                "record of the table `{table}` unknown to this version {:?}",
+                sync.pk_fields
+            )));
+        }
         sync.verify_record()
             .map_err(|e| SqliteError::ValueConversionError(e.to_string()))?;
-        let sql_string: String;
-        let result = match sync.sync_action {
+        let statement = match sync.sync_action {
             SyncAction::Update => {
-                if sync.relevant_fields.is_none()
-                    || sync.relevant_fields.as_ref().unwrap().is_empty()
-                {
-                    warn!("Update sync with no changes: `{:?}`", sync);
-                    return Ok(());
-                }
-                let mut arg_counter: usize = 0;
-                let mut param_args: Vec<rusqlite::types::Value> = vec![];
-                let mut set_values = vec![];
-                for (col, val) in sync
-                    .relevant_fields
-                    .as_ref()
-                    .unwrap_or(&HashMap::new())
-                    .iter()
-                {
-                    param_args.push(convert_to_sql_value(val)?);
-                    arg_counter += 1;
-                    set_values.push(format!("{col} = ?{arg_counter}"))
-                }
-                let set_values = set_values.join(", ");
-                let mut where_values = vec![];
-                for (col, val) in sync.pk_fields.iter() {
-                    param_args.push(convert_to_sql_value(val)?);
-                    arg_counter += 1;
-                    where_values.push(format!("{col} = ?{arg_counter}"))
-                }
-
-                let where_values = where_values.join(" AND ");
-                sql_string = format!(
-                    "UPDATE {} SET {} WHERE {};",
-                    sync.table_name, set_values, where_values
-                );
-                trace!("update: {}", &sql_string);
-                let mut maybe_statement = conn.prepare_cached(&sql_string)?;
-
-                maybe_statement.execute(params_from_iter(param_args))
-            }
-            SyncAction::Delete => {
-                let mut arg_counter: usize = 0;
-                let mut param_args: Vec<rusqlite::types::Value> = vec![];
-                let mut where_values = vec![];
-                for (col, val) in sync.pk_fields.iter() {
-                    param_args.push(convert_to_sql_value(val)?);
-                    arg_counter += 1;
-                    where_values.push(format!("{col} = ?{arg_counter}"))
-                }
-                let where_values = where_values.join(" AND ");
-
-                sql_string = format!("DELETE FROM {} WHERE {};", sync.table_name, where_values);
-                trace!("delete: {}", &sql_string);
-                let mut maybe_statement = conn.prepare_cached(&sql_string)?;
-                maybe_statement.execute(params_from_iter(param_args))
-            }
-            SyncAction::Insert => {
-                let mut arg_counter: usize = 0;
-                let mut param_args: Vec<rusqlite::types::Value> = vec![];
-                let mut columns = vec![];
-                let mut values = vec![];
-                for (col, val) in sync.pk_fields.iter() {
-                    param_args.push(convert_to_sql_value(val)?);
-                    arg_counter += 1;
-                    columns.push(col.to_string());
-                    values.push(format!("?{arg_counter}"))
-                }
-                for (col, val) in sync
+                if sync
                     .relevant_fields
                     .as_ref()
-                    .unwrap_or(&HashMap::new())
-                    .iter()
+                    .is_none_or(|fields| fields.is_empty())
                 {
-                    param_args.push(convert_to_sql_value(val)?);
-                    arg_counter += 1;
-                    columns.push(col.to_string());
-                    values.push(format!("?{arg_counter}"))
+                    warn!("Update sync with no changes: `{:?}`", sync);
+                    warnings.push(Warning::EmptyUpdate {
+                        table: sync.table_name.clone(),
+                    });
+                    return Ok(None);
                 }
-                let columns = columns.join(", ");
-                let values = values.join(", ");
-
-                sql_string = format!(
-                    "INSERT INTO {} ({}) VALUES ({});",
-                    sync.table_name, columns, values
-                );
-                trace!("insert: {} {:?}", &sql_string, param_args);
-                let mut maybe_statement = conn.prepare_cached(&sql_string)?;
-
-                maybe_statement.execute(params_from_iter(param_args))
+                build_update_sql(sync, options, warnings)?
             }
+            SyncAction::Delete => build_delete_sql(sync, options, warnings)?,
+            SyncAction::Insert => build_insert_sql(sync, options, warnings)?,
         };
+        trace!("{:?}: {} {:?}", sync.sync_action, statement.0, statement.1);
+        Ok(Some(statement))
+    }
 
-        let query_output = result.map_err(|err| SqliteError::FailedSqliteQuery {
-            query_info: format!("sync query `{}`", sql_string),
-            source: err,
-        })?;
+    fn execute_sync(
+        conn: &Connection,
+        sync: &ClassDataSync,
+        options: &ApplyOptions,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<usize, SqliteError> {
+        let Some((sql_string, param_args)) = Self::into_sql(sync, options, warnings)? else {
+            return Ok(0);
+        };
+        let mut maybe_statement = conn.prepare_cached(&sql_string)?;
+        let query_output = maybe_statement
+            .execute(params_from_iter(param_args))
+            .map_err(|err| SqliteError::FailedSqliteQuery {
+                query_info: format!("sync query `{}`", sql_string),
+                source: err,
+            })?;
 
-        match (query_output, is_strict) {
-            (n, false) if n != 1 => {
-                warn!("Query affected {} rows expected 1", n);
-                Ok(())
+        let expected = options.strict_row_counts.of(sync.sync_action);
+        match (query_output, options.is_strict) {
+            (n, _) if expected.contains(n) => Ok(n),
+            (n, false) => {
+                warn!("Query affected {n} rows expected {expected}");
+                warnings.push(Warning::UnexpectedRowCount {
+                    table: sync.table_name.clone(),
+                    affected: n,
+                    expected,
+                });
+                Ok(n)
             }
-            (n, true) if n != 1 => Err(SqliteError::UnexpectedQueryResult {
+            (n, true) => Err(SqliteError::UnexpectedQueryResult {
                 query: sql_string.to_string(),
                 result: n.to_string(),
-                expected: "1".to_string(),
+                expected: expected.to_string(),
             }),
-            (_, _) => Ok(()),
         }
     }
 
+    /// The statements that would apply the records with their values written out as literals
+    /// instead of being bound
+    ///
+    /// Literals are escaped but the script can still only be as trustworthy as the response it
+    /// was rendered from so it should not be run against a database without review
+    pub fn sql_script(
+        config: &SqliteConfig,
+        records: impl IntoIterator<Item = ClassDataSync>,
+    ) -> Result<String, SqliteError> {
+        warn!("Rendering values as literals which is unsafe for responses that are not trusted");
+        let options = ApplyOptions::from_config(config)?;
+        let mut warnings = vec![];
+        let mut script = String::new();
+        for sync in records {
+            if let Some((sql_string, param_args)) = Self::into_sql(&sync, &options, &mut warnings)?
+            {
+                script.push_str(&inline_params(&sql_string, &param_args));
+                script.push('\n');
+            }
+        }
+        Ok(script)
+    }
+
+    fn apply_syncs(
+        conn: &Connection,
+        syncs: impl IntoIterator<Item = ClassDataSync>,
+        options: &ApplyOptions,
+        mut applied: Option<&mut Vec<(ClassDataSync, AffectedRows)>>,
+    ) -> Result<SyncReport, SqliteError> {
+        let mut report = SyncReport::default();
+        let mut inserted = HashSet::new();
+        for sync in syncs {
+            if let Some(deadline) = options.deadline {
+                deadline.check()?;
+            }
+            // strict records of unknown tables fail when their statement is built
+            if let TableName::Unknown(table) = &sync.table_name
+                && !options.strict_tables
+            {
+                warn!(
+                    "Skipping record of the table `{table}` unknown to this version {:?}",
+                    sync.pk_fields
+                );
+                report.warnings.push(Warning::UnknownTable {
+                    table: table.clone(),
+                });
+                report.filtered += 1;
+                continue;
+            }
+            if matches!(sync.sync_action, SyncAction::Delete) && options.skip_deletes {
+                trace!(
+                    "Ignoring delete of `{}` {:?}",
+                    sync.table_name, sync.pk_fields
+                );
+                report.ignored_deletes += 1;
+                continue;
+            }
+            if matches!(sync.sync_action, SyncAction::Insert)
+                && options.replayed_tables.contains(&sync.table_name)
+                && Self::row_exists(conn, &sync, options)?
+            {
+                report.filtered += 1;
+                continue;
+            }
+            let sync = match sync.sync_action {
+                SyncAction::Insert if options.conflict_policy == ConflictPolicy::LastWriteWins => {
+                    if inserted.insert(primary_key_of(&sync)) {
+                        sync
+                    } else {
+                        warn!(
+                            "Collapsing repeated insert into an update for `{}` {:?}",
+                            sync.table_name, sync.pk_fields
+                        );
+                        report.warnings.push(Warning::CollapsedInsert {
+                            table: sync.table_name.clone(),
+                        });
+                        ClassDataSync {
+                            sync_action: SyncAction::Update,
+                            ..sync
+                        }
+                    }
+                }
+                SyncAction::Insert
+                    if options.conflict_policy == ConflictPolicy::Upsert
+                        && Self::row_exists(conn, &sync, options)? =>
+                {
+                    trace!(
+                        "Upserting existing row of `{}` {:?}",
+                        sync.table_name, sync.pk_fields
+                    );
+                    ClassDataSync {
+                        sync_action: SyncAction::Update,
+                        ..sync
+                    }
+                }
+                _ => sync,
+            };
+            match Self::execute_sync(conn, &sync, options, &mut report.warnings) {
+                Ok(affected) => {
+                    report.applied += 1;
+                    report.affected += affected;
+                    if affected > 0 {
+                        report.record_discovery(&sync);
+                    }
+                    if let Some(applied) = applied.as_deref_mut() {
+                        applied.push((sync, affected));
+                    }
+                }
+                // sqlite only rolls back the failed statement so the rest of the transaction is kept
+                Err(err) if options.continue_on_error => {
+                    warn!("Skipping record that could not be applied: {err}");
+                    report.skipped.push(SkippedRecord {
+                        record: sync,
+                        reason: err.to_string(),
+                    });
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(report)
+    }
+
+    fn row_exists(
+        conn: &Connection,
+        sync: &ClassDataSync,
+        options: &ApplyOptions,
+    ) -> Result<bool, SqliteError> {
+        let (where_values, param_args) = pk_where(&sync.pk_fields, options)?;
+        let sql_string = format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE {where_values});",
+            options.quoted_table(&sync.table_name),
+        );
+        conn.query_row(&sql_string, params_from_iter(param_args), |row| row.get(0))
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: format!("row exists `{sql_string}`"),
+                source: e,
+            })
+    }
+
     fn is_all_sync(&mut self) -> Result<bool, SqliteError> {
         // Return SqliteError
         self.conn
             .query_row(
-                r#"
+                &prefix_tables(
+                    r#"
             SELECT EXISTS (
                 SELECT 1 FROM _previous_all_collections
             );
             "#,
+                    &self.apply.table_qualifier,
+                ),
                 (),
                 |row| row.get(0),
             )
@@ -221,11 +1095,14 @@ impl Sqlite {
         // Return SqliteError
         self.conn
             .query_row(
-                r#"
+                &prefix_tables(
+                    r#"
         SELECT (
             EXISTS (SELECT 1 FROM _school_strategies)
         );
         "#,
+                    &self.apply.table_qualifier,
+                ),
                 (),
                 |row| row.get(0),
             )
@@ -244,10 +1121,13 @@ impl Sqlite {
         let last_sync: u64 = self
             .conn
             .query_row(
-                r#"
+                &prefix_tables(
+                    r#"
                 SELECT COALESCE(MAX(synced_at), 0)
                 FROM _previous_all_collections;
             "#,
+                    &self.apply.table_qualifier,
+                ),
                 (),
                 |row| row.get(0),
             )
@@ -267,16 +1147,10 @@ impl Sqlite {
                 "Cannot sync select because sync all has been run previously".to_string(),
             ));
         }
-        let mut all_school_query = self.conn.prepare(
-            r#"
-                SELECT s.school_id, COALESCE(MAX(p.synced_at), 0) AS sequence
-                FROM _school_strategies s
-                LEFT JOIN _previous_school_collections p ON s.school_id = p.school_id
-                WHERE s.term_collection_id IS NULL
-                GROUP BY s.school_id
-                ;
-            "#,
-        )?;
+        let mut all_school_query = self.conn.prepare(&prefix_tables(
+            SCHOOL_SEQUENCES_QUERY,
+            &self.apply.table_qualifier,
+        ))?;
         let school_to_last_sequence = all_school_query
             .query_map((), |r| {
                 let res: (String, u64) = (r.get(0)?, r.get(1)?);
@@ -288,17 +1162,10 @@ impl Sqlite {
                 source: e,
             })?;
 
-        let mut term_school_query = self.conn.prepare(
-            r#"
-                SELECT s.school_id, s.term_collection_id, COALESCE(MAX(p.synced_at), 0) AS sequence
-                FROM _school_strategies s
-                LEFT JOIN _previous_term_collections p
-                    ON s.school_id = p.school_id AND s.term_collection_id = p.term_collection_id
-                WHERE s.term_collection_id IS NOT NULL
-                GROUP BY s.school_id, s.term_collection_id
-                ;
-            "#,
-        )?; // #[from] RusqliteError
+        let mut term_school_query = self.conn.prepare(&prefix_tables(
+            TERM_SEQUENCES_QUERY,
+            &self.apply.table_qualifier,
+        ))?; // #[from] RusqliteError
 
         let term_to_last_sequence = term_school_query
             .query_map((), |r| {
@@ -316,13 +1183,18 @@ impl Sqlite {
                 )
             })?;
 
-        let mut term_sync = SelectSync::new();
+        // an upper bound since several terms can belong to the same school
+        let mut term_sync =
+            SelectSync::with_capacity(school_to_last_sequence.len() + term_to_last_sequence.len());
         for ((school_id, term_collection_id), sequence) in term_to_last_sequence {
-            if school_to_last_sequence.contains_key(&school_id) {
+            if let Some(school_sequence) = school_to_last_sequence.get(&school_id) {
                 // this situation happens when an the scope of syncing goes from term to the whole
                 // school
                 // this exclusion is just for the next sync operation and then it should no longer
                 // be needed so long as the school's sync is >= the excluded sequence
+                if sequence <= *school_sequence {
+                    continue;
+                }
                 term_sync
                     .add_exclusion(school_id.clone(), term_collection_id.clone(), sequence)
                     .map_err(|_| {
@@ -350,49 +1222,275 @@ impl Sqlite {
                     ))
                 })?
         }
+        // the request would be answered with nothing and the sync would silently do nothing
+        if term_sync.is_empty() {
+            return Err(SqliteError::NoSyncResources);
+        }
         Ok(term_sync)
     }
-}
 
-impl Datastore for Sqlite {
-    fn execute_all_request_sync(
-        &mut self,
-        all_sync_response: AllSyncResult,
-    ) -> Result<(), DataStoreError> {
-        let tx = self.conn.transaction().map_err(SqliteError::from)?;
-        tx.execute(
-            r#" INSERT INTO _previous_all_collections (synced_at)
-            VALUES ($1);
-        "#,
-            (all_sync_response.new_latest_sync,),
+    /// Removes the strategies of terms that are no longer collecting since they will not change
+    fn unset_finished_terms(conn: &Connection, prefix: &str) -> Result<(), SqliteError> {
+        let mut finished_query = conn.prepare(&prefix_tables(
+            r#"
+            SELECT s.school_id, s.term_collection_id
+            FROM _school_strategies s
+            JOIN term_collections t ON t.school_id = s.school_id AND t.id = s.term_collection_id
+            WHERE t.still_collecting = 0
+            "#,
+            prefix,
+        ))?;
+        let finished = finished_query
+            .query_map((), |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "collecting finished terms".to_string(),
+                source: e,
+            })?;
+        for (school_id, term_collection_id) in finished {
+            conn.execute(
+                &prefix_tables(
+                    r#"
+                DELETE FROM _school_strategies
+                WHERE school_id = ?1 AND term_collection_id = ?2;
+                "#,
+                    prefix,
+                ),
+                (&school_id, &term_collection_id),
+            )
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "unsetting finished term".to_string(),
+                source: e,
+            })?;
+            info!(
+                "Stopped syncing term `{term_collection_id}` of `{school_id}` because it is no longer collecting"
+            );
+        }
+        Ok(())
+    }
+
+    /// The names of the indexes created by `INDEX_MIGRATION`
+    fn deferrable_indexes() -> Result<Vec<String>, SqliteError> {
+        let migration = fs::read_to_string(INDEX_MIGRATION)?;
+        Ok(CREATED_INDEX
+            .captures_iter(&migration)
+            .map(|captures| captures[1].to_string())
+            .collect())
+    }
+
+    /// Whether none of the synced tables have any rows
+    fn is_empty(conn: &Connection, prefix: &str) -> Result<bool, SqliteError> {
+        for table in [
+            TableName::Schools,
+            TableName::TermCollections,
+            TableName::Professors,
+            TableName::Courses,
+            TableName::Sections,
+            TableName::MeetingTimes,
+        ] {
+            let has_rows: bool = conn
+                .query_row(
+                    &prefix_tables(&format!("SELECT EXISTS(SELECT 1 FROM {table});"), prefix),
+                    (),
+                    |row| row.get(0),
+                )
+                .map_err(|e| SqliteError::FailedSqliteQuery {
+                    query_info: format!("checking whether `{table}` has rows"),
+                    source: e,
+                })?;
+            if has_rows {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Drops the indexes of `INDEX_MIGRATION` when an all sync is about to load empty tables
+    fn defer_indexes(&mut self) -> Result<(), SqliteError> {
+        let prefix = &self.apply.table_qualifier;
+        if Self::read_meta(&self.conn, prefix, DEFERRED_INDEXES_KEY)?.is_some()
+            || !Self::is_empty(&self.conn, prefix)?
+        {
+            return Ok(());
+        }
+        let tx = self.conn.savepoint()?;
+        for index in Self::deferrable_indexes()? {
+            tx.execute(
+                &prefix_tables(&format!("DROP INDEX IF EXISTS {index};"), prefix),
+                (),
+            )
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: format!("dropping index `{index}`"),
+                source: e,
+            })?;
+        }
+        Self::write_meta(&tx, prefix, DEFERRED_INDEXES_KEY, Some("true"))?;
+        tx.commit()?;
+        info!("Deferred creating indexes until the initial all sync is applied");
+        Ok(())
+    }
+
+    /// Creates the indexes dropped by `defer_indexes` again
+    fn restore_indexes(conn: &Connection, prefix: &str) -> Result<(), SqliteError> {
+        if Self::read_meta(conn, prefix, DEFERRED_INDEXES_KEY)?.is_none() {
+            return Ok(());
+        }
+        let migration = fs::read_to_string(INDEX_MIGRATION)?;
+        conn.execute_batch(&prefix_tables(&migration, prefix))
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "creating deferred indexes".to_string(),
+                source: e,
+            })?;
+        Self::write_meta(conn, prefix, DEFERRED_INDEXES_KEY, None)
+    }
+
+    /// Runs an apply failing it once it has taken longer than the `apply_timeout`
+    fn with_apply_timeout<T>(
+        &mut self,
+        apply: impl FnOnce(&mut Sqlite) -> Result<T, DataStoreError>,
+    ) -> Result<T, DataStoreError> {
+        let Some(timeout) = self.apply_timeout else {
+            return apply(self);
+        };
+        let deadline = Deadline {
+            at: Instant::now() + timeout,
+            timeout,
+        };
+        // records are checked as they are applied but a single statement can run long too
+        self.conn.progress_handler(
+            PROGRESS_HANDLER_OPS,
+            Some(move || deadline.check().is_err()),
+        );
+        self.apply.deadline = Some(deadline);
+        let result = apply(self);
+        self.apply.deadline = None;
+        self.conn.progress_handler(0, None::<fn() -> bool>);
+        match result {
+            // an interrupted statement only reports that it was interrupted
+            Err(_) if deadline.check().is_err() => Err(SqliteError::ApplyTimeout { timeout })?,
+            result => result,
+        }
+    }
+
+    fn apply_all_sync(
+        &mut self,
+        all_sync_response: AllSyncResult,
+    ) -> Result<SyncReport, DataStoreError> {
+        let mut report = SyncReport::default();
+        if self.defer_indexes && !self.no_commit {
+            self.defer_indexes()?;
+        }
+        let mut sync_data = all_sync_response.sync_data;
+        if let Some(tables) = Self::read_all_sync_tables(&self.conn, &self.apply.table_qualifier)? {
+            let unfiltered_count = sync_data.len();
+            sync_data.retain(|sync| tables.contains(&sync.table_name));
+            report.filtered = unfiltered_count - sync_data.len();
+        }
+        let resync = Self::read_resync(&self.conn, &self.apply.table_qualifier)?;
+        let mut options = Cow::Borrowed(&self.apply);
+        match &resync {
+            Some(resync) if all_sync_response.new_latest_sync <= resync.until => {
+                let unfiltered_count = sync_data.len();
+                sync_data.retain(|sync| resync.tables.contains(&sync.table_name));
+                report.filtered += unfiltered_count - sync_data.len();
+            }
+            // the page crossing where the tables were cleared starts with records every other
+            // table already has
+            Some(resync) => {
+                let options = options.to_mut();
+                options.replayed_tables = sync_data
+                    .iter()
+                    .map(|sync| sync.table_name.clone())
+                    .filter(|table| !resync.tables.contains(table))
+                    .collect();
+                options.is_strict = false;
+            }
+            None => {}
+        }
+        let mut records = sync_data.into_iter();
+        // chunks are committed as they go so they cannot be rolled back
+        let commit_every = self.commit_every.filter(|n| *n > 0 && !self.no_commit);
+        if let Some(commit_every) = commit_every {
+            while records.len() > commit_every {
+                let tx = self.conn.savepoint().map_err(SqliteError::from)?;
+                let mut applied = self.record_hook.collector();
+                report.merge(Self::apply_syncs(
+                    &tx,
+                    records.by_ref().take(commit_every),
+                    &options,
+                    applied.as_mut(),
+                )?);
+                tx.commit().map_err(SqliteError::from)?;
+                self.record_hook
+                    .committed(applied, !self.conn.is_autocommit());
+            }
+        }
+
+        let tx = self.conn.savepoint().map_err(SqliteError::from)?;
+        // only the latest sequence is ever read so it is the only row kept
+        tx.execute(
+            &prefix_tables(
+                "DELETE FROM _previous_all_collections;",
+                &self.apply.table_qualifier,
+            ),
+            (),
+        )
+        .map_err(|e| SqliteError::FailedSqliteQuery {
+            query_info: "clearing previous all collections".to_string(),
+            source: e,
+        })?;
+        tx.execute(
+            &prefix_tables(
+                r#" INSERT INTO _previous_all_collections (synced_at)
+            VALUES ($1);
+        "#,
+                &self.apply.table_qualifier,
+            ),
+            (all_sync_response.new_latest_sync,),
         )
         .map_err(|e| SqliteError::FailedSqliteQuery {
             query_info: "inserting previous all collections".to_string(),
             source: e,
         })?;
-        for sync in all_sync_response.sync_data.into_iter() {
-            Self::execute_sync(&tx, sync, self.is_strict)?
+        if resync.is_some_and(|resync| all_sync_response.new_latest_sync > resync.until) {
+            Self::write_json_meta::<Resync>(&tx, &self.apply.table_qualifier, RESYNC_KEY, None)?;
         }
-        tx.commit().map_err(SqliteError::from)?;
-        Ok(())
+        let mut applied = self.record_hook.collector();
+        report.merge(Self::apply_syncs(&tx, records, &options, applied.as_mut())?);
+        if !all_sync_response.has_more {
+            Self::restore_indexes(&tx, &self.apply.table_qualifier)?;
+        }
+        finish_transaction(tx, self.no_commit)?;
+        if !self.no_commit {
+            self.record_hook
+                .committed(applied, !self.conn.is_autocommit());
+        }
+        #[cfg(feature = "tracing")]
+        record_report_counts(&report);
+        Ok(report)
     }
 
-    fn execute_select_request_sync(
+    fn apply_select_sync(
         &mut self,
         select_sync_request: SelectSync,
         select_sync_response: TermSyncResult,
-    ) -> Result<(), DataStoreError> {
-        let _ = select_sync_request;
-        let tx = self.conn.transaction().map_err(SqliteError::from)?;
-        for (school_id, entry) in &select_sync_response.new_sync_term_sequences {
+    ) -> Result<SyncReport, DataStoreError> {
+        let reached = select_sync_request.reconcile(&select_sync_response.new_sync_term_sequences);
+        // the bookkeeping is written in the same savepoint as the records so a record that fails
+        // rolls it back too and a sequence is never committed without the records it covers
+        let tx = self.conn.savepoint().map_err(SqliteError::from)?;
+        for (school_id, entry) in &reached {
             match entry {
                 sync_requests::SchoolEntry::TermToSequence(term_sequence) => {
                     for (term, sequence) in term_sequence {
                         tx.execute(
-                            r#"
-                            INSERT INTO _previous_term_collections (synced_at, school_id, term_collection_id)
-                            VALUES ($1, $2, $3);
-                            "#,
+                            &prefix_tables(
+                                r#"
+                                INSERT INTO _previous_term_collections (synced_at, school_id, term_collection_id)
+                                VALUES ($1, $2, $3);
+                                "#,
+                                &self.apply.table_qualifier,
+                            ),
                             (sequence, school_id, term),
                         )
                         .map_err(|e| SqliteError::FailedSqliteQuery { query_info: "insert previous term collelctions".to_string(), source: e })?;
@@ -400,10 +1498,13 @@ impl Datastore for Sqlite {
                 }
                 sync_requests::SchoolEntry::Sequence(sequence) => {
                     tx.execute(
-                        r#"
+                        &prefix_tables(
+                            r#"
                         INSERT INTO _previous_school_collections (synced_at, school_id)
                         VALUES ($1, $2);
                         "#,
+                            &self.apply.table_qualifier,
+                        ),
                         (sequence, school_id),
                     )
                     .map_err(|e| SqliteError::FailedSqliteQuery {
@@ -413,48 +1514,182 @@ impl Datastore for Sqlite {
                 }
             }
         }
-        for sync in select_sync_response.sync_data.into_iter() {
-            Self::execute_sync(&tx, sync, self.is_strict)?
+        let mut applied = self.record_hook.collector();
+        let report = Self::apply_syncs(
+            &tx,
+            select_sync_response.sync_data,
+            &self.apply,
+            applied.as_mut(),
+        )?;
+        if self.unset_finished_terms && !select_sync_response.any_has_more {
+            Self::unset_finished_terms(&tx, &self.apply.table_qualifier)?;
         }
-        tx.commit().map_err(SqliteError::from)?;
-        Ok(())
+        finish_transaction(tx, self.no_commit)?;
+        if !self.no_commit {
+            self.record_hook
+                .committed(applied, !self.conn.is_autocommit());
+        }
+        #[cfg(feature = "tracing")]
+        record_report_counts(&report);
+        Ok(report)
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Sqlite {
+    /// Runs a query returning the first column of its only row
+    pub fn raw_query_scalar<T: rusqlite::types::FromSql>(
+        &self,
+        query: &str,
+    ) -> Result<T, SqliteError> {
+        self.conn
+            .query_row(query, (), |row| row.get(0))
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: format!("raw query `{query}`"),
+                source: e,
+            })
+    }
+
+    pub fn last_all_sync_sequence(&self) -> Result<u64, SqliteError> {
+        self.raw_query_scalar(&prefix_tables(
+            "SELECT COALESCE(MAX(synced_at), 0) FROM _previous_all_collections",
+            &self.apply.table_qualifier,
+        ))
+    }
+
+    /// Every `(school_id, term_collection_id)` set to be synced ordered by school then term
+    pub fn registered_strategies(&self) -> Result<Vec<(String, Option<String>)>, SqliteError> {
+        let mut strategies_query = self.conn.prepare(&prefix_tables(
+            r#"
+            SELECT school_id, term_collection_id
+            FROM _school_strategies
+            ORDER BY school_id, term_collection_id
+            "#,
+            &self.apply.table_qualifier,
+        ))?;
+        strategies_query
+            .query_map((), |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "collecting registered strategies".to_string(),
+                source: e,
+            })
+    }
+}
+
+impl Datastore for Sqlite {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(
+                mode = "all",
+                records = all_sync_response.sync_data.len(),
+                applied = tracing::field::Empty,
+                skipped = tracing::field::Empty,
+            )
+        )
+    )]
+    fn execute_all_request_sync(
+        &mut self,
+        all_sync_response: AllSyncResult,
+    ) -> Result<SyncReport, DataStoreError> {
+        self.with_apply_timeout(|sqlite| sqlite.apply_all_sync(all_sync_response))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip_all,
+            fields(
+                mode = "select",
+                records = select_sync_response.sync_data.len(),
+                applied = tracing::field::Empty,
+                skipped = tracing::field::Empty,
+            )
+        )
+    )]
+    fn execute_select_request_sync(
+        &mut self,
+        select_sync_request: SelectSync,
+        select_sync_response: TermSyncResult,
+    ) -> Result<SyncReport, DataStoreError> {
+        self.with_apply_timeout(|sqlite| {
+            sqlite.apply_select_sync(select_sync_request, select_sync_response)
+        })
     }
 
     fn generate_sync_options(&mut self) -> Result<SyncOptions, DataStoreError> {
-        match (self.is_select_sync()?, self.is_all_sync()?) {
-            (true, true) => Err(SqliteError::DataIntegrityError(
+        match self.sync_mode()? {
+            SyncMode::Dirty => Err(SqliteError::DataIntegrityError(
                 "dirty db state cannot be both select and all sync".to_string(),
             ))?,
-            (true, false) => Ok(SyncOptions::Select(self.get_select_request_options()?)),
-            (false, true) => Ok(SyncOptions::All(self.get_all_request_options()?)),
-            (false, false) => Err(SqliteError::DataIntegrityError(
-                "sync stratgey not set, Set the resources to sync".to_string(),
-            ))?,
+            SyncMode::Select => Ok(SyncOptions::Select(self.get_select_request_options()?)),
+            SyncMode::All => Ok(SyncOptions::All(self.get_all_request_options()?)),
+            SyncMode::Unset => Err(SqliteError::NoSyncResources)?,
         }
     }
 
+    fn count_pending_strategies(&mut self) -> Result<usize, DataStoreError> {
+        Ok(self
+            .conn
+            .query_row(
+                &prefix_tables(
+                    "SELECT COUNT(*) FROM _school_strategies;",
+                    &self.apply.table_qualifier,
+                ),
+                (),
+                |row| row.get(0),
+            )
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "counting strategies".to_string(),
+                source: e,
+            })?)
+    }
+
+    fn sync_mode(&mut self) -> Result<SyncMode, DataStoreError> {
+        Ok(match (self.is_select_sync()?, self.is_all_sync()?) {
+            (true, true) => SyncMode::Dirty,
+            (true, false) => SyncMode::Select,
+            (false, true) => SyncMode::All,
+            (false, false) => SyncMode::Unset,
+        })
+    }
+
     fn set_request_sync_resources(
         &mut self,
         resources: SyncResources,
-    ) -> Result<(), DataStoreError> {
+    ) -> Result<Vec<Warning>, DataStoreError> {
+        let mut warnings = vec![];
         match resources {
-            SyncResources::Everything => {
+            SyncResources::Everything(tables) => {
                 if self.is_select_sync()? {
                     Err(SqliteError::DataIntegrityError(
                         "Cannot set sync all because select syncs have already been done"
                             .to_string(),
                     ))?
                 }
+                // changing the filter later does not bring back records of tables that were
+                // filtered out by previous syncs
+                Self::write_json_meta(
+                    &self.conn,
+                    &self.apply.table_qualifier,
+                    ALL_SYNC_TABLES_KEY,
+                    tables.as_ref(),
+                )?;
                 // is already set to sync all so do nothing
                 if self.is_all_sync()? {
-                    return Ok(());
+                    return Ok(warnings);
                 }
                 self.conn
                     .execute(
-                        r#"
+                        &prefix_tables(
+                            r#"
                     INSERT INTO _previous_all_collections (synced_at)
                     VALUES (0);
                     "#,
+                            &self.apply.table_qualifier,
+                        ),
                         (),
                     )
                     .map_err(|e| SqliteError::FailedSqliteQuery {
@@ -468,14 +1703,16 @@ impl Datastore for Sqlite {
                         "Cannot set sync select because sync all has already been done".to_string(),
                     ))?
                 }
-                let mut get_full_schools = self
-                    .conn
-                    .prepare(
+                // every strategy of the options is written or none are when one conflicts
+                let tx = self.conn.transaction().map_err(SqliteError::from)?;
+                let mut get_full_schools = tx
+                    .prepare(&prefix_tables(
                         r#"
                     SELECT school_id, term_collection_id
                     FROM _school_strategies
                     "#,
-                    )
+                        &self.apply.table_qualifier,
+                    ))
                     .map_err(SqliteError::from)?;
                 let mut full_school_collections: HashSet<(String, Option<String>)> = HashSet::new();
                 let full_school_collections_rows = get_full_schools
@@ -493,58 +1730,86 @@ impl Datastore for Sqlite {
                 for f in full_school_collections_rows {
                     full_school_collections.insert(f);
                 }
+                drop(get_full_schools);
 
+                let mut conflicts = vec![];
                 for (school_id, collection_type) in select_sync_options.get_collections() {
                     match collection_type {
                         CollectionType::AllSchoolData => {
-                            if !full_school_collections.contains(&(school_id.clone(), None)) {
-                                self.conn
-                                    .execute(
+                            if full_school_collections.contains(&(school_id.clone(), None)) {
+                                warn!("School `{school_id}` is already being synced");
+                                warnings.push(Warning::AlreadySyncing {
+                                    school_id: school_id.clone(),
+                                    term_collection_id: None,
+                                });
+                            } else {
+                                tx.execute(
+                                    &prefix_tables(
                                         r#"
                                     INSERT INTO _school_strategies
                                     (school_id, term_collection_id)
                                     VALUES (?, NULL)
                                     "#,
-                                        [school_id],
-                                    )
-                                    .map_err(|e| SqliteError::FailedSqliteQuery {
+                                        &self.apply.table_qualifier,
+                                    ),
+                                    [school_id],
+                                )
+                                .map_err(|e| {
+                                    SqliteError::FailedSqliteQuery {
                                         query_info: "insert all school strategies".to_string(),
                                         source: e,
-                                    })?;
+                                    }
+                                })?;
                             }
                         }
                         CollectionType::SelectTermData(terms) => {
                             if full_school_collections.contains(&(school_id.clone(), None)) {
-                                Err(SqliteError::DataIntegrityError(format!(
+                                conflicts.push(format!(
                                     "Cannot do select term sync for school `{school_id}` because the whole school as been synced"
-                                )))?
+                                ));
+                                continue;
                             }
                             for term in terms {
-                                if !full_school_collections
+                                if full_school_collections
                                     .contains(&(school_id.clone(), Some(term.clone())))
                                 {
-                                    self.conn
-                                        .execute(
+                                    warn!("Term `{term}` of `{school_id}` is already being synced");
+                                    warnings.push(Warning::AlreadySyncing {
+                                        school_id: school_id.clone(),
+                                        term_collection_id: Some(term.clone()),
+                                    });
+                                } else {
+                                    tx.execute(
+                                        &prefix_tables(
                                             r#"
                                         INSERT INTO _school_strategies
                                         (school_id, term_collection_id)
                                         VALUES (?, ?)
                                         "#,
-                                            [school_id, term],
-                                        )
-                                        .map_err(|e| SqliteError::FailedSqliteQuery {
+                                            &self.apply.table_qualifier,
+                                        ),
+                                        [school_id, term],
+                                    )
+                                    .map_err(|e| {
+                                        SqliteError::FailedSqliteQuery {
                                             query_info: "insert select school strategies"
                                                 .to_string(),
                                             source: e,
-                                        })?;
+                                        }
+                                    })?;
                                 }
                             }
                         }
                     }
                 }
+                // dropping the transaction rolls back the strategies written before a conflict
+                if !conflicts.is_empty() {
+                    Err(SqliteError::ConflictingSyncResources(conflicts))?
+                }
+                tx.commit().map_err(SqliteError::from)?;
             }
         }
-        Ok(())
+        Ok(warnings)
     }
 
     fn unset_request_sync_resources(
@@ -555,97 +1820,754 @@ impl Datastore for Sqlite {
         todo!()
     }
 
-    fn add_schools(&mut self, schools: Vec<sync_requests::School>) -> Result<(), DataStoreError> {
+    fn add_schools(
+        &mut self,
+        schools: Vec<sync_requests::School>,
+    ) -> Result<Vec<String>, DataStoreError> {
         let tx = self.conn.transaction().map_err(SqliteError::from)?;
+        let mut existing = vec![];
         for school in schools {
-            tx.execute(
-                r#"
+            let inserted = tx
+                .execute(
+                    &prefix_tables(
+                        r#"
             INSERT INTO schools (id, name)
-            VALUES ($1, $2);
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING;
+            "#,
+                        &self.apply.table_qualifier,
+                    ),
+                    (&school.id, school.name),
+                )
+                .map_err(|e| SqliteError::FailedSqliteQuery {
+                    query_info: "insert schools".to_string(),
+                    source: e,
+                })?;
+            if inserted == 0 {
+                existing.push(school.id);
+            }
+        }
+        tx.commit().map_err(SqliteError::from)?;
+        Ok(existing)
+    }
+
+    fn add_terms(
+        &mut self,
+        terms: Vec<sync_requests::Term>,
+    ) -> Result<Vec<(String, String)>, DataStoreError> {
+        let tx = self.conn.transaction().map_err(SqliteError::from)?;
+        let mut existing = vec![];
+        for term in terms {
+            let season = term
+                .verify()
+                .map_err(|e| SqliteError::ValueConversionError(e.to_string()))?;
+            let inserted = tx
+                .execute(
+                    &prefix_tables(
+                        r#"
+            INSERT INTO term_collections (id, school_id, year, season, name, still_collecting)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT DO NOTHING;
             "#,
-                (school.id, school.name),
+                        &self.apply.table_qualifier,
+                    ),
+                    (
+                        &term.id,
+                        &term.school_id,
+                        term.year,
+                        season.to_string(),
+                        term.name,
+                        term.still_collecting,
+                    ),
+                )
+                .map_err(|e| SqliteError::FailedSqliteQuery {
+                    query_info: "insert terms".to_string(),
+                    source: e,
+                })?;
+            if inserted == 0 {
+                existing.push((term.school_id, term.id));
+            }
+        }
+        tx.commit().map_err(SqliteError::from)?;
+        Ok(existing)
+    }
+
+    fn prune_before(&mut self, sequence: u64) -> Result<usize, DataStoreError> {
+        let tx = self.conn.transaction().map_err(SqliteError::from)?;
+        let pruned_terms = tx
+            .execute(
+                &prefix_tables(
+                    r#"
+                DELETE FROM _previous_term_collections
+                WHERE synced_at < ?1
+                    AND synced_at < (
+                        SELECT MAX(m.synced_at)
+                        FROM _previous_term_collections m
+                        WHERE m.school_id = _previous_term_collections.school_id
+                            AND m.term_collection_id = _previous_term_collections.term_collection_id
+                    );
+                "#,
+                    &self.apply.table_qualifier,
+                ),
+                [sequence],
             )
             .map_err(|e| SqliteError::FailedSqliteQuery {
-                query_info: "insert schools".to_string(),
+                query_info: "pruning previous term collections".to_string(),
+                source: e,
+            })?;
+        let pruned_schools = tx
+            .execute(
+                &prefix_tables(
+                    r#"
+                DELETE FROM _previous_school_collections
+                WHERE synced_at < ?1
+                    AND synced_at < (
+                        SELECT MAX(m.synced_at)
+                        FROM _previous_school_collections m
+                        WHERE m.school_id = _previous_school_collections.school_id
+                    );
+                "#,
+                    &self.apply.table_qualifier,
+                ),
+                [sequence],
+            )
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "pruning previous school collections".to_string(),
                 source: e,
             })?;
-        }
         tx.commit().map_err(SqliteError::from)?;
+        Ok(pruned_terms + pruned_schools)
+    }
+
+    fn is_persistent(&mut self) -> Result<bool, DataStoreError> {
+        Ok(self.database_file()?.is_some())
+    }
+
+    fn describe(&mut self) -> Result<DatastoreInfo, DataStoreError> {
+        let path = self.database_file()?;
+        Ok(DatastoreInfo {
+            backend: "sqlite",
+            in_memory: path.is_none(),
+            path,
+            schema_version: self.schema_version()?,
+        })
+    }
+
+    fn begin_sync(&mut self) -> Result<(), DataStoreError> {
+        if !self.conn.is_autocommit() {
+            Err(SqliteError::UnsupportedSyncOperation(
+                "a sync has already begun".to_string(),
+            ))?
+        }
+        self.conn
+            .execute_batch("BEGIN;")
+            .map_err(SqliteError::from)?;
+        Ok(())
+    }
+
+    fn finish_sync(&mut self, commit: bool) -> Result<(), DataStoreError> {
+        if self.conn.is_autocommit() {
+            Err(SqliteError::UnsupportedSyncOperation(
+                "no sync has begun".to_string(),
+            ))?
+        }
+        let finish = if commit { "COMMIT;" } else { "ROLLBACK;" };
+        let finished = self.conn.execute_batch(finish);
+        self.record_hook.finish_sync(commit && finished.is_ok());
+        finished.map_err(SqliteError::from)?;
         Ok(())
     }
 
-    fn add_terms(&mut self, terms: Vec<sync_requests::Term>) -> Result<(), DataStoreError> {
+    fn set_record_hook(&mut self, hook: Option<RecordHook>) -> Result<(), DataStoreError> {
+        self.record_hook.hook = hook;
+        Ok(())
+    }
+
+    fn resync_table(&mut self, table: TableName) -> Result<(), DataStoreError> {
+        if !self.is_all_sync()? {
+            Err(SqliteError::UnsupportedSyncOperation(
+                "Resyncing a table is only supported for all syncs".to_string(),
+            ))?
+        }
+        if matches!(table, TableName::Unknown(_)) {
+            Err(SqliteError::UnsupportedSyncOperation(
+                "Cannot resync a table unknown to this version".to_string(),
+            ))?
+        }
+        let last_sync = self.get_all_request_options()?.last_sync;
+        let qualifier = self.apply.table_qualifier.clone();
+        // rows referencing the cleared ones cannot be kept so their tables are fetched again too
+        let cleared = [referencing_tables(&table), vec![table]].concat();
         let tx = self.conn.transaction().map_err(SqliteError::from)?;
-        for term in terms {
+        // a resync that is still going keeps the point its tables were cleared at
+        let mut resync = Self::read_resync(&tx, &qualifier)?.unwrap_or(Resync {
+            tables: HashSet::new(),
+            until: last_sync,
+        });
+        for table in cleared {
             tx.execute(
-                r#"
-            INSERT INTO terms (id, school_id, year, season, name, still_collecting)
-            VALUES ($1, $2, $3, $4, $5, $6);
-            "#,
-                (
-                    term.id,
-                    term.school_id,
-                    term.year,
-                    term.season,
-                    term.name,
-                    term.still_collecting,
-                ),
+                &format!("DELETE FROM {};", self.apply.quoted_table(&table)),
+                (),
             )
             .map_err(|e| SqliteError::FailedSqliteQuery {
-                query_info: "insert schools".to_string(),
+                query_info: format!("clearing `{table}`"),
                 source: e,
             })?;
+            resync.tables.insert(table);
         }
+        tx.execute(
+            &prefix_tables(
+                r#"
+            UPDATE _previous_all_collections SET synced_at = 0;
+            "#,
+                &qualifier,
+            ),
+            (),
+        )
+        .map_err(|e| SqliteError::FailedSqliteQuery {
+            query_info: "resetting previous all collections".to_string(),
+            source: e,
+        })?;
+        Self::write_json_meta(&tx, &qualifier, RESYNC_KEY, Some(&resync))?;
         tx.commit().map_err(SqliteError::from)?;
         Ok(())
     }
-}
 
-// This helper function also needs to return SqliteError
-fn convert_to_sql_value(v: &Value) -> Result<rusqlite::types::Value, SqliteError> {
-    match v {
-        Value::String(s) => Ok(rusqlite::types::Value::Text(s.to_string())),
-        Value::Null => Ok(rusqlite::types::Value::Null),
-        Value::Bool(b) => Ok(rusqlite::types::Value::Integer(*b as i64)),
-        Value::Number(n) => {
-            if let Some(n) = n.as_i64() {
-                Ok(rusqlite::types::Value::Integer(n))
-            } else if let Some(n) = n.as_f64() {
-                Ok(rusqlite::types::Value::Real(n))
-            } else {
-                // If the number cannot be represented as i64 or f64, treat as null or error
-                // Changed to an error for explicit handling
-                Err(SqliteError::ValueConversionError(format!(
-                    "Unsupported number format: {n:?}"
-                )))
+    fn get_row(
+        &mut self,
+        table: TableName,
+        pk: &HashMap<String, Value>,
+    ) -> Result<Option<HashMap<String, Value>>, DataStoreError> {
+        let invalid_columns: Vec<&String> = pk
+            .keys()
+            .filter(|col| !sync_requests::is_valid_column(col))
+            .collect();
+        if pk.is_empty() || !invalid_columns.is_empty() {
+            Err(SqliteError::ValueConversionError(format!(
+                "invalid primary key columns {invalid_columns:?} for `{table}`"
+            )))?
+        }
+        let (where_values, param_args) = pk_where(pk, &self.apply)?;
+        let sql_string = format!(
+            "SELECT * FROM {} WHERE {where_values};",
+            self.apply.quoted_table(table),
+        );
+        let mut statement = self.conn.prepare(&sql_string).map_err(SqliteError::from)?;
+        let columns: Vec<String> = statement
+            .column_names()
+            .into_iter()
+            .map(String::from)
+            .collect();
+        Ok(statement
+            .query_row(params_from_iter(param_args), |row| {
+                columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, col)| Ok((col.clone(), convert_from_sql_value(row.get_ref(i)?))))
+                    .collect()
+            })
+            .optional()
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: format!("get row `{sql_string}`"),
+                source: e,
+            })?)
+    }
+
+    fn read_only_query(&mut self, sql: &str) -> Result<QueryRows, DataStoreError> {
+        let read_only = self.read_only_connection()?;
+        Ok(query_rows(read_only.as_ref().unwrap_or(&self.conn), sql)?)
+    }
+
+    fn explain(&mut self, records: Vec<ClassDataSync>) -> Result<Vec<QueryPlan>, DataStoreError> {
+        let mut warnings = vec![];
+        let mut explained = HashSet::new();
+        let mut plans = vec![];
+        for sync in records {
+            // records of unknown tables are skipped by syncs unless they are strict about them
+            if matches!(sync.table_name, TableName::Unknown(_)) {
+                continue;
             }
+            let Some((statement, param_args)) = Self::into_sql(&sync, &self.apply, &mut warnings)?
+            else {
+                continue;
+            };
+            // records with the same columns make the same statement
+            if !explained.insert(statement.clone()) {
+                continue;
+            }
+            let mut explain = self
+                .conn
+                .prepare(&format!("EXPLAIN QUERY PLAN {statement}"))
+                .map_err(SqliteError::from)?;
+            let steps = explain
+                .query_map(params_from_iter(param_args), |row| row.get::<_, String>(3))
+                .and_then(|steps| steps.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| SqliteError::FailedSqliteQuery {
+                    query_info: format!("explaining `{statement}`"),
+                    source: e,
+                })?;
+            plans.push(QueryPlan { statement, steps });
         }
-        _ => Err(SqliteError::ValueConversionError(format!(
-            "Unsupported type {v:?}"
-        ))),
+        Ok(plans)
     }
-}
 
-#[cfg(test)]
-mod sync_tests {
-    use super::*;
-    use log::info;
-    use serde_json::from_str;
-    use std::{fs, path::PathBuf};
+    fn bookkeeping(&mut self) -> Result<Vec<(String, QueryRows)>, DataStoreError> {
+        let prefix = &self.apply.table_qualifier;
+        let bookkeeping = [
+            (
+                "_previous_all_collections",
+                "SELECT synced_at, created_at FROM _previous_all_collections ORDER BY synced_at",
+            ),
+            (
+                "_previous_school_collections",
+                "SELECT school_id, synced_at, created_at FROM _previous_school_collections ORDER BY school_id, synced_at",
+            ),
+            (
+                "_previous_term_collections",
+                "SELECT school_id, term_collection_id, synced_at, created_at FROM _previous_term_collections ORDER BY school_id, term_collection_id, synced_at",
+            ),
+            (
+                "_school_strategies",
+                "SELECT school_id, term_collection_id FROM _school_strategies ORDER BY school_id, term_collection_id",
+            ),
+        ];
+        let mut tables = Vec::with_capacity(bookkeeping.len());
+        for (table, query) in bookkeeping {
+            let rows = query_rows(&self.conn, &prefix_tables(query, prefix))?;
+            tables.push((table.to_string(), rows));
+        }
+        Ok(tables)
+    }
 
-    // note if not using an in-memory database only run a single test or use --test-threads=1
-    //   which will leave your database with the last sqlite test data in the db
-    #[test]
-    fn full_sync() {
-        // dotenv().ok();
-        // env_logger::init();
-        let mut sqlite = Sqlite::new(SqliteConfig {
-            ..Default::default()
-        })
-        .unwrap();
+    fn expected_schema_version(&mut self) -> Result<u32, DataStoreError> {
+        Ok(UP_MIGRATIONS[UP_MIGRATIONS.len() - 1].0)
+    }
 
-        let mut stored_syncs = Vec::new();
-        let directory_of_test_syncs = "test-syncs/maristfall2024";
+    fn schema_version(&mut self) -> Result<u32, DataStoreError> {
+        let is_versioned = table_exists(
+            &self.conn,
+            &self.apply.table_qualifier,
+            "_schema_migrations",
+        )?;
+        // databases created before migrations were versioned
+        if !is_versioned {
+            return Ok(0);
+        }
+        Ok(applied_schema_version(
+            &self.conn,
+            &self.apply.table_qualifier,
+            true,
+        )?)
+    }
+
+    fn integrity_check(&mut self) -> Result<bool, DataStoreError> {
+        let mut integrity_query = self
+            .conn
+            .prepare("PRAGMA integrity_check;")
+            .map_err(SqliteError::from)?;
+        let integrity_problems = integrity_query
+            .query_map((), |r| r.get::<_, String>(0))
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "integrity check".to_string(),
+                source: e,
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "collecting integrity check".to_string(),
+                source: e,
+            })?
+            .into_iter()
+            // a sound database reports a single `ok` row
+            .filter(|problem| problem != "ok")
+            .collect::<Vec<_>>();
+        for problem in &integrity_problems {
+            warn!("Integrity check problem: {problem}");
+        }
+
+        let mut foreign_key_query = self
+            .conn
+            .prepare("PRAGMA foreign_key_check;")
+            .map_err(SqliteError::from)?;
+        let foreign_key_problems = foreign_key_query
+            .query_map((), |r| {
+                let res: (String, Option<i64>, String) = (r.get(0)?, r.get(1)?, r.get(2)?);
+                Ok(res)
+            })
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "foreign key check".to_string(),
+                source: e,
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "collecting foreign key check".to_string(),
+                source: e,
+            })?;
+        for (table, rowid, parent) in &foreign_key_problems {
+            warn!("Foreign key problem: `{table}` row {rowid:?} has no parent in `{parent}`");
+        }
+
+        Ok(integrity_problems.is_empty() && foreign_key_problems.is_empty())
+    }
+
+    fn flush(&mut self) -> Result<(), DataStoreError> {
+        // a database not in WAL mode has nothing to checkpoint and reports a log of -1
+        let (busy, log, checkpointed): (i64, i64, i64) = self
+            .conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE);", (), |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "checkpointing the write ahead log".to_string(),
+                source: e,
+            })?;
+        // the applied syncs are already committed so an open reader only delays the checkpoint
+        if busy != 0 {
+            warn!(
+                "Checkpoint was blocked by another connection after {checkpointed} of {log} pages"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Commits the transaction of an applied sync response or rolls it back when only inspecting it
+fn finish_transaction(mut tx: Savepoint, no_commit: bool) -> Result<(), SqliteError> {
+    if no_commit {
+        tx.set_drop_behavior(DropBehavior::Rollback);
+        tx.finish()?;
+    } else {
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Points every migrated table in a statement at its prefixed name within its schema
+///
+/// Foreign keys can only reference tables of their own schema so those are only prefixed
+fn prefix_tables<'a>(sql: &'a str, qualifier: &str) -> Cow<'a, str> {
+    if qualifier.is_empty() {
+        return Cow::Borrowed(sql);
+    }
+    let prefix = qualifier
+        .split_once('.')
+        .map_or(qualifier, |(_, prefix)| prefix);
+    MIGRATED_TABLE.replace_all(sql, |captures: &regex::Captures| match captures.get(1) {
+        Some(references) => format!("{}{prefix}{}", references.as_str(), &captures[2]),
+        None => format!("{qualifier}{}", &captures[2]),
+    })
+}
+
+/// Runs a statement that does not write returning every row
+fn query_rows(conn: &Connection, sql: &str) -> Result<QueryRows, SqliteError> {
+    let mut statement = conn
+        .prepare(sql)
+        .map_err(|e| SqliteError::FailedSqliteQuery {
+            query_info: format!("preparing `{sql}`"),
+            source: e,
+        })?;
+    // what an in memory database runs is only kept from writing by this check
+    if !statement.readonly() {
+        return Err(SqliteError::UnsupportedSyncOperation(format!(
+            "`{sql}` would change the database"
+        )));
+    }
+    let columns: Vec<String> = statement
+        .column_names()
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let rows = statement
+        .query_map((), |row| {
+            (0..columns.len())
+                .map(|i| Ok(convert_from_sql_value(row.get_ref(i)?)))
+                .collect()
+        })
+        .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| SqliteError::FailedSqliteQuery {
+            query_info: format!("running `{sql}`"),
+            source: e,
+        })?;
+    Ok(QueryRows { columns, rows })
+}
+
+/// The fields ordered by column so records with the same columns make the same statement which
+/// keeps them sharing one prepared statement
+fn by_column(fields: &HashMap<String, Value>) -> Vec<(&String, &Value)> {
+    let mut fields: Vec<_> = fields.iter().collect();
+    fields.sort_unstable_by_key(|(col, _)| *col);
+    fields
+}
+
+/// Binds the values of the fields in column order returning each quoted column with the number
+/// of its placeholder
+fn bind_columns<'a>(
+    fields: impl IntoIterator<Item = (&'a String, &'a Value)>,
+    options: &ApplyOptions,
+    warnings: &mut Vec<Warning>,
+    param_args: &mut Vec<rusqlite::types::Value>,
+) -> Result<Vec<(String, usize)>, SqliteError> {
+    fields
+        .into_iter()
+        .map(|(col, val)| {
+            param_args.push(options.to_sql(col, val, warnings)?);
+            Ok((options.quoting.quote(col), param_args.len()))
+        })
+        .collect()
+}
+
+fn assignments(columns: &[(String, usize)], separator: &str) -> String {
+    columns
+        .iter()
+        .map(|(col, placeholder)| format!("{col} = ?{placeholder}"))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// The condition matching the row of a primary key with its values bound in column order so a
+/// compound key always makes the same condition
+fn pk_where(
+    pk: &HashMap<String, Value>,
+    options: &ApplyOptions,
+) -> Result<(String, Vec<rusqlite::types::Value>), SqliteError> {
+    let mut param_args = vec![];
+    let mut where_values = vec![];
+    for (col, val) in by_column(pk) {
+        param_args.push(options.codec().to_sql(val)?);
+        where_values.push((options.quoting.quote(col), param_args.len()));
+    }
+    Ok((assignments(&where_values, " AND "), param_args))
+}
+
+/// The statement updating the row of the record's primary key with its fields
+fn build_update_sql(
+    sync: &ClassDataSync,
+    options: &ApplyOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<(String, Vec<rusqlite::types::Value>), SqliteError> {
+    let mut param_args = vec![];
+    let set_values = bind_columns(
+        sync.relevant_fields.iter().flat_map(by_column),
+        options,
+        warnings,
+        &mut param_args,
+    )?;
+    let where_values = bind_columns(
+        by_column(&sync.pk_fields),
+        options,
+        warnings,
+        &mut param_args,
+    )?;
+    let sql_string = format!(
+        "UPDATE {} SET {} WHERE {};",
+        options.quoted_table(&sync.table_name),
+        assignments(&set_values, ", "),
+        assignments(&where_values, " AND ")
+    );
+    Ok((sql_string, param_args))
+}
+
+/// The statement inserting the record's primary key and fields as a new row
+fn build_insert_sql(
+    sync: &ClassDataSync,
+    options: &ApplyOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<(String, Vec<rusqlite::types::Value>), SqliteError> {
+    let mut param_args = vec![];
+    let fields = by_column(&sync.pk_fields)
+        .into_iter()
+        .chain(sync.relevant_fields.iter().flat_map(by_column));
+    let (columns, values): (Vec<_>, Vec<_>) =
+        bind_columns(fields, options, warnings, &mut param_args)?
+            .into_iter()
+            .map(|(col, placeholder)| (col, format!("?{placeholder}")))
+            .unzip();
+    let sql_string = format!(
+        "INSERT INTO {} ({}) VALUES ({});",
+        options.quoted_table(&sync.table_name),
+        columns.join(", "),
+        values.join(", ")
+    );
+    Ok((sql_string, param_args))
+}
+
+/// The statement deleting the row of the record's primary key
+fn build_delete_sql(
+    sync: &ClassDataSync,
+    options: &ApplyOptions,
+    warnings: &mut Vec<Warning>,
+) -> Result<(String, Vec<rusqlite::types::Value>), SqliteError> {
+    let mut param_args = vec![];
+    let where_values = bind_columns(
+        by_column(&sync.pk_fields),
+        options,
+        warnings,
+        &mut param_args,
+    )?;
+    let sql_string = format!(
+        "DELETE FROM {} WHERE {};",
+        options.quoted_table(&sync.table_name),
+        assignments(&where_values, " AND ")
+    );
+    Ok((sql_string, param_args))
+}
+
+/// The latest migration applied to the database
+///
+/// Files from before migrations were versioned are at the latest migration whose tables they have
+fn applied_schema_version(
+    conn: &Connection,
+    qualifier: &str,
+    is_versioned: bool,
+) -> Result<u32, SqliteError> {
+    if is_versioned {
+        return conn
+            .query_row(
+                &prefix_tables(
+                    "SELECT COALESCE(MAX(version), 0) FROM _schema_migrations;",
+                    qualifier,
+                ),
+                (),
+                |row| row.get(0),
+            )
+            .map_err(|e| SqliteError::FailedSqliteQuery {
+                query_info: "getting schema version".to_string(),
+                source: e,
+            });
+    }
+    let mut applied = 0;
+    for (version, table) in EXPECTED_TABLES {
+        if table_exists(conn, qualifier, table)? {
+            applied = applied.max(version);
+        }
+    }
+    Ok(applied)
+}
+
+/// Whether a migrated table exists in the schema of the qualifier
+fn table_exists(conn: &Connection, qualifier: &str, table: &str) -> Result<bool, SqliteError> {
+    let (schema, prefix) = qualifier.split_once('.').unwrap_or(("main", qualifier));
+    conn.query_row(
+        &format!(
+            "SELECT EXISTS (SELECT 1 FROM \"{schema}\".sqlite_master WHERE type = 'table' AND name = ?1);"
+        ),
+        [format!("{prefix}{table}")],
+        |row| row.get(0),
+    )
+    .map_err(|e| SqliteError::FailedSqliteQuery {
+        query_info: format!("checking for table `{table}`"),
+        source: e,
+    })
+}
+
+/// Fills in the counts of the current apply span once the records are committed
+#[cfg(feature = "tracing")]
+fn record_report_counts(report: &SyncReport) {
+    let span = tracing::Span::current();
+    span.record("applied", report.applied);
+    span.record("skipped", report.skipped.len());
+}
+
+/// Every table with foreign keys to the given one ordered so referencing tables come first
+fn referencing_tables(table: &TableName) -> Vec<TableName> {
+    match table {
+        TableName::Courses | TableName::Professors => {
+            vec![TableName::MeetingTimes, TableName::Sections]
+        }
+        TableName::Sections => vec![TableName::MeetingTimes],
+        _ => vec![],
+    }
+}
+
+/// Identifies the row a record refers to regardless of the order of its primary key fields
+fn primary_key_of(sync: &ClassDataSync) -> (String, String) {
+    let pk_fields = by_column(&sync.pk_fields);
+    (sync.table_name.to_string(), format!("{pk_fields:?}"))
+}
+
+// This helper function also needs to return SqliteError
+fn convert_to_sql_value(v: &Value) -> Result<rusqlite::types::Value, SqliteError> {
+    match v {
+        Value::String(s) => Ok(rusqlite::types::Value::Text(s.to_string())),
+        Value::Null => Ok(rusqlite::types::Value::Null),
+        Value::Bool(b) => Ok(rusqlite::types::Value::Integer(*b as i64)),
+        Value::Number(n) => {
+            if let Some(n) = n.as_i64() {
+                Ok(rusqlite::types::Value::Integer(n))
+            } else if let Some(n) = n.as_f64() {
+                Ok(rusqlite::types::Value::Real(n))
+            } else {
+                // If the number cannot be represented as i64 or f64, treat as null or error
+                // Changed to an error for explicit handling
+                Err(SqliteError::ValueConversionError(format!(
+                    "Unsupported number format: {n:?}"
+                )))
+            }
+        }
+        _ => Err(SqliteError::ValueConversionError(format!(
+            "Unsupported type {v:?}"
+        ))),
+    }
+}
+
+/// Writes the bound values of a statement into it as sql literals
+fn inline_params(sql: &str, params: &[rusqlite::types::Value]) -> String {
+    // identifiers are validated so the only placeholders are the ones made for the values
+    PLACEHOLDER
+        .replace_all(sql, |captures: &regex::Captures| {
+            let index: usize = captures[1].parse().expect("placeholders are numbered");
+            sql_literal(&params[index - 1])
+        })
+        .into_owned()
+}
+
+fn sql_literal(value: &rusqlite::types::Value) -> String {
+    match value {
+        rusqlite::types::Value::Null => "NULL".to_string(),
+        rusqlite::types::Value::Integer(n) => n.to_string(),
+        // debug formatting keeps the decimal point so the value stays a real
+        rusqlite::types::Value::Real(n) => format!("{n:?}"),
+        rusqlite::types::Value::Text(s) => format!("'{}'", s.replace('\'', "''")),
+        rusqlite::types::Value::Blob(b) => {
+            let hex: String = b.iter().map(|byte| format!("{byte:02X}")).collect();
+            format!("X'{hex}'")
+        }
+    }
+}
+
+fn convert_from_sql_value(v: rusqlite::types::ValueRef) -> Value {
+    match v {
+        rusqlite::types::ValueRef::Null => Value::Null,
+        rusqlite::types::ValueRef::Integer(n) => Value::from(n),
+        rusqlite::types::ValueRef::Real(n) => Value::from(n),
+        rusqlite::types::ValueRef::Text(s) => Value::from(String::from_utf8_lossy(s)),
+        // none of the migrated columns are blobs
+        rusqlite::types::ValueRef::Blob(b) => Value::from(b),
+    }
+}
+
+#[cfg(test)]
+mod sync_tests {
+    use super::*;
+    use crate::argument_parser::SelectSyncOptions;
+    use crate::data_stores::sync_requests::SchoolEntry;
+    use indexmap::IndexMap;
+    use log::info;
+    use serde_json::from_str;
+    use std::{fs, path::PathBuf};
+
+    // note if not using an in-memory database only run a single test or use --test-threads=1
+    //   which will leave your database with the last sqlite test data in the db
+    #[test]
+    fn full_sync() {
+        // dotenv().ok();
+        // env_logger::init();
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut stored_syncs = Vec::new();
+        let directory_of_test_syncs = "test-syncs/maristfall2024";
         for entry in fs::read_dir(directory_of_test_syncs).unwrap() {
             let entry = entry.unwrap();
             let path = entry.path();
@@ -674,7 +2596,15 @@ mod sync_tests {
             let updates_text = fs::read_to_string(&full_path).unwrap();
             let response: AllSyncResult = from_str(&updates_text).unwrap();
             for update in response.sync_data {
-                let res = Sqlite::execute_sync(&tx, update, true);
+                let res = Sqlite::execute_sync(
+                    &tx,
+                    &update,
+                    &ApplyOptions {
+                        is_strict: true,
+                        ..Default::default()
+                    },
+                    &mut vec![],
+                );
                 if let Err(err) = res {
                     panic!("could not do sync {test_sync} {err}")
                 }
@@ -683,4 +2613,1977 @@ mod sync_tests {
             info!("Finished sync: {}", test_sync);
         }
     }
+
+    #[test]
+    fn integrity_check_after_sync() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        let response: AllSyncResult = from_str(&updates_text).unwrap();
+        sqlite.execute_all_request_sync(response).unwrap();
+
+        assert!(
+            sqlite.integrity_check().unwrap(),
+            "Expected a sound database"
+        );
+    }
+
+    #[test]
+    fn select_sync_bookkeeping_joins_use_indexes() {
+        for table_prefix in ["", "classy_"] {
+            let sqlite = Sqlite::new(SqliteConfig {
+                table_prefix: table_prefix.to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+            let plan = |query: &str| -> String {
+                let mut explain = sqlite
+                    .conn
+                    .prepare(&format!(
+                        "EXPLAIN QUERY PLAN {}",
+                        prefix_tables(query, &sqlite.apply.table_qualifier)
+                    ))
+                    .unwrap();
+                explain
+                    .query_map((), |row| row.get::<_, String>(3))
+                    .unwrap()
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap()
+                    .join("\n")
+            };
+            let school_plan = plan(SCHOOL_SEQUENCES_QUERY);
+            assert!(
+                school_plan.contains(&format!(
+                    "INDEX {table_prefix}previous_school_collections_by_school"
+                )),
+                "{school_plan}"
+            );
+            let term_plan = plan(TERM_SEQUENCES_QUERY);
+            assert!(
+                term_plan.contains(&format!(
+                    "INDEX {table_prefix}previous_term_collections_by_term"
+                )),
+                "{term_plan}"
+            );
+        }
+    }
+
+    #[test]
+    fn select_sync_without_strategies_guides_to_set() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        assert_eq!(sqlite.count_pending_strategies().unwrap(), 0);
+        assert!(matches!(
+            sqlite.get_select_request_options(),
+            Err(SqliteError::NoSyncResources)
+        ));
+        let err = sqlite.generate_sync_options().unwrap_err();
+        assert!(err.to_string().contains("run `set <sync instructions>`"));
+
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        assert_eq!(sqlite.count_pending_strategies().unwrap(), 1);
+        assert!(!sqlite.get_select_request_options().unwrap().is_empty());
+    }
+
+    #[test]
+    fn flush_after_sync() {
+        let db_path =
+            std::env::temp_dir().join(format!("classy-sync-flush-{}.sqlite", std::process::id()));
+        let wal_path = PathBuf::from(format!("{}-wal", db_path.display()));
+        let _ = fs::remove_file(&db_path);
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            db_path: Some(db_path.to_string_lossy().into_owned()),
+            optimize_on_drop: false,
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .conn
+            .query_row("PRAGMA journal_mode = WAL;", (), |_| Ok(()))
+            .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        let response: AllSyncResult = from_str(&updates_text).unwrap();
+        sqlite.execute_all_request_sync(response).unwrap();
+        assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+
+        sqlite.flush().unwrap();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+
+        // a reader such as `shell` blocks the checkpoint without failing it
+        let reader = Connection::open(&db_path).unwrap();
+        let mut statement = reader.prepare("SELECT id FROM schools").unwrap();
+        let mut rows = statement.query(()).unwrap();
+        rows.next().unwrap();
+        sqlite.conn.busy_timeout(Duration::ZERO).unwrap();
+        sqlite.conn.execute("DELETE FROM schools", ()).unwrap();
+        sqlite.flush().unwrap();
+        assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+        drop(rows);
+        drop(statement);
+        drop(reader);
+        drop(sqlite);
+        fs::remove_file(&db_path).unwrap();
+        let _ = fs::remove_file(&wal_path);
+        let _ = fs::remove_file(format!("{}-shm", db_path.display()));
+    }
+
+    #[test]
+    fn optimize_after_sync() {
+        let db_path = std::env::temp_dir().join(format!(
+            "classy-sync-optimize-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&db_path);
+        let config = || SqliteConfig {
+            db_path: Some(db_path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let mut sqlite = Sqlite::new(config()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        let response: AllSyncResult = from_str(&updates_text).unwrap();
+        sqlite.execute_all_request_sync(response).unwrap();
+        sqlite.optimize().unwrap();
+        // optimizing again on drop leaves a database that opens like any other
+        drop(sqlite);
+        let sqlite = Sqlite::new(SqliteConfig {
+            optimize_on_drop: false,
+            ..config()
+        })
+        .unwrap();
+        assert_eq!(sqlite.last_all_sync_sequence().unwrap(), 6303);
+        drop(sqlite);
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn continue_on_error_applies_valid_records() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            continue_on_error: true,
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let response: AllSyncResult = from_str(
+            r#"
+            {
+              "new_latest_sync": 3,
+              "has_more": false,
+              "sync_data": [
+                {
+                  "table_name": "schools",
+                  "sync_action": "insert",
+                  "pk_fields": { "id": "marist" },
+                  "relevant_fields": { "name": "Marist" }
+                },
+                {
+                  "table_name": "schools",
+                  "sync_action": "insert",
+                  "pk_fields": { "id": "temple" },
+                  "relevant_fields": { "not_a_column": "Temple" }
+                },
+                {
+                  "table_name": "schools",
+                  "sync_action": "insert",
+                  "pk_fields": { "id": "vassar" },
+                  "relevant_fields": { "name": "Vassar" }
+                }
+              ]
+            }
+            "#,
+        )
+        .unwrap();
+
+        let report = sqlite.execute_all_request_sync(response).unwrap();
+        assert_eq!(report.applied, 2);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].record.pk_fields["id"], "temple");
+
+        let schools: Vec<String> = sqlite
+            .conn
+            .prepare("SELECT id FROM schools ORDER BY id")
+            .unwrap()
+            .query_map((), |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(schools, vec!["marist", "vassar"]);
+    }
+
+    #[test]
+    fn sync_mode_states() {
+        let new_sqlite = || {
+            Sqlite::new(SqliteConfig {
+                ..Default::default()
+            })
+            .unwrap()
+        };
+
+        let mut sqlite = new_sqlite();
+        assert_eq!(sqlite.sync_mode().unwrap(), SyncMode::Unset);
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        assert_eq!(sqlite.sync_mode().unwrap(), SyncMode::All);
+
+        let mut sqlite = new_sqlite();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        assert_eq!(sqlite.sync_mode().unwrap(), SyncMode::Select);
+        assert_eq!(
+            sqlite.registered_strategies().unwrap(),
+            vec![("marist".to_string(), Some("202440".to_string()))]
+        );
+
+        // the datastore refuses to get into this state so force it
+        sqlite
+            .conn
+            .execute(
+                "INSERT INTO _previous_all_collections (synced_at) VALUES (0)",
+                (),
+            )
+            .unwrap();
+        assert_eq!(sqlite.sync_mode().unwrap(), SyncMode::Dirty);
+    }
+
+    fn row_count(sqlite: &Sqlite, table: &str) -> u64 {
+        sqlite
+            .raw_query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+            .unwrap()
+    }
+
+    #[test]
+    fn commit_every_matches_single_transaction() {
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        let synced = |commit_every| {
+            let mut sqlite = Sqlite::new(SqliteConfig {
+                commit_every,
+                ..Default::default()
+            })
+            .unwrap();
+            sqlite
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            let response: AllSyncResult = from_str(&updates_text).unwrap();
+            let report = sqlite.execute_all_request_sync(response).unwrap();
+            (sqlite, report)
+        };
+
+        let (single, single_report) = synced(None);
+        let (mut chunked, chunked_report) = synced(Some(500));
+        assert_eq!(single_report.applied, chunked_report.applied);
+        for table in [
+            "meeting_times",
+            "sections",
+            "professors",
+            "courses",
+            "term_collections",
+            "schools",
+        ] {
+            assert_eq!(row_count(&single, table), row_count(&chunked, table));
+        }
+        match chunked.get_all_request_options() {
+            Ok(all_sync) => assert_eq!(all_sync.last_sync, 6303),
+            Err(err) => panic!("Could not get all sync options {err}"),
+        }
+    }
+
+    #[test]
+    fn record_hook_sees_committed_records() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let calls = Rc::new(RefCell::new(vec![]));
+        let seen = Rc::clone(&calls);
+        sqlite
+            .set_record_hook(Some(Box::new(move |record, affected| {
+                seen.borrow_mut().push((record.clone(), affected))
+            })))
+            .unwrap();
+        let page = |page: &str| -> AllSyncResult {
+            from_str(&fs::read_to_string(format!("test-syncs/maristfall2024/{page}.json")).unwrap())
+                .unwrap()
+        };
+
+        let first = page("01");
+        let report = sqlite.execute_all_request_sync(first.clone()).unwrap();
+        assert_eq!(calls.borrow().len(), report.applied);
+        assert_eq!(
+            calls
+                .borrow()
+                .iter()
+                .map(|(_, affected)| affected)
+                .sum::<usize>(),
+            report.affected
+        );
+        for ((record, _), expected) in calls.borrow().iter().zip(first.records()) {
+            assert_eq!(record.table_name, expected.table_name);
+            assert_eq!(record.pk_fields, expected.pk_fields);
+        }
+
+        // held pages only reach the hook once they are kept
+        calls.borrow_mut().clear();
+        sqlite.begin_sync().unwrap();
+        sqlite.execute_all_request_sync(page("02")).unwrap();
+        assert!(calls.borrow().is_empty());
+        sqlite.finish_sync(false).unwrap();
+        assert!(calls.borrow().is_empty());
+
+        sqlite.begin_sync().unwrap();
+        let report = sqlite.execute_all_request_sync(page("02")).unwrap();
+        sqlite.finish_sync(true).unwrap();
+        assert_eq!(calls.borrow().len(), report.applied);
+    }
+
+    #[test]
+    fn skipped_deletes_are_counted() {
+        let synced = |skip_deletes| {
+            let mut sqlite = Sqlite::new(SqliteConfig {
+                skip_deletes,
+                ..Default::default()
+            })
+            .unwrap();
+            sqlite
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            let mut report = SyncReport::default();
+            for page in ["01", "02"] {
+                let response: AllSyncResult = from_str(
+                    &fs::read_to_string(format!("test-syncs/maristfall2024/{page}.json")).unwrap(),
+                )
+                .unwrap();
+                report.merge(sqlite.execute_all_request_sync(response).unwrap());
+            }
+            (sqlite, report)
+        };
+
+        let (mirror, mirror_report) = synced(false);
+        let (history, history_report) = synced(true);
+        assert_eq!(mirror_report.ignored_deletes, 0);
+        assert_eq!(history_report.ignored_deletes, 5);
+        assert_eq!(history_report.applied + 5, mirror_report.applied);
+        assert_eq!(history.last_all_sync_sequence().unwrap(), 6698);
+        assert_eq!(mirror.last_all_sync_sequence().unwrap(), 6698);
+        let total_rows = |sqlite: &Sqlite| -> u64 {
+            ["meeting_times", "sections", "professors", "courses"]
+                .into_iter()
+                .map(|table| row_count(sqlite, table))
+                .sum()
+        };
+        assert!(total_rows(&history) > total_rows(&mirror));
+    }
+
+    #[test]
+    fn deferred_indexes_are_created_after_initial_sync() {
+        let index_count = |sqlite: &Sqlite| -> u64 {
+            sqlite
+                .raw_query_scalar(
+                    "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name GLOB 'classy_*_by_*'",
+                )
+                .unwrap()
+        };
+        let synced = |defer_indexes| {
+            let mut sqlite = Sqlite::new(SqliteConfig {
+                defer_indexes,
+                table_prefix: "classy_".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+            sqlite
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            // the bookkeeping indexes are never deferred
+            assert_eq!(index_count(&sqlite), 8);
+            for (page, has_more) in [("01", true), ("02", false)] {
+                let mut response: AllSyncResult = from_str(
+                    &fs::read_to_string(format!("test-syncs/maristfall2024/{page}.json")).unwrap(),
+                )
+                .unwrap();
+                response.has_more = has_more;
+                sqlite.execute_all_request_sync(response).unwrap();
+                let expected_indexes = if defer_indexes && has_more { 5 } else { 8 };
+                assert_eq!(index_count(&sqlite), expected_indexes);
+            }
+            sqlite
+        };
+
+        let deferred = synced(true);
+        let kept = synced(false);
+        for table in [
+            "meeting_times",
+            "sections",
+            "professors",
+            "courses",
+            "term_collections",
+            "schools",
+        ] {
+            let table = format!("classy_{table}");
+            assert_eq!(row_count(&deferred, &table), row_count(&kept, &table));
+        }
+
+        // only an initially empty database has its indexes deferred
+        let mut deferred = deferred;
+        let mut response: AllSyncResult =
+            from_str(&fs::read_to_string("test-syncs/maristfall2024/03.json").unwrap()).unwrap();
+        response.has_more = true;
+        deferred.execute_all_request_sync(response).unwrap();
+        assert_eq!(index_count(&deferred), 8);
+    }
+
+    #[test]
+    fn same_columns_make_the_same_statement() {
+        use serde_json::json;
+
+        let fields = |columns: &[(&str, Value)]| -> HashMap<String, Value> {
+            columns
+                .iter()
+                .map(|(col, val)| (col.to_string(), val.clone()))
+                .collect()
+        };
+        let record = |action, relevant: &[(&str, Value)]| ClassDataSync {
+            table_name: TableName::Courses,
+            sync_action: action,
+            pk_fields: fields(&[
+                ("school_id", json!("marist")),
+                ("subject_code", json!("CMPT")),
+                ("number", json!("120")),
+            ]),
+            relevant_fields: Some(fields(relevant)),
+        };
+        let columns = [
+            ("title", json!("Intro to Programming")),
+            ("credit_hours", json!(4)),
+            ("description", json!(null)),
+            ("prerequisites", json!("none")),
+        ];
+        let mut reversed = columns.clone();
+        reversed.reverse();
+        for action in [SyncAction::Insert, SyncAction::Update, SyncAction::Delete] {
+            let sql = |relevant: &[(&str, Value)]| {
+                Sqlite::into_sql(
+                    &record(action, relevant),
+                    &ApplyOptions::default(),
+                    &mut vec![],
+                )
+                .unwrap()
+                .unwrap()
+            };
+            assert_eq!(sql(&columns), sql(&reversed));
+        }
+        let (update, _) = Sqlite::into_sql(
+            &record(SyncAction::Update, &columns),
+            &ApplyOptions::default(),
+            &mut vec![],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            update,
+            r#"UPDATE "courses" SET "credit_hours" = ?1, "description" = ?2, "prerequisites" = ?3, "title" = ?4 WHERE "number" = ?5 AND "school_id" = ?6 AND "subject_code" = ?7;"#
+        );
+    }
+
+    #[test]
+    fn reserved_word_columns_are_quoted() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .conn
+            .execute(r#"ALTER TABLE schools ADD COLUMN "order" INTEGER"#, ())
+            .unwrap();
+        let records: Vec<ClassDataSync> = from_str(
+            r#"
+            [
+              {
+                "table_name": "schools",
+                "sync_action": "insert",
+                "pk_fields": { "id": "marist" },
+                "relevant_fields": { "name": "Marist", "order": 1 }
+              },
+              {
+                "table_name": "schools",
+                "sync_action": "update",
+                "pk_fields": { "id": "marist" },
+                "relevant_fields": { "order": 2 }
+              }
+            ]
+            "#,
+        )
+        .unwrap();
+        let tx = sqlite.conn.transaction().unwrap();
+        for record in &records {
+            Sqlite::execute_sync(
+                &tx,
+                record,
+                &ApplyOptions {
+                    is_strict: true,
+                    quoting: IdentifierQuoting::DoubleQuote,
+                    ..Default::default()
+                },
+                &mut vec![],
+            )
+            .unwrap();
+        }
+        tx.commit().unwrap();
+        let order: u32 = sqlite
+            .conn
+            .query_row(r#"SELECT "order" FROM schools"#, (), |r| r.get(0))
+            .unwrap();
+        assert_eq!(order, 2);
+
+        let tx = sqlite.conn.transaction().unwrap();
+        let unquoted = ApplyOptions {
+            quoting: IdentifierQuoting::Unquoted,
+            ..Default::default()
+        };
+        assert!(Sqlite::execute_sync(&tx, &records[1], &unquoted, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn strict_row_counts_depend_on_the_action() {
+        let sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        let record = |sync_action: &str| -> ClassDataSync {
+            from_str(&format!(
+                r#"{{
+                "table_name": "schools",
+                "sync_action": "{sync_action}",
+                "pk_fields": {{ "id": "marist" }},
+                "relevant_fields": {{ "name": "Marist" }}
+            }}"#
+            ))
+            .unwrap()
+        };
+        let strict = ApplyOptions {
+            is_strict: true,
+            ..Default::default()
+        };
+        let apply = |action: &str, options: &ApplyOptions| {
+            Sqlite::execute_sync(&sqlite.conn, &record(action), options, &mut vec![])
+        };
+
+        // updates and deletes of a row that is not there are applied again idempotently
+        assert_eq!(apply("update", &strict).unwrap(), 0);
+        assert_eq!(apply("delete", &strict).unwrap(), 0);
+        assert_eq!(apply("insert", &strict).unwrap(), 1);
+        assert_eq!(apply("update", &strict).unwrap(), 1);
+        assert_eq!(apply("delete", &strict).unwrap(), 1);
+
+        let exact = ApplyOptions {
+            is_strict: true,
+            strict_row_counts: StrictRowCounts {
+                update: ExpectedRows::exactly(1),
+                delete: ExpectedRows::exactly(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        for action in ["update", "delete"] {
+            match apply(action, &exact) {
+                Err(SqliteError::UnexpectedQueryResult {
+                    result, expected, ..
+                }) => assert_eq!((result.as_str(), expected.as_str()), ("0", "1")),
+                applied => panic!("expected the {action} to fail but got {applied:?}"),
+            }
+        }
+        let no_inserts = ApplyOptions {
+            is_strict: true,
+            strict_row_counts: StrictRowCounts {
+                insert: ExpectedRows::exactly(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            apply("insert", &no_inserts),
+            Err(SqliteError::UnexpectedQueryResult { .. })
+        ));
+
+        // without strict mode only the counts outside the expectations are warned about
+        let warnings_of = |action: &str, strict_row_counts| {
+            let options = ApplyOptions {
+                strict_row_counts,
+                ..Default::default()
+            };
+            let mut warnings = vec![];
+            Sqlite::execute_sync(&sqlite.conn, &record(action), &options, &mut warnings).unwrap();
+            warnings
+        };
+        assert_eq!(warnings_of("update", StrictRowCounts::default()), vec![]);
+        assert_eq!(warnings_of("delete", StrictRowCounts::default()), vec![]);
+        assert_eq!(
+            warnings_of("delete", exact.strict_row_counts),
+            vec![Warning::UnexpectedRowCount {
+                table: TableName::Schools,
+                affected: 0,
+                expected: ExpectedRows::exactly(1),
+            }]
+        );
+        let at_most_one = Warning::UnexpectedRowCount {
+            table: TableName::Schools,
+            affected: 2,
+            expected: ExpectedRows::at_most(1),
+        };
+        assert_eq!(
+            at_most_one.to_string(),
+            "record of `schools` affected 2 rows expected 0 to 1"
+        );
+    }
+
+    #[test]
+    fn strictness_is_taken_from_the_config() {
+        // library callers keep warning about unexpected row counts unless they opt in
+        assert!(!SqliteConfig::default().is_strict);
+        assert!(!ApplyOptions::default().is_strict);
+        for is_strict in [true, false] {
+            let options = ApplyOptions::from_config(&SqliteConfig {
+                is_strict,
+                ..Default::default()
+            })
+            .unwrap();
+            assert_eq!(options.is_strict, is_strict);
+        }
+    }
+
+    #[test]
+    fn statements_are_built_from_records() {
+        let record = |sync_action: &str| -> ClassDataSync {
+            from_str(&format!(
+                r#"{{
+                "table_name": "meeting_times",
+                "sync_action": "{sync_action}",
+                "pk_fields": {{ "sequence": 2, "section_sequence": 1, "term_collection_id": "202440" }},
+                "relevant_fields": {{ "start_time": "09:00", "days": "MWF" }}
+            }}"#
+            ))
+            .unwrap()
+        };
+        let options = ApplyOptions::default();
+        let text = |text: &str| rusqlite::types::Value::Text(text.to_string());
+
+        let (sql, params) = build_update_sql(&record("update"), &options, &mut vec![]).unwrap();
+        assert_eq!(
+            sql,
+            r#"UPDATE "meeting_times" SET "days" = ?1, "start_time" = ?2 WHERE "section_sequence" = ?3 AND "sequence" = ?4 AND "term_collection_id" = ?5;"#
+        );
+        assert_eq!(
+            params,
+            vec![
+                text("MWF"),
+                text("09:00"),
+                rusqlite::types::Value::Integer(1),
+                rusqlite::types::Value::Integer(2),
+                text("202440"),
+            ]
+        );
+
+        let (sql, params) = build_insert_sql(&record("insert"), &options, &mut vec![]).unwrap();
+        assert_eq!(
+            sql,
+            r#"INSERT INTO "meeting_times" ("section_sequence", "sequence", "term_collection_id", "days", "start_time") VALUES (?1, ?2, ?3, ?4, ?5);"#
+        );
+        assert_eq!(params.len(), 5);
+
+        let (sql, params) = build_delete_sql(&record("delete"), &options, &mut vec![]).unwrap();
+        assert_eq!(
+            sql,
+            r#"DELETE FROM "meeting_times" WHERE "section_sequence" = ?1 AND "sequence" = ?2 AND "term_collection_id" = ?3;"#
+        );
+        assert_eq!(params.len(), 3);
+
+        // quotes inside a column are escaped unless quoting is turned off
+        let mut odd_column = record("delete");
+        odd_column.pk_fields = HashMap::from([("we\"ird".to_string(), Value::from(1))]);
+        let (sql, _) = build_delete_sql(&odd_column, &options, &mut vec![]).unwrap();
+        assert_eq!(sql, r#"DELETE FROM "meeting_times" WHERE "we""ird" = ?1;"#);
+        let unquoted = ApplyOptions {
+            quoting: IdentifierQuoting::Unquoted,
+            ..Default::default()
+        };
+        let (sql, _) = build_delete_sql(&record("delete"), &unquoted, &mut vec![]).unwrap();
+        assert_eq!(
+            sql,
+            "DELETE FROM meeting_times WHERE section_sequence = ?1 AND sequence = ?2 AND term_collection_id = ?3;"
+        );
+    }
+
+    #[test]
+    fn updates_by_primary_key_are_explained() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        let update = |name: &str| -> ClassDataSync {
+            from_str(&format!(
+                r#"{{
+                "table_name": "schools",
+                "sync_action": "update",
+                "pk_fields": {{ "id": "marist" }},
+                "relevant_fields": {{ "name": "{name}" }}
+            }}"#
+            ))
+            .unwrap()
+        };
+
+        let plans = sqlite
+            .explain(vec![update("Marist"), update("Marist College")])
+            .unwrap();
+        assert_eq!(plans.len(), 1, "{plans:?}");
+        assert_eq!(
+            plans[0].statement,
+            r#"UPDATE "schools" SET "name" = ?1 WHERE "id" = ?2;"#
+        );
+        assert!(
+            plans[0].steps.iter().any(|step| step.contains("INDEX")),
+            "{plans:?}"
+        );
+        assert_eq!(row_count(&sqlite, "schools"), 0);
+    }
+
+    #[test]
+    fn compound_keys_make_a_stable_condition() {
+        let options = ApplyOptions::default();
+        let expected = (
+            r#""school_id" = ?1 AND "term_collection_id" = ?2"#.to_string(),
+            vec![
+                rusqlite::types::Value::Text("marist".to_string()),
+                rusqlite::types::Value::Text("202440".to_string()),
+            ],
+        );
+        // every map hashes its keys differently so some of them iterate in the other order
+        for _ in 0..32 {
+            let pk = HashMap::from([
+                ("term_collection_id".to_string(), Value::from("202440")),
+                ("school_id".to_string(), Value::from("marist")),
+            ]);
+            assert_eq!(pk_where(&pk, &options).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn repeated_insert_in_page_is_collapsed() {
+        let response = || -> AllSyncResult {
+            from_str(
+                r#"
+                {
+                  "new_latest_sync": 3,
+                  "has_more": false,
+                  "sync_data": [
+                    {
+                      "table_name": "schools",
+                      "sync_action": "insert",
+                      "pk_fields": { "id": "marist" },
+                      "relevant_fields": { "name": "Marist" }
+                    },
+                    {
+                      "table_name": "schools",
+                      "sync_action": "insert",
+                      "pk_fields": { "id": "marist" },
+                      "relevant_fields": { "name": "Marist College" }
+                    },
+                    {
+                      "table_name": "schools",
+                      "sync_action": "update",
+                      "pk_fields": { "id": "marist" },
+                      "relevant_fields": { "name": "Marist University" }
+                    }
+                  ]
+                }
+                "#,
+            )
+            .unwrap()
+        };
+        let new_sqlite = |conflict_policy| {
+            let mut sqlite = Sqlite::new(SqliteConfig {
+                conflict_policy,
+                ..Default::default()
+            })
+            .unwrap();
+            sqlite
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            sqlite
+        };
+
+        let mut sqlite = new_sqlite(ConflictPolicy::Fail);
+        assert!(sqlite.execute_all_request_sync(response()).is_err());
+
+        let mut sqlite = new_sqlite(ConflictPolicy::LastWriteWins);
+        let report = sqlite.execute_all_request_sync(response()).unwrap();
+        assert_eq!(report.applied, 3);
+        let name: String = sqlite
+            .raw_query_scalar("SELECT name FROM schools WHERE id = 'marist'")
+            .unwrap();
+        assert_eq!(name, "Marist University");
+    }
+
+    #[test]
+    fn verify_record_checks_column_names() {
+        let record = |column: &str| -> ClassDataSync {
+            from_str(&format!(
+                r#"{{
+                    "table_name": "schools",
+                    "sync_action": "insert",
+                    "pk_fields": {{ "id": "marist" }},
+                    "relevant_fields": {{ "{column}": 1 }}
+                }}"#
+            ))
+            .unwrap()
+        };
+        for column in ["name", "a", "main_campus", "_order2"] {
+            assert!(record(column).verify_record().is_ok(), "{column}");
+        }
+        for column in ["1 = 1; --", "name\\\"", "2nd", "", "campus name"] {
+            assert!(record(column).verify_record().is_err(), "{column}");
+        }
+        let mut unsafe_pk = record("name");
+        unsafe_pk
+            .pk_fields
+            .insert("id; --".to_string(), serde_json::Value::from(1));
+        assert!(unsafe_pk.verify_record().is_err());
+    }
+
+    #[test]
+    fn partial_upsert_keeps_omitted_columns() {
+        let page = |sequence: u64, record: &str| -> AllSyncResult {
+            from_str(&format!(
+                r#"{{ "new_latest_sync": {sequence}, "has_more": false, "sync_data": [{record}] }}"#
+            ))
+            .unwrap()
+        };
+        let full = r#"{
+            "table_name": "courses",
+            "sync_action": "insert",
+            "pk_fields": { "school_id": "marist", "subject_code": "CMPT", "number": "120L" },
+            "relevant_fields": { "title": "Intro to Programming", "credit_hours": 4 }
+        }"#;
+        let partial = r#"{
+            "table_name": "courses",
+            "sync_action": "insert",
+            "pk_fields": { "school_id": "marist", "subject_code": "CMPT", "number": "120L" },
+            "relevant_fields": { "title": "Introduction to Programming" }
+        }"#;
+        let new_sqlite = |conflict_policy| {
+            let mut sqlite = Sqlite::new(SqliteConfig {
+                conflict_policy,
+                ..Default::default()
+            })
+            .unwrap();
+            sqlite
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            sqlite.execute_all_request_sync(page(1, full)).unwrap();
+            sqlite
+        };
+
+        let mut sqlite = new_sqlite(ConflictPolicy::Fail);
+        assert!(sqlite.execute_all_request_sync(page(2, partial)).is_err());
+
+        let mut sqlite = new_sqlite(ConflictPolicy::Upsert);
+        let report = sqlite.execute_all_request_sync(page(2, partial)).unwrap();
+        assert_eq!((report.applied, report.affected), (1, 1));
+        let title: String = sqlite
+            .raw_query_scalar("SELECT title FROM courses WHERE number = '120L'")
+            .unwrap();
+        assert_eq!(title, "Introduction to Programming");
+        let credit_hours: f64 = sqlite
+            .raw_query_scalar("SELECT credit_hours FROM courses WHERE number = '120L'")
+            .unwrap();
+        assert_eq!(credit_hours, 4.0);
+        assert_eq!(row_count(&sqlite, "courses"), 1);
+    }
+
+    #[test]
+    fn filtered_everything_skips_tables() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(
+                SyncResources::from_input("all:courses,professors").unwrap(),
+            )
+            .unwrap();
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        let response: AllSyncResult = from_str(&updates_text).unwrap();
+        let report = sqlite.execute_all_request_sync(response).unwrap();
+
+        assert_eq!(report.applied, 1141 + 429);
+        assert_eq!(report.filtered, 6303 - report.applied);
+        assert_eq!(row_count(&sqlite, "courses"), 1141);
+        assert_eq!(row_count(&sqlite, "professors"), 429);
+        assert_eq!(row_count(&sqlite, "sections"), 0);
+        assert_eq!(row_count(&sqlite, "meeting_times"), 0);
+        assert_eq!(sqlite.last_all_sync_sequence().unwrap(), 6303);
+    }
+
+    #[test]
+    fn prefixed_tables() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            table_prefix: "classy_".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        let updates: AllSyncResult = from_str(&updates_text).unwrap();
+        sqlite.execute_all_request_sync(updates).unwrap();
+
+        assert_eq!(row_count(&sqlite, "classy_courses"), 1141);
+        assert_eq!(row_count(&sqlite, "classy__previous_all_collections"), 1);
+        assert_eq!(sqlite.last_all_sync_sequence().unwrap(), 6303);
+        assert_eq!(sqlite.sync_mode().unwrap(), SyncMode::All);
+        let unprefixed: u64 = sqlite
+            .raw_query_scalar(
+                r#"
+                SELECT COUNT(*) FROM sqlite_master
+                WHERE type = 'table' AND name NOT LIKE 'classy\_%' ESCAPE '\'
+                "#,
+            )
+            .unwrap();
+        assert_eq!(unprefixed, 0);
+
+        assert!(
+            Sqlite::new(SqliteConfig {
+                table_prefix: "bad prefix;".to_string(),
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn select_options_match_fresh_store() {
+        let options = SelectSyncOptions::from_input("marist,202440,202540;temple").unwrap();
+        let expected = options.to_select_sync();
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Select(options))
+            .unwrap();
+        assert_eq!(
+            sqlite.generate_sync_options().unwrap(),
+            SyncOptions::Select(expected)
+        );
+    }
+
+    #[test]
+    fn conflicting_set_writes_no_strategies() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("temple").unwrap())
+            .unwrap();
+        // temple is already synced as a whole school so its term conflicts
+        let conflicting =
+            SyncResources::from_input("marist,202440;vassar,202440;yale;temple,202540").unwrap();
+        assert!(sqlite.set_request_sync_resources(conflicting).is_err());
+        assert_eq!(
+            sqlite.registered_strategies().unwrap(),
+            vec![("temple".to_string(), None)]
+        );
+    }
+
+    #[test]
+    fn every_conflict_is_reported() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("temple;yale").unwrap())
+            .unwrap();
+        let conflicting =
+            SyncResources::from_input("temple,202540;marist,202440;yale,202440").unwrap();
+        let err = sqlite
+            .set_request_sync_resources(conflicting)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`temple`"), "{err}");
+        assert!(err.contains("`yale`"), "{err}");
+        assert!(!err.contains("`marist`"), "{err}");
+        assert_eq!(sqlite.registered_strategies().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn schema_version_after_migrate() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(sqlite.schema_version().unwrap(), 6);
+
+        sqlite
+            .conn
+            .execute("DROP TABLE _schema_migrations", ())
+            .unwrap();
+        assert_eq!(sqlite.schema_version().unwrap(), 0);
+    }
+
+    #[test]
+    fn new_schools_and_terms_are_reported() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        let report = sqlite
+            .execute_all_request_sync(from_str(&updates_text).unwrap())
+            .unwrap();
+        assert_eq!(report.new_schools, vec!["marist".to_string()]);
+        assert_eq!(
+            report.new_terms,
+            vec![("marist".to_string(), "202440".to_string())]
+        );
+
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/02.json").unwrap();
+        let report = sqlite
+            .execute_all_request_sync(from_str(&updates_text).unwrap())
+            .unwrap();
+        assert!(report.new_schools.is_empty());
+        assert!(report.new_terms.is_empty());
+    }
+
+    #[test]
+    fn over_length_values() {
+        let long_name = "a".repeat(50);
+        let record = format!(
+            r#"{{
+                "table_name": "schools",
+                "sync_action": "insert",
+                "pk_fields": {{ "id": "marist" }},
+                "relevant_fields": {{ "name": "{long_name}" }}
+            }}"#
+        );
+        let apply = |policy| {
+            let mut sqlite = Sqlite::new(SqliteConfig {
+                max_value_length: Some(10),
+                over_length_policy: policy,
+                ..Default::default()
+            })
+            .unwrap();
+            let tx = sqlite.conn.transaction().unwrap();
+            let result = Sqlite::apply_syncs(
+                &tx,
+                vec![from_str::<ClassDataSync>(&record).unwrap()],
+                &sqlite.apply,
+                None,
+            )
+            .map(|_| ());
+            tx.commit().unwrap();
+            (sqlite, result)
+        };
+
+        let (sqlite, result) = apply(OverLengthPolicy::Reject);
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("`name` has 50 characters"), "{err}");
+        assert_eq!(row_count(&sqlite, "schools"), 0);
+
+        let (sqlite, result) = apply(OverLengthPolicy::Truncate);
+        result.unwrap();
+        let name: String = sqlite
+            .raw_query_scalar("SELECT name FROM schools WHERE id = 'marist'")
+            .unwrap();
+        assert_eq!(name, "a".repeat(10));
+    }
+
+    #[test]
+    fn get_section_by_pk() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        sqlite
+            .execute_all_request_sync(from_str(&updates_text).unwrap())
+            .unwrap();
+
+        let mut pk: HashMap<String, Value> = from_str(
+            r#"{
+                "course_number": "301N",
+                "school_id": "marist",
+                "sequence": "020",
+                "subject_code": "MEDT",
+                "term_collection_id": "202440"
+            }"#,
+        )
+        .unwrap();
+        let row = sqlite
+            .get_row(TableName::Sections, &pk)
+            .unwrap()
+            .expect("section should exist");
+        assert_eq!(row["campus"], "Marist College Campus");
+        assert_eq!(row["max_enrollment"], 0);
+        assert_eq!(row["other"], Value::Null);
+        assert_eq!(row["sequence"], "020");
+
+        pk.insert("sequence".to_string(), Value::from("999"));
+        assert_eq!(sqlite.get_row(TableName::Sections, &pk).unwrap(), None);
+        pk.insert("1 = 1; --".to_string(), Value::from(1));
+        assert!(sqlite.get_row(TableName::Sections, &pk).is_err());
+    }
+
+    #[test]
+    fn unknown_tables() {
+        let response = fs::read_to_string("test-syncs/malformed/unknown_table.json").unwrap();
+        let updates: AllSyncResult = from_str(&response).unwrap();
+        assert_eq!(
+            updates.sync_data[1].table_name,
+            TableName::Unknown("buildings".to_string())
+        );
+
+        let sync_with = |strict_tables| {
+            let mut sqlite = Sqlite::new(SqliteConfig {
+                strict_tables,
+                // the primary key of every insert is formatted with its table to collapse repeats
+                conflict_policy: ConflictPolicy::LastWriteWins,
+                ..Default::default()
+            })
+            .unwrap();
+            sqlite
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            let report = sqlite.execute_all_request_sync(from_str(&response).unwrap());
+            (sqlite, report)
+        };
+        let (sqlite, report) = sync_with(true);
+        let error = report.unwrap_err().to_string();
+        assert!(error.contains("`buildings`"), "{error}");
+        assert_eq!(row_count(&sqlite, "schools"), 0);
+
+        let (sqlite, report) = sync_with(false);
+        let report = report.unwrap();
+        assert_eq!((report.applied, report.filtered), (1, 1));
+        assert_eq!(
+            report.warnings,
+            vec![Warning::UnknownTable {
+                table: "buildings".to_string()
+            }]
+        );
+        assert_eq!(row_count(&sqlite, "schools"), 1);
+    }
+
+    #[test]
+    fn failed_page_rolls_back_alone() {
+        let mut failing_page: AllSyncResult =
+            from_str(&fs::read_to_string("test-syncs/malformed/unknown_table.json").unwrap())
+                .unwrap();
+        failing_page.has_more = true;
+        let page = |name: &str| -> AllSyncResult {
+            from_str(&fs::read_to_string(format!("test-syncs/maristfall2024/{name}")).unwrap())
+                .unwrap()
+        };
+        let sync_with = |commit| {
+            let mut sqlite = Sqlite::new(SqliteConfig {
+                commit_every: Some(100),
+                strict_tables: true,
+                ..Default::default()
+            })
+            .unwrap();
+            sqlite
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            sqlite.begin_sync().unwrap();
+            assert!(sqlite.begin_sync().is_err());
+            sqlite.execute_all_request_sync(page("01.json")).unwrap();
+            assert!(
+                sqlite
+                    .execute_all_request_sync(failing_page.clone())
+                    .is_err()
+            );
+            sqlite.execute_all_request_sync(page("02.json")).unwrap();
+            let options = sqlite.generate_sync_options().unwrap();
+            sqlite.finish_sync(commit).unwrap();
+            (sqlite, options)
+        };
+
+        let (mut sqlite, options) = sync_with(true);
+        let vassar = HashMap::from([("id".to_string(), Value::from("vassar"))]);
+        assert_eq!(sqlite.get_row(TableName::Schools, &vassar).unwrap(), None);
+        assert!(row_count(&sqlite, "sections") > 0);
+        assert_eq!(sqlite.generate_sync_options().unwrap(), options);
+
+        let (sqlite, _) = sync_with(false);
+        for table in ["schools", "courses", "sections", "meeting_times"] {
+            assert_eq!(row_count(&sqlite, table), 0);
+        }
+    }
+
+    #[test]
+    fn sync_into_attached_database() {
+        let attached_path = std::env::temp_dir().join(format!(
+            "classy-sync-attached-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&attached_path);
+        let config = || SqliteConfig {
+            attached: Some(AttachedDatabase {
+                path: attached_path.to_string_lossy().into_owned(),
+                schema: "classy".to_string(),
+            }),
+            table_prefix: "synced_".to_string(),
+            ..Default::default()
+        };
+
+        let mut sqlite = Sqlite::new(config()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let updates: AllSyncResult =
+            from_str(&fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap()).unwrap();
+        let record_count = updates.len();
+        let report = sqlite.execute_all_request_sync(updates).unwrap();
+        assert_eq!(report.applied, record_count);
+        let main_tables: u64 = sqlite
+            .raw_query_scalar("SELECT COUNT(*) FROM main.sqlite_master")
+            .unwrap();
+        assert_eq!(main_tables, 0);
+        let sections: u64 = sqlite
+            .raw_query_scalar("SELECT COUNT(*) FROM classy.synced_sections")
+            .unwrap();
+        assert!(sections > 0);
+        let options = sqlite.generate_sync_options().unwrap();
+        drop(sqlite);
+
+        // attaching the same file again keeps what was synced into it
+        let mut sqlite = Sqlite::new(config()).unwrap();
+        assert_eq!(sqlite.schema_version().unwrap(), 6);
+        let info = sqlite.describe().unwrap();
+        assert!(!info.in_memory);
+        assert_eq!(
+            info.path.map(PathBuf::from),
+            Some(attached_path.canonicalize().unwrap())
+        );
+        assert_eq!(sqlite.generate_sync_options().unwrap(), options);
+        drop(sqlite);
+        fs::remove_file(&attached_path).unwrap();
+
+        assert!(
+            Sqlite::new(SqliteConfig {
+                attached: Some(AttachedDatabase {
+                    path: IN_MEMORY_DB_PATH.to_string(),
+                    schema: "main; DROP".to_string(),
+                }),
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn duplicate_term_addition_warns() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        let warnings = sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        assert!(warnings.is_empty());
+
+        let warnings = sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440,202540").unwrap())
+            .unwrap();
+        assert_eq!(
+            warnings,
+            vec![Warning::AlreadySyncing {
+                school_id: "marist".to_string(),
+                term_collection_id: Some("202440".to_string()),
+            }]
+        );
+        let SyncOptions::Select(select_sync) = sqlite.generate_sync_options().unwrap() else {
+            panic!("expected select sync options");
+        };
+        assert_eq!(select_sync.exclusions().count(), 0);
+        assert_eq!(select_sync.get_schools().len(), 1);
+    }
+
+    #[test]
+    fn all_sync_bookkeeping_is_bounded() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        for page in ["01", "02", "03", "04", "05"] {
+            let updates_text =
+                fs::read_to_string(format!("test-syncs/maristfall2024/{page}.json")).unwrap();
+            let updates: AllSyncResult = from_str(&updates_text).unwrap();
+            sqlite.execute_all_request_sync(updates).unwrap();
+        }
+        for _ in 0..20 {
+            let empty_page: AllSyncResult =
+                from_str(r#"{ "new_latest_sync": 8329, "has_more": false, "sync_data": [] }"#)
+                    .unwrap();
+            sqlite.execute_all_request_sync(empty_page).unwrap();
+        }
+
+        assert_eq!(row_count(&sqlite, "_previous_all_collections"), 1);
+        assert_eq!(sqlite.last_all_sync_sequence().unwrap(), 8329);
+        assert_eq!(sqlite.sync_mode().unwrap(), SyncMode::All);
+    }
+
+    #[test]
+    fn resync_table_refetches_only_that_table() {
+        let page = |page: &str| -> AllSyncResult {
+            from_str(&fs::read_to_string(format!("test-syncs/maristfall2024/{page}.json")).unwrap())
+                .unwrap()
+        };
+        let new_all_sync = || {
+            let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+            sqlite
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            sqlite
+        };
+        let mut expected = new_all_sync();
+        for name in ["01", "02", "03", "04"] {
+            expected.execute_all_request_sync(page(name)).unwrap();
+        }
+
+        let mut sqlite = new_all_sync();
+        for name in ["01", "02", "03"] {
+            sqlite.execute_all_request_sync(page(name)).unwrap();
+        }
+        sqlite.resync_table(TableName::Sections).unwrap();
+        assert_eq!(row_count(&sqlite, "sections"), 0);
+        assert_eq!(row_count(&sqlite, "courses"), 1141);
+        assert_eq!(sqlite.last_all_sync_sequence().unwrap(), 0);
+        assert_eq!(sqlite.sync_mode().unwrap(), SyncMode::All);
+        let resync = Sqlite::read_resync(&sqlite.conn, "").unwrap().unwrap();
+        assert_eq!(
+            resync.tables,
+            HashSet::from([TableName::Sections, TableName::MeetingTimes])
+        );
+        assert_eq!(resync.until, 7530);
+
+        let report = sqlite.execute_all_request_sync(page("01")).unwrap();
+        assert_eq!(report.applied, 2283 + 2448);
+        sqlite.execute_all_request_sync(page("02")).unwrap();
+        // the last page crosses where sections were cleared so the other tables replay 03
+        let mut straddling = page("03");
+        let last = page("04");
+        straddling.sync_data.extend(last.sync_data);
+        straddling.new_latest_sync = last.new_latest_sync;
+        sqlite.execute_all_request_sync(straddling).unwrap();
+
+        for table in ["courses", "professors", "sections", "meeting_times"] {
+            assert_eq!(row_count(&sqlite, table), row_count(&expected, table));
+        }
+        assert!(Sqlite::read_resync(&sqlite.conn, "").unwrap().is_none());
+        assert_eq!(sqlite.last_all_sync_sequence().unwrap(), 8221);
+    }
+
+    #[test]
+    fn apply_timeout_rolls_back() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            apply_timeout: Some(Duration::from_millis(1)),
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        let updates: AllSyncResult = from_str(&updates_text).unwrap();
+
+        let err = sqlite.execute_all_request_sync(updates).err().unwrap();
+        assert!(matches!(
+            err,
+            DataStoreError::SqliteError(SqliteError::ApplyTimeout { .. })
+        ));
+        assert_eq!(row_count(&sqlite, "courses"), 0);
+        assert_eq!(sqlite.last_all_sync_sequence().unwrap(), 0);
+    }
+
+    #[test]
+    fn finished_terms_are_unset() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            unset_finished_terms: true,
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440,202520").unwrap())
+            .unwrap();
+        let terms = from_str(
+            r#"[
+            { "id": "202440", "school_id": "marist", "year": 2024, "season": "Fall", "name": "Fall 2024", "still_collecting": true },
+            { "id": "202520", "school_id": "marist", "year": 2025, "season": "Spring", "name": "Spring 2025", "still_collecting": true }
+        ]"#,
+        )
+        .unwrap();
+        sqlite.add_terms(terms).unwrap();
+        let page = |sync_data: &str| -> TermSyncResult {
+            from_str(&format!(
+                r#"{{
+                "new_sync_term_sequences": {{ "marist": {{ "202440": 10, "202520": 10 }} }},
+                "sync_data": [{sync_data}],
+                "any_has_more": false
+            }}"#
+            ))
+            .unwrap()
+        };
+        fn request(sqlite: &mut Sqlite) -> SelectSync {
+            match sqlite.generate_sync_options().unwrap() {
+                SyncOptions::Select(request) => request,
+                options => panic!("expected select sync options but got {options:?}"),
+            }
+        }
+
+        let select_sync = request(&mut sqlite);
+        sqlite
+            .execute_select_request_sync(select_sync, page(""))
+            .unwrap();
+        assert_eq!(sqlite.registered_strategies().unwrap().len(), 2);
+
+        let select_sync = request(&mut sqlite);
+        sqlite
+            .execute_select_request_sync(
+                select_sync,
+                page(
+                    r#"{
+                    "sequence": 11,
+                    "table_name": "term_collections",
+                    "pk_fields": { "id": "202440", "school_id": "marist" },
+                    "sync_action": "update",
+                    "relevant_fields": { "still_collecting": false }
+                }"#,
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            sqlite.registered_strategies().unwrap(),
+            vec![("marist".to_string(), Some("202520".to_string()))]
+        );
+    }
+
+    #[test]
+    fn resync_table_needs_all_sync() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        assert!(sqlite.resync_table(TableName::Courses).is_err());
+    }
+
+    /// Stores booleans as `Y` or `N`
+    #[derive(Debug)]
+    struct YesNoCodec;
+
+    impl ValueCodec for YesNoCodec {
+        fn to_sql(&self, value: &Value) -> Result<rusqlite::types::Value, SqliteError> {
+            match value {
+                Value::Bool(b) => Ok(rusqlite::types::Value::Text(
+                    if *b { "Y" } else { "N" }.to_string(),
+                )),
+                value => DefaultValueCodec.to_sql(value),
+            }
+        }
+    }
+
+    #[test]
+    fn custom_value_codec() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            value_codec: Some(Arc::new(YesNoCodec)),
+            ..Default::default()
+        })
+        .unwrap();
+        // the migrated boolean columns only allow 0 or 1 so the codec is for columns like this
+        sqlite
+            .conn
+            .execute_batch("ALTER TABLE schools ADD COLUMN is_public TEXT;")
+            .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let updates: AllSyncResult = from_str(
+            r#"{
+                "new_latest_sync": 2,
+                "has_more": false,
+                "sync_data": [
+                  {
+                    "table_name": "schools",
+                    "sync_action": "insert",
+                    "pk_fields": { "id": "marist" },
+                    "relevant_fields": { "name": "Marist", "is_public": false }
+                  },
+                  {
+                    "table_name": "schools",
+                    "sync_action": "insert",
+                    "pk_fields": { "id": "temple" },
+                    "relevant_fields": { "name": "Temple", "is_public": true }
+                  }
+                ]
+            }"#,
+        )
+        .unwrap();
+        sqlite.execute_all_request_sync(updates).unwrap();
+
+        let is_public: String = sqlite
+            .raw_query_scalar(
+                "SELECT group_concat(is_public) FROM (SELECT is_public FROM schools ORDER BY id)",
+            )
+            .unwrap();
+        assert_eq!(is_public, "N,Y");
+        let temple = HashMap::from([("id".to_string(), Value::from("temple"))]);
+        let row = sqlite
+            .get_row(TableName::Schools, &temple)
+            .unwrap()
+            .unwrap();
+        assert_eq!(row["is_public"], Value::from("Y"));
+    }
+
+    #[test]
+    fn sql_script_snapshot() {
+        let records: AllSyncResult = from_str(
+            r#"{
+                "new_latest_sync": 3,
+                "has_more": false,
+                "sync_data": [
+                  {
+                    "table_name": "schools",
+                    "sync_action": "insert",
+                    "pk_fields": { "id": "obrien" },
+                    "relevant_fields": { "name": "O'Brien College" }
+                  },
+                  {
+                    "table_name": "schools",
+                    "sync_action": "update",
+                    "pk_fields": { "id": "marist" },
+                    "relevant_fields": { "name": "Marist University" }
+                  },
+                  {
+                    "table_name": "schools",
+                    "sync_action": "update",
+                    "pk_fields": { "id": "marist" },
+                    "relevant_fields": {}
+                  },
+                  {
+                    "table_name": "professors",
+                    "sync_action": "delete",
+                    "pk_fields": { "id": "42" }
+                  }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let config = SqliteConfig {
+            table_prefix: "classy_".to_string(),
+            ..Default::default()
+        };
+        let script = Sqlite::sql_script(&config, records).unwrap();
+        assert_eq!(
+            script,
+            r#"INSERT INTO "classy_schools" ("id", "name") VALUES ('obrien', 'O''Brien College');
+UPDATE "classy_schools" SET "name" = 'Marist University' WHERE "id" = 'marist';
+DELETE FROM "classy_professors" WHERE "id" = '42';
+"#
+        );
+        assert_eq!(sql_literal(&rusqlite::types::Value::Real(2.0)), "2.0");
+        assert_eq!(
+            sql_literal(&rusqlite::types::Value::Blob(vec![0xAB, 0x01])),
+            "X'AB01'"
+        );
+    }
+
+    #[test]
+    fn partly_migrated_databases_are_upgraded_at_open() {
+        let db_path = std::env::temp_dir().join(format!(
+            "classy-sync-partly-migrated-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&db_path);
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(&fs::read_to_string(UP_MIGRATIONS[0].1).unwrap())
+            .unwrap();
+        drop(conn);
+
+        let config = || SqliteConfig {
+            db_path: Some(db_path.to_string_lossy().into_owned()),
+            optimize_on_drop: false,
+            ..Default::default()
+        };
+        let mut sqlite = Sqlite::new(config()).unwrap();
+        assert_eq!(sqlite.schema_version().unwrap(), 6);
+        assert_eq!(sqlite.last_all_sync_sequence().unwrap(), 0);
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        drop(sqlite);
+        // an upgraded file opens like any other
+        let sqlite = Sqlite::new(config()).unwrap();
+        drop(sqlite);
+
+        // versioned files only get the migrations newer than the ones they recorded
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(
+            &fs::read_to_string("src/data_stores/sqlite/migrations/006.down.sql").unwrap(),
+        )
+        .unwrap();
+        conn.execute("DELETE FROM _schema_migrations WHERE version = 6", ())
+            .unwrap();
+        drop(conn);
+        let mut sqlite = Sqlite::new(config()).unwrap();
+        assert_eq!(sqlite.schema_version().unwrap(), 6);
+        let index_count: i64 = sqlite
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'previous_all_collections_by_sequence'",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(index_count, 1);
+        drop(sqlite);
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn read_only_opens_leave_the_file_alone() {
+        let db_path = std::env::temp_dir().join(format!(
+            "classy-sync-read-only-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&db_path);
+        let config = |read_only| SqliteConfig {
+            db_path: Some(db_path.to_string_lossy().into_owned()),
+            read_only,
+            ..Default::default()
+        };
+        // a mistyped path is not created
+        assert!(Sqlite::new(config(true)).is_err());
+        assert!(!db_path.exists());
+
+        let mut sqlite = Sqlite::new(config(false)).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        drop(sqlite);
+        let modified = fs::metadata(&db_path).unwrap().modified().unwrap();
+        let mut sqlite = Sqlite::new(config(true)).unwrap();
+        assert!(sqlite.is_all_sync().unwrap());
+        assert_eq!(sqlite.bookkeeping().unwrap().len(), 4);
+        let plans = sqlite
+            .explain(vec![ClassDataSync {
+                table_name: TableName::Schools,
+                sync_action: SyncAction::Insert,
+                pk_fields: HashMap::from([("id".to_string(), Value::from("marist"))]),
+                relevant_fields: Some(HashMap::from([("name".to_string(), Value::from("Marist"))])),
+            }])
+            .unwrap();
+        assert_eq!(plans.len(), 1);
+        assert!(sqlite.conn.execute("DELETE FROM schools", ()).is_err());
+        drop(sqlite);
+        assert_eq!(
+            fs::metadata(&db_path).unwrap().modified().unwrap(),
+            modified
+        );
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn dropped_tables_fail_at_open() {
+        let db_path = std::env::temp_dir().join(format!(
+            "classy-sync-dropped-tables-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&db_path);
+        let config = || SqliteConfig {
+            db_path: Some(db_path.to_string_lossy().into_owned()),
+            optimize_on_drop: false,
+            ..Default::default()
+        };
+        drop(Sqlite::new(config()).unwrap());
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute("DROP TABLE _sync_meta", ()).unwrap();
+        drop(conn);
+
+        let err = Sqlite::new(config()).err().unwrap();
+        let SqliteError::MissingTables { tables, migration } = &err else {
+            panic!("expected missing tables but got {err}");
+        };
+        assert_eq!(tables, &vec!["_sync_meta".to_string()]);
+        assert_eq!(migration, UP_MIGRATIONS[2].1);
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn held_lock_fails_fast() {
+        let db_path =
+            std::env::temp_dir().join(format!("classy-sync-locked-{}.sqlite", std::process::id()));
+        let lock_path = PathBuf::from(format!("{}.lock", db_path.display()));
+        let _ = fs::remove_file(&db_path);
+        let _ = fs::remove_file(&lock_path);
+        let config = |lock| SqliteConfig {
+            db_path: Some(db_path.to_string_lossy().into_owned()),
+            lock,
+            ..Default::default()
+        };
+
+        let sqlite = Sqlite::new(config(true)).unwrap();
+        assert!(lock_path.exists());
+        let err = Sqlite::new(config(true)).err().unwrap();
+        let SqliteError::DatabaseLocked { holder, .. } = &err else {
+            panic!("expected a locked database but got {err}");
+        };
+        assert_eq!(holder, &std::process::id().to_string());
+        // opting out ignores the lock
+        drop(Sqlite::new(config(false)).unwrap());
+        drop(sqlite);
+        drop(Sqlite::new(config(true)).unwrap());
+
+        // a file left by a run that ended without releasing it is not locked by anyone
+        fs::write(&lock_path, "12345").unwrap();
+        let sqlite = Sqlite::new(config(true)).unwrap();
+        assert_eq!(
+            fs::read_to_string(&lock_path).unwrap(),
+            std::process::id().to_string()
+        );
+        drop(sqlite);
+        fs::remove_file(&lock_path).unwrap();
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn memory_sentinel_is_in_memory() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            db_path: Some(IN_MEMORY_DB_PATH.to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(sqlite.schema_version().unwrap(), 6);
+        assert_eq!(sqlite.sync_mode().unwrap(), SyncMode::Unset);
+        let file: String = sqlite
+            .raw_query_scalar("SELECT file FROM pragma_database_list WHERE name = 'main'")
+            .unwrap();
+        assert_eq!(file, "");
+        assert!(!Path::new(IN_MEMORY_DB_PATH).exists());
+        assert!(!sqlite.is_persistent().unwrap());
+        assert_eq!(
+            sqlite.describe().unwrap(),
+            DatastoreInfo {
+                backend: "sqlite",
+                path: None,
+                in_memory: true,
+                schema_version: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn no_commit_rolls_back() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            no_commit: true,
+            commit_every: Some(100),
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let before = sqlite.generate_sync_options().unwrap();
+        let updates_text = fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap();
+        let updates: AllSyncResult = from_str(&updates_text).unwrap();
+        let record_count = updates.len();
+
+        let report = sqlite.execute_all_request_sync(updates).unwrap();
+        assert_eq!(report.applied, record_count);
+        assert_eq!(report.affected, record_count);
+        for table in ["schools", "courses", "sections", "meeting_times"] {
+            assert_eq!(row_count(&sqlite, table), 0);
+        }
+        assert_eq!(sqlite.generate_sync_options().unwrap(), before);
+    }
+
+    /// The next select sync request of the datastore with a response to it without records
+    fn empty_select_response(
+        sqlite: &mut Sqlite,
+        new_sync_term_sequences: &str,
+    ) -> (SelectSync, TermSyncResult) {
+        let SyncOptions::Select(request) = sqlite.generate_sync_options().unwrap() else {
+            panic!("expected select sync options");
+        };
+        let response = TermSyncResult {
+            new_sync_term_sequences: from_str(new_sync_term_sequences).unwrap(),
+            sync_data: vec![],
+            any_has_more: false,
+        };
+        (request, response)
+    }
+
+    #[test]
+    fn prune_keeps_latest_bookkeeping() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440;temple").unwrap())
+            .unwrap();
+        for sequence in [5, 10, 15] {
+            let (request, response) = empty_select_response(
+                &mut sqlite,
+                &format!(r#"{{ "marist": {{ "202440": {sequence} }}, "temple": {sequence} }}"#),
+            );
+            sqlite
+                .execute_select_request_sync(request, response)
+                .unwrap();
+        }
+        let before = sqlite.generate_sync_options().unwrap();
+
+        assert_eq!(sqlite.prune_before(12).unwrap(), 4);
+        assert_eq!(row_count(&sqlite, "_previous_term_collections"), 1);
+        assert_eq!(row_count(&sqlite, "_previous_school_collections"), 1);
+        assert_eq!(sqlite.generate_sync_options().unwrap(), before);
+
+        // the latest bookkeeping is kept even when it is before the given sequence
+        assert_eq!(sqlite.prune_before(100).unwrap(), 0);
+        assert_eq!(sqlite.generate_sync_options().unwrap(), before);
+    }
+
+    #[test]
+    fn failed_records_roll_back_their_sequences() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        let before = sqlite.generate_sync_options().unwrap();
+        let (request, mut response) =
+            empty_select_response(&mut sqlite, r#"{ "marist": { "202440": 10 } }"#);
+        response.any_has_more = true;
+        // applied after the bookkeeping is written and rejected for its column
+        response.sync_data = vec![
+            from_str(
+                r#"{
+                "table_name": "schools",
+                "sync_action": "insert",
+                "pk_fields": { "id": "marist" },
+                "relevant_fields": { "name; --": "Marist" }
+            }"#,
+            )
+            .unwrap(),
+        ];
+
+        assert!(
+            sqlite
+                .execute_select_request_sync(request, response)
+                .is_err()
+        );
+        assert_eq!(row_count(&sqlite, "_previous_term_collections"), 0);
+        assert_eq!(sqlite.generate_sync_options().unwrap(), before);
+    }
+
+    #[test]
+    fn requested_terms_without_data_keep_their_bookkeeping() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440,202520").unwrap())
+            .unwrap();
+        let mut sync_once = |new_sync_term_sequences: &str, any_has_more: bool| {
+            let SyncOptions::Select(request) = sqlite.generate_sync_options().unwrap() else {
+                panic!("expected select sync options");
+            };
+            let response = TermSyncResult {
+                new_sync_term_sequences: from_str(new_sync_term_sequences).unwrap(),
+                sync_data: vec![],
+                any_has_more,
+            };
+            sqlite
+                .execute_select_request_sync(request, response)
+                .unwrap();
+        };
+        sync_once(r#"{ "marist": { "202440": 10, "202520": 10 } }"#, false);
+        // the server leaves out 202520 since nothing changed for it
+        sync_once(r#"{ "marist": { "202440": 20 } }"#, true);
+
+        let expected = IndexMap::from([(
+            "marist".to_string(),
+            SchoolEntry::TermToSequence(IndexMap::from([
+                ("202440".to_string(), 20),
+                ("202520".to_string(), 10),
+            ])),
+        )]);
+        let untouched: u64 = sqlite
+            .raw_query_scalar(
+                "SELECT COUNT(*) FROM _previous_term_collections WHERE term_collection_id = '202520' AND synced_at = 10",
+            )
+            .unwrap();
+        assert_eq!(untouched, 2);
+        let SyncOptions::Select(next) = sqlite.generate_sync_options().unwrap() else {
+            panic!("expected select sync options");
+        };
+        assert_eq!(next.get_schools(), &expected);
+    }
+
+    fn term(season: &str, year: u32) -> sync_requests::Term {
+        sync_requests::Term {
+            id: "202440".to_string(),
+            school_id: "marist".to_string(),
+            year,
+            season: season.to_string(),
+            name: "Fall 2024".to_string(),
+            still_collecting: false,
+        }
+    }
+
+    #[test]
+    fn add_terms_normalizes_season() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite.add_terms(vec![term("fall", 2024)]).unwrap();
+        let season: String = sqlite
+            .conn
+            .query_row("SELECT season FROM term_collections", (), |r| r.get(0))
+            .unwrap();
+        assert_eq!(season, "Fall");
+    }
+
+    #[test]
+    fn add_terms_rejects_invalid_terms() {
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(sqlite.add_terms(vec![term("Autumn", 2024)]).is_err());
+        assert!(sqlite.add_terms(vec![term("Fall", 24)]).is_err());
+        let err = term("Autumn", 24).verify().unwrap_err();
+        match err {
+            crate::errors::Error::InvalidSchemaValues { invalid_values, .. } => {
+                assert_eq!(invalid_values, vec!["season `Autumn`", "year `24`"])
+            }
+            err => panic!("Expected invalid schema values got {err}"),
+        }
+        let count: u32 = sqlite
+            .conn
+            .query_row("SELECT COUNT(*) FROM term_collections", (), |r| r.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
 }
@@ -0,0 +1,198 @@
+use crate::data_stores::sync_requests::{ClassDataSync, SyncAction, TableName};
+use serde_json::Value;
+use std::fmt;
+
+/// What applying a sync response did to a datastore
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub applied: usize,
+    /// records that could not be applied, only populated when the datastore continues on errors
+    pub skipped: Vec<SkippedRecord>,
+    /// records ignored because their table is not being synced
+    pub filtered: usize,
+    /// delete records ignored because the datastore keeps rows the server removed
+    pub ignored_deletes: usize,
+    /// rows changed by the applied records
+    pub affected: usize,
+    /// ids of schools inserted by the applied records
+    pub new_schools: Vec<String>,
+    /// `(school_id, id)` of terms inserted by the applied records
+    pub new_terms: Vec<(String, String)>,
+    /// what went unexpectedly without stopping the records from being applied
+    pub warnings: Vec<Warning>,
+}
+
+impl SyncReport {
+    /// Folds the report of a later page into this one
+    pub fn merge(&mut self, other: SyncReport) {
+        self.applied += other.applied;
+        self.skipped.extend(other.skipped);
+        self.filtered += other.filtered;
+        self.ignored_deletes += other.ignored_deletes;
+        self.affected += other.affected;
+        self.new_schools.extend(other.new_schools);
+        self.new_terms.extend(other.new_terms);
+        self.warnings.extend(other.warnings);
+    }
+
+    /// Remembers the school or term introduced by an applied record
+    pub fn record_discovery(&mut self, sync: &ClassDataSync) {
+        if !matches!(sync.sync_action, SyncAction::Insert) {
+            return;
+        }
+        let pk = |field: &str| sync.pk_fields.get(field).map(id_of);
+        match sync.table_name {
+            TableName::Schools => {
+                if let Some(id) = pk("id") {
+                    self.new_schools.push(id);
+                }
+            }
+            TableName::TermCollections => {
+                if let (Some(school_id), Some(id)) = (pk("school_id"), pk("id")) {
+                    self.new_terms.push((school_id, id));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn id_of(value: &Value) -> String {
+    match value {
+        Value::String(id) => id.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// How many rows a record is expected to change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpectedRows {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl ExpectedRows {
+    pub fn exactly(rows: usize) -> ExpectedRows {
+        ExpectedRows {
+            min: rows,
+            max: rows,
+        }
+    }
+
+    pub fn at_most(rows: usize) -> ExpectedRows {
+        ExpectedRows { min: 0, max: rows }
+    }
+
+    pub fn contains(&self, rows: usize) -> bool {
+        (self.min..=self.max).contains(&rows)
+    }
+}
+
+impl fmt::Display for ExpectedRows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{} to {}", self.min, self.max)
+        }
+    }
+}
+
+/// Something that did not stop a datastore from doing what it was asked which callers may still
+/// want to react to
+///
+/// Each is also logged when it happens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// an update record without any fields to change
+    EmptyUpdate { table: TableName },
+    /// a record changed a number of rows outside what its action is expected to change
+    UnexpectedRowCount {
+        table: TableName,
+        affected: usize,
+        expected: ExpectedRows,
+    },
+    /// a record of a table this version does not know which was skipped
+    UnknownTable { table: String },
+    /// a repeated insert of a row that was applied as an update
+    CollapsedInsert { table: TableName },
+    /// a string value that was cut down to the most characters allowed
+    Truncated {
+        column: String,
+        length: usize,
+        max_length: usize,
+    },
+    /// a school or term that was already set to be synced
+    AlreadySyncing {
+        school_id: String,
+        term_collection_id: Option<String>,
+    },
+    /// the server is at an earlier sequence than the datastore such as after it was rolled back
+    SequenceSkew { message: String },
+    /// a select sync response had sequences for schools that were not requested which were not
+    /// kept
+    UnrequestedSchools { message: String },
+    /// a page that failed to apply which was rolled back leaving the pages after it for the next
+    /// sync
+    DiscardedPage { page: usize, reason: String },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::EmptyUpdate { table } => write!(f, "update of `{table}` with no changes"),
+            Warning::UnexpectedRowCount {
+                table,
+                affected,
+                expected,
+            } => write!(
+                f,
+                "record of `{table}` affected {affected} rows expected {expected}"
+            ),
+            Warning::UnknownTable { table } => {
+                write!(f, "skipped a record of the unknown table `{table}`")
+            }
+            Warning::CollapsedInsert { table } => {
+                write!(f, "repeated insert into `{table}` applied as an update")
+            }
+            Warning::Truncated {
+                column,
+                length,
+                max_length,
+            } => write!(
+                f,
+                "truncated `{column}` from {length} to {max_length} characters"
+            ),
+            Warning::AlreadySyncing {
+                school_id,
+                term_collection_id: None,
+            } => write!(f, "school `{school_id}` is already being synced"),
+            Warning::AlreadySyncing {
+                school_id,
+                term_collection_id: Some(term_collection_id),
+            } => write!(
+                f,
+                "term `{term_collection_id}` of `{school_id}` is already being synced"
+            ),
+            Warning::SequenceSkew { message } => write!(
+                f,
+                "the server is behind the datastore: {message}, reset the datastore if the server was rolled back"
+            ),
+            Warning::UnrequestedSchools { message } => {
+                write!(
+                    f,
+                    "the server sent sequences that were not requested: {message}"
+                )
+            }
+            Warning::DiscardedPage { page, reason } => {
+                write!(f, "discarded page {page} which failed to apply: {reason}")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SkippedRecord {
+    pub record: ClassDataSync,
+    pub reason: String,
+}
@@ -1,13 +1,30 @@
+use crate::argument_parser::{CollectionType, SelectSyncOptions};
 use crate::errors::Error;
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::Value;
 use std::collections::HashMap;
-use strum_macros::Display;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use strum_macros::{Display, EnumString};
 
 const DEFUALT_MAX_RECORDS: u16 = 10_000;
+const VALID_TERM_YEARS: RangeInclusive<u32> = 1900..=2200;
 
-#[derive(Serialize, Deserialize, Debug)]
+lazy_static! {
+    static ref IS_COLUMN: Regex = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+}
+
+/// Whether a column name is safe to use in sql expressions
+pub fn is_valid_column(column: &str) -> bool {
+    IS_COLUMN.is_match(column)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 pub enum SyncAction {
     Update,
@@ -15,8 +32,9 @@ pub enum SyncAction {
     Insert,
 }
 
-#[derive(Serialize, Display, Debug, Deserialize)]
-#[serde(rename_all = "snake_case")]
+/// Parsing never fails since any other name is a table of a newer server which is kept as
+/// `Unknown` so it can be skipped and reported by name
+#[derive(Display, EnumString, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TableName {
     #[strum(serialize = "meeting_times")]
     MeetingTimes,
@@ -30,9 +48,70 @@ pub enum TableName {
     TermCollections,
     #[strum(serialize = "schools")]
     Schools,
+    /// a table added by a newer server which this version does not know how to apply
+    #[strum(default)]
+    Unknown(String),
+}
+
+impl Serialize for TableName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for TableName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TableName, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(TableName::from_str(&name).unwrap_or(TableName::Unknown(name)))
+    }
+}
+
+impl TableName {
+    /// Position of the table when parents are ordered before the tables referencing them
+    ///
+    /// Tables at the same rank do not depend on each other
+    pub fn dependency_rank(&self) -> u8 {
+        match self {
+            TableName::Schools => 0,
+            TableName::TermCollections => 1,
+            TableName::Professors => 1,
+            TableName::Courses => 1,
+            TableName::Sections => 2,
+            TableName::MeetingTimes => 3,
+            TableName::Unknown(_) => u8::MAX,
+        }
+    }
+
+    /// The common table this is when its rows are shared by every term of a school
+    pub fn common_table(&self) -> Option<CommonTable> {
+        match self {
+            TableName::Professors => Some(CommonTable::Professors),
+            TableName::Courses => Some(CommonTable::Courses),
+            TableName::TermCollections => Some(CommonTable::TermCollections),
+            TableName::MeetingTimes
+            | TableName::Sections
+            | TableName::Schools
+            | TableName::Unknown(_) => None,
+        }
+    }
 }
 
-#[derive(Serialize, Display, Debug, Deserialize)]
+/// Orders tables by `dependency_rank` breaking ties by name
+impl Ord for TableName {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.dependency_rank()
+            .cmp(&other.dependency_rank())
+            .then_with(|| self.to_string().cmp(&other.to_string()))
+    }
+}
+
+impl PartialOrd for TableName {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Serialize, Display, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum CommonTable {
     #[strum(serialize = "professors")]
@@ -43,7 +122,7 @@ pub enum CommonTable {
     TermCollections,
 }
 
-#[derive(Serialize, Debug, Deserialize)]
+#[derive(Serialize, Debug, Clone)]
 pub struct ClassDataSync {
     pub table_name: TableName,
     pub sync_action: SyncAction,
@@ -56,16 +135,39 @@ pub struct ClassDataSync {
 }
 
 impl ClassDataSync {
+    pub fn table(&self) -> &TableName {
+        &self.table_name
+    }
+
+    pub fn action(&self) -> SyncAction {
+        self.sync_action
+    }
+
+    /// Whether the record is of a table whose rows are shared by every term of a school
+    pub fn is_common_table(&self) -> bool {
+        self.table_name.common_table().is_some()
+    }
+
     /// This funciton should be used to verify columns in case of sql injection
     pub fn verify_record(&self) -> Result<(), Error> {
-        let is_column = Regex::new(r"\b[a-zA-Z_]\b").unwrap();
+        // there would be nothing to put in the statement such as `INSERT INTO t () VALUES ();`
+        // and updates or deletes without a key would match every row of the table
+        let needs_key = matches!(self.sync_action, SyncAction::Update | SyncAction::Delete);
+        if self.pk_fields.is_empty()
+            && (needs_key || self.relevant_fields.as_ref().is_none_or(HashMap::is_empty))
+        {
+            return Err(Error::EmptyRecord {
+                record: serde_json::to_value(self)?,
+            });
+        }
+
         let invalid_cols: Vec<_> = self
             .relevant_fields
             .as_ref()
             .unwrap_or(&HashMap::new())
-            .iter()
-            .filter_map(|(col, _)| {
-                if is_column.is_match(col) {
+            .keys()
+            .filter_map(|col| {
+                if !is_valid_column(col) {
                     Some(col.to_string())
                 } else {
                     None
@@ -83,9 +185,9 @@ impl ClassDataSync {
 
         let invalid_cols: Vec<_> = self
             .pk_fields
-            .iter()
-            .filter_map(|(col, _)| {
-                if is_column.is_match(col) {
+            .keys()
+            .filter_map(|col| {
+                if !is_valid_column(col) {
                     Some(col.to_string())
                 } else {
                     None
@@ -104,6 +206,230 @@ impl ClassDataSync {
     }
 }
 
+impl<'de> Deserialize<'de> for ClassDataSync {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        RecordSeed {
+            columns: &mut ColumnCounts::default(),
+        }
+        .deserialize(deserializer)
+    }
+}
+
+/// Deserializes the records of a page allocating the column maps of each record once
+///
+/// The maps are sized by how many columns earlier records of the same table had instead of
+/// growing as their columns are read which reallocates them several times on large syncs
+fn deserialize_records<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<ClassDataSync>, D::Error> {
+    struct RecordsVisitor;
+
+    impl<'de> Visitor<'de> for RecordsVisitor {
+        type Value = Vec<ClassDataSync>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a list of sync records")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut columns = ColumnCounts::default();
+            let mut records = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(record) = seq.next_element_seed(RecordSeed {
+                columns: &mut columns,
+            })? {
+                records.push(record);
+            }
+            Ok(records)
+        }
+    }
+
+    deserializer.deserialize_seq(RecordsVisitor)
+}
+
+/// The most `pk_fields` and `relevant_fields` columns seen for each table
+#[derive(Default)]
+struct ColumnCounts {
+    by_table: HashMap<TableName, (usize, usize)>,
+}
+
+impl ColumnCounts {
+    fn of(&self, table: Option<&TableName>) -> (usize, usize) {
+        table
+            .and_then(|table| self.by_table.get(table).copied())
+            .unwrap_or_default()
+    }
+
+    fn record(&mut self, sync: &ClassDataSync) {
+        let counts = self.by_table.entry(sync.table_name.clone()).or_default();
+        counts.0 = counts.0.max(sync.pk_fields.len());
+        counts.1 = counts
+            .1
+            .max(sync.relevant_fields.as_ref().map_or(0, HashMap::len));
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum RecordField {
+    TableName,
+    SyncAction,
+    PkFields,
+    RelevantFields,
+    #[serde(other)]
+    Ignored,
+}
+
+struct RecordSeed<'a> {
+    columns: &'a mut ColumnCounts,
+}
+
+impl<'de> DeserializeSeed<'de> for RecordSeed<'_> {
+    type Value = ClassDataSync;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RecordSeed<'_> {
+    type Value = ClassDataSync;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sync record")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut table_name = None;
+        let mut sync_action = None;
+        let mut pk_fields = None;
+        let mut relevant_fields = None;
+        while let Some(field) = map.next_key()? {
+            match field {
+                RecordField::TableName if table_name.is_some() => {
+                    return Err(de::Error::duplicate_field("table_name"));
+                }
+                RecordField::TableName => table_name = Some(map.next_value()?),
+                RecordField::SyncAction if sync_action.is_some() => {
+                    return Err(de::Error::duplicate_field("sync_action"));
+                }
+                RecordField::SyncAction => sync_action = Some(map.next_value()?),
+                RecordField::PkFields if pk_fields.is_some() => {
+                    return Err(de::Error::duplicate_field("pk_fields"));
+                }
+                RecordField::PkFields => {
+                    let (capacity, _) = self.columns.of(table_name.as_ref());
+                    pk_fields = Some(map.next_value_seed(ColumnsSeed { capacity })?);
+                }
+                RecordField::RelevantFields if relevant_fields.is_some() => {
+                    return Err(de::Error::duplicate_field("relevant_fields"));
+                }
+                RecordField::RelevantFields => {
+                    let (_, capacity) = self.columns.of(table_name.as_ref());
+                    relevant_fields = Some(map.next_value_seed(OptionalColumnsSeed { capacity })?);
+                }
+                RecordField::Ignored => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        let sync = ClassDataSync {
+            table_name: table_name.ok_or_else(|| de::Error::missing_field("table_name"))?,
+            sync_action: sync_action.ok_or_else(|| de::Error::missing_field("sync_action"))?,
+            pk_fields: pk_fields.ok_or_else(|| de::Error::missing_field("pk_fields"))?,
+            relevant_fields: relevant_fields.flatten(),
+        };
+        self.columns.record(&sync);
+        Ok(sync)
+    }
+}
+
+/// A column to value map allocated with room for `capacity` columns
+struct ColumnsSeed {
+    capacity: usize,
+}
+
+impl<'de> DeserializeSeed<'de> for ColumnsSeed {
+    type Value = HashMap<String, Value>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ColumnsSeed {
+    type Value = HashMap<String, Value>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of columns to values")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut columns =
+            HashMap::with_capacity(self.capacity.max(map.size_hint().unwrap_or_default()));
+        while let Some((column, value)) = map.next_entry()? {
+            columns.insert(column, value);
+        }
+        Ok(columns)
+    }
+}
+
+struct OptionalColumnsSeed {
+    capacity: usize,
+}
+
+impl<'de> DeserializeSeed<'de> for OptionalColumnsSeed {
+    type Value = Option<HashMap<String, Value>>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_option(self)
+    }
+}
+
+impl<'de> Visitor<'de> for OptionalColumnsSeed {
+    type Value = Option<HashMap<String, Value>>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of columns to values or null")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        ColumnsSeed {
+            capacity: self.capacity,
+        }
+        .deserialize(deserializer)
+        .map(Some)
+    }
+}
+
+/// How identifiers (table and column names) are quoted when building sql statements
+///
+/// Quoting lets columns that happen to be reserved words such as `order` be used
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IdentifierQuoting {
+    /// standard sql double quotes which are understood by both sqlite and postgres
+    #[default]
+    DoubleQuote,
+    /// identifiers are used as is
+    Unquoted,
+}
+
+impl IdentifierQuoting {
+    pub fn quote(&self, identifier: &str) -> String {
+        match self {
+            IdentifierQuoting::DoubleQuote => format!("\"{}\"", identifier.replace('"', "\"\"")),
+            IdentifierQuoting::Unquoted => identifier.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum SyncOptions {
     All(AllSync),
@@ -111,18 +437,20 @@ pub enum SyncOptions {
 }
 
 // TERM SYNCS - for getting information about specfic terms from classy
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum SchoolEntry {
-    TermToSequence(HashMap<String, u64>),
+    /// the terms in the order they were added
+    TermToSequence(IndexMap<String, u64>),
     Sequence(u64),
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
 pub struct SelectSync {
     exclude: HashMap<String, HashMap<String, u64>>,
     max_records_per_request: Option<u16>,
-    schools: HashMap<String, SchoolEntry>,
+    /// the schools in the order they were added so requests are the same for the same input
+    schools: IndexMap<String, SchoolEntry>,
 }
 
 impl SelectSync {
@@ -133,18 +461,44 @@ impl SelectSync {
         }
     }
 
+    /// Creates an empty select sync with room for the given number of schools
+    pub fn with_capacity(schools: usize) -> SelectSync {
+        SelectSync {
+            schools: IndexMap::with_capacity(schools),
+            ..SelectSync::new()
+        }
+    }
+
     pub fn get_exclusions(&self) -> &HashMap<String, HashMap<String, u64>> {
         &self.exclude
     }
 
+    /// Every excluded term as `(school_id, term_collection_id, sequence)`
+    pub fn exclusions(&self) -> impl Iterator<Item = (&str, &str, u64)> {
+        self.exclude.iter().flat_map(|(school_id, terms)| {
+            terms.iter().map(move |(term_collection_id, sequence)| {
+                (school_id.as_str(), term_collection_id.as_str(), *sequence)
+            })
+        })
+    }
+
     pub fn get_max_records(&self) -> Option<u16> {
         self.max_records_per_request
     }
 
-    pub fn get_schools(&self) -> &HashMap<String, SchoolEntry> {
+    pub fn set_max_records(&mut self, max_records: Option<u16>) {
+        self.max_records_per_request = max_records;
+    }
+
+    pub fn get_schools(&self) -> &IndexMap<String, SchoolEntry> {
         &self.schools
     }
 
+    /// Whether there is no school or term to sync
+    pub fn is_empty(&self) -> bool {
+        self.schools.is_empty()
+    }
+
     // all of these setter methods are pretty picky so maybe just make them less so
 
     pub fn add_school_sync(&mut self, school_id: String, synced_at: u64) -> Result<(), Error> {
@@ -167,7 +521,7 @@ impl SelectSync {
         let school_entry = self
             .schools
             .entry(school_id)
-            .or_insert(SchoolEntry::TermToSequence(HashMap::new()));
+            .or_insert(SchoolEntry::TermToSequence(IndexMap::new()));
         match school_entry {
             SchoolEntry::TermToSequence(terms) => {
                 if let Some(old_sync) = terms.insert(term_collection_id, synced_at) {
@@ -185,6 +539,138 @@ impl SelectSync {
         Ok(())
     }
 
+    /// Moves the requested sequences up to the ones a response reported so the same request can be
+    /// made for the next page
+    ///
+    /// Schools and terms that were not requested are ignored
+    pub fn advance(&mut self, new_sequences: &HashMap<String, SchoolEntry>) {
+        for (school_id, new_entry) in new_sequences {
+            match (self.schools.get_mut(school_id), new_entry) {
+                (Some(SchoolEntry::Sequence(sequence)), SchoolEntry::Sequence(new_sequence)) => {
+                    *sequence = *new_sequence;
+                }
+                (
+                    Some(SchoolEntry::TermToSequence(terms)),
+                    SchoolEntry::TermToSequence(new_terms),
+                ) => {
+                    for (term_collection_id, new_sequence) in new_terms {
+                        if let Some(sequence) = terms.get_mut(term_collection_id) {
+                            *sequence = *new_sequence;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The sequence of every school and term a response to this request reached
+    ///
+    /// Requested schools and terms the response left out had nothing new so they keep the
+    /// requested sequence while schools that were not requested and terms this request excluded
+    /// are left out since they are not synced on their own
+    pub fn reconcile(
+        &self,
+        new_sequences: &HashMap<String, SchoolEntry>,
+    ) -> IndexMap<String, SchoolEntry> {
+        let mut reached = self.schools.clone();
+        for (school_id, new_entry) in new_sequences {
+            let excluded = self.exclude.get(school_id);
+            let Some(entry) = reached.get_mut(school_id) else {
+                continue;
+            };
+            match (entry, new_entry) {
+                (SchoolEntry::TermToSequence(terms), SchoolEntry::TermToSequence(new_terms)) => {
+                    terms.extend(
+                        new_terms
+                            .iter()
+                            .map(|(term, sequence)| (term.clone(), *sequence)),
+                    );
+                }
+                (entry, SchoolEntry::TermToSequence(new_terms)) => {
+                    let kept: IndexMap<_, _> = new_terms
+                        .iter()
+                        .filter(|(term, _)| {
+                            !excluded.is_some_and(|terms| terms.contains_key(*term))
+                        })
+                        .map(|(term, sequence)| (term.clone(), *sequence))
+                        .collect();
+                    if !kept.is_empty() {
+                        *entry = SchoolEntry::TermToSequence(kept);
+                    }
+                }
+                (entry, new_entry) => *entry = new_entry.clone(),
+            }
+        }
+        reached
+    }
+
+    /// Keeps only the schools and terms of `targets` so they can be synced on their own
+    ///
+    /// A target that is only a school keeps everything synced for it while every term target
+    /// has to be synced on its own already
+    pub fn narrow_to(&mut self, targets: &SelectSyncOptions) -> Result<(), Error> {
+        let not_synced = |target: String| {
+            Err(Error::InputParseError {
+                message: format!("{target} is not set to be synced, run `set` for it first"),
+            })
+        };
+        let mut schools = IndexMap::with_capacity(targets.get_collections().len());
+        for (school_id, collection) in targets.get_collections() {
+            let Some(entry) = self.schools.get(school_id) else {
+                return not_synced(format!("school `{school_id}`"));
+            };
+            let entry = match (collection, entry) {
+                (CollectionType::AllSchoolData, entry) => entry.clone(),
+                (CollectionType::SelectTermData(terms), SchoolEntry::TermToSequence(sequences)) => {
+                    let mut kept = IndexMap::with_capacity(terms.len());
+                    for term in terms {
+                        let Some(sequence) = sequences.get(term) else {
+                            return not_synced(format!("term `{term}` of `{school_id}`"));
+                        };
+                        kept.insert(term.clone(), *sequence);
+                    }
+                    SchoolEntry::TermToSequence(kept)
+                }
+                (CollectionType::SelectTermData(_), SchoolEntry::Sequence(_)) => {
+                    return Err(Error::InputParseError {
+                        message: format!(
+                            "`{school_id}` is synced as a whole so its terms cannot be synced on their own"
+                        ),
+                    });
+                }
+            };
+            schools.insert(school_id.clone(), entry);
+        }
+        self.exclude
+            .retain(|school_id, _| schools.contains_key(school_id));
+        self.schools = schools;
+        Ok(())
+    }
+
+    /// Checks every exclusion belongs to a school synced as a whole and excludes more than that
+    /// school's sync already covers
+    pub fn validate(&self) -> Result<(), Error> {
+        for (school_id, term_collection_id, sequence) in self.exclusions() {
+            let message = match self.schools.get(school_id) {
+                Some(SchoolEntry::Sequence(school_sequence)) if sequence > *school_sequence => {
+                    continue;
+                }
+                Some(SchoolEntry::Sequence(school_sequence)) => format!(
+                    "term `{term_collection_id}` of `{school_id}` is excluded up to {sequence} which the school's sync at {school_sequence} already covers"
+                ),
+                Some(SchoolEntry::TermToSequence(_)) => format!(
+                    "term `{term_collection_id}` is excluded but `{school_id}` is synced by term"
+                ),
+                None => format!(
+                    "term `{term_collection_id}` is excluded but `{school_id}` is not synced"
+                ),
+            };
+            return Err(Error::InconsistentSelectSync { message });
+        }
+        Ok(())
+    }
+
     pub fn add_exclusion(
         &mut self,
         school_id: String,
@@ -201,13 +687,96 @@ impl SelectSync {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TermSyncResult {
     pub new_sync_term_sequences: HashMap<String, SchoolEntry>,
+    /// also read from `data` as sent by older servers
+    #[serde(alias = "data", deserialize_with = "deserialize_records")]
     pub sync_data: Vec<ClassDataSync>,
     pub any_has_more: bool,
 }
 
+impl TermSyncResult {
+    pub fn records(&self) -> &[ClassDataSync] {
+        &self.sync_data
+    }
+
+    pub fn len(&self) -> usize {
+        self.sync_data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sync_data.is_empty()
+    }
+
+    /// Checks that a page followed by more moves the sync forward from the `request` it answers
+    /// so a misbehaving server cannot keep a sync requesting pages forever
+    pub fn check_progress(&self, request: &SelectSync) -> Result<(), Error> {
+        if !self.any_has_more {
+            return Ok(());
+        }
+        if self.sync_data.is_empty() {
+            return Err(Error::InvalidPagination {
+                message: "an empty page says there are more".to_string(),
+            });
+        }
+        if let Some(regressed) = self.regressed(request) {
+            return Err(Error::InvalidPagination {
+                message: format!("the sequence of {regressed} went back"),
+            });
+        }
+        Ok(())
+    }
+
+    /// Describes a school or term the server reports an earlier sequence for than the `request`
+    /// already synced which happens when the server was rolled back
+    pub fn sequence_skew(&self, request: &SelectSync) -> Option<String> {
+        self.regressed(request)
+            .map(|regressed| format!("the sequence of {regressed} went back"))
+    }
+
+    /// Describes the schools the response has sequences for that the `request` did not ask about
+    pub fn unrequested_schools(&self, request: &SelectSync) -> Option<String> {
+        let mut unrequested: Vec<_> = self
+            .new_sync_term_sequences
+            .keys()
+            .filter(|school_id| !request.schools.contains_key(*school_id))
+            .map(|school_id| format!("`{school_id}`"))
+            .collect();
+        unrequested.sort_unstable();
+        (!unrequested.is_empty()).then(|| unrequested.join(", "))
+    }
+
+    fn regressed(&self, request: &SelectSync) -> Option<String> {
+        self.new_sync_term_sequences
+            .iter()
+            .find_map(
+                |(school_id, entry)| match (entry, request.schools.get(school_id)) {
+                    (SchoolEntry::Sequence(new), Some(SchoolEntry::Sequence(old))) => {
+                        (new < old).then(|| format!("`{school_id}` from {old} to {new}"))
+                    }
+                    (SchoolEntry::TermToSequence(new), Some(SchoolEntry::TermToSequence(old))) => {
+                        new.iter().find_map(|(term, new)| {
+                            old.get(term)
+                                .filter(|old| new < *old)
+                                .map(|old| format!("`{term}` of `{school_id}` from {old} to {new}"))
+                        })
+                    }
+                    _ => None,
+                },
+            )
+    }
+}
+
+impl IntoIterator for TermSyncResult {
+    type Item = ClassDataSync;
+    type IntoIter = std::vec::IntoIter<ClassDataSync>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.sync_data.into_iter()
+    }
+}
+
 // ALL SYNCS - for getting all information from class
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -216,14 +785,171 @@ pub struct AllSync {
     pub max_records_count: Option<u16>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AllSyncResult {
+    /// also read from `last_update` as sent by older servers
+    #[serde(alias = "last_update")]
     pub new_latest_sync: u64,
+    /// also read from `data` as sent by older servers
+    #[serde(alias = "data", deserialize_with = "deserialize_records")]
     pub sync_data: Vec<ClassDataSync>,
     pub has_more: bool,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+/// ```
+/// use classy_sync::data_stores::sync_requests::{AllSyncResult, SyncAction};
+///
+/// let result: AllSyncResult = serde_json::from_str(
+///     r#"{
+///         "new_latest_sync": 1,
+///         "has_more": false,
+///         "sync_data": [{
+///             "table_name": "schools",
+///             "sync_action": "insert",
+///             "pk_fields": { "id": "marist" },
+///             "relevant_fields": { "name": "Marist" }
+///         }]
+///     }"#,
+/// )
+/// .unwrap();
+/// assert_eq!(result.len(), 1);
+/// for record in result {
+///     assert!(matches!(record.sync_action, SyncAction::Insert));
+/// }
+/// ```
+impl AllSyncResult {
+    pub fn records(&self) -> &[ClassDataSync] {
+        &self.sync_data
+    }
+
+    pub fn len(&self) -> usize {
+        self.sync_data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sync_data.is_empty()
+    }
+
+    /// Checks that a page followed by more moves the sync forward from the `last_sync` it was
+    /// requested with so a misbehaving server cannot keep a sync requesting pages forever
+    pub fn check_progress(&self, last_sync: u64) -> Result<(), Error> {
+        if !self.has_more {
+            return Ok(());
+        }
+        if self.sync_data.is_empty() {
+            return Err(Error::InvalidPagination {
+                message: "an empty page says there are more".to_string(),
+            });
+        }
+        if self.new_latest_sync < last_sync {
+            return Err(Error::InvalidPagination {
+                message: format!(
+                    "the sequence went back from {last_sync} to {}",
+                    self.new_latest_sync
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Describes the server reporting an earlier latest sequence than the `last_sync` already
+    /// synced which happens when the server was rolled back
+    pub fn sequence_skew(&self, last_sync: u64) -> Option<String> {
+        (self.new_latest_sync < last_sync).then(|| {
+            format!(
+                "the latest sequence went back from {last_sync} to {}",
+                self.new_latest_sync
+            )
+        })
+    }
+}
+
+impl IntoIterator for AllSyncResult {
+    type Item = ClassDataSync;
+    type IntoIter = std::vec::IntoIter<ClassDataSync>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.sync_data.into_iter()
+    }
+}
+
+// STRICT RESULTS - rejecting fields this version does not know so protocol drift is caught
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrictClassDataSync {
+    table_name: TableName,
+    sync_action: SyncAction,
+    pk_fields: HashMap<String, Value>,
+    relevant_fields: Option<HashMap<String, Value>>,
+}
+
+impl From<StrictClassDataSync> for ClassDataSync {
+    fn from(sync: StrictClassDataSync) -> Self {
+        ClassDataSync {
+            table_name: sync.table_name,
+            sync_action: sync.sync_action,
+            pk_fields: sync.pk_fields,
+            relevant_fields: sync.relevant_fields,
+        }
+    }
+}
+
+/// Parses into an `AllSyncResult` failing on any field that is not part of it
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrictAllSyncResult {
+    #[serde(alias = "last_update")]
+    new_latest_sync: u64,
+    #[serde(alias = "data")]
+    sync_data: Vec<StrictClassDataSync>,
+    has_more: bool,
+}
+
+impl From<StrictAllSyncResult> for AllSyncResult {
+    fn from(result: StrictAllSyncResult) -> Self {
+        AllSyncResult {
+            new_latest_sync: result.new_latest_sync,
+            sync_data: result.sync_data.into_iter().map(Into::into).collect(),
+            has_more: result.has_more,
+        }
+    }
+}
+
+/// Parses into a `TermSyncResult` failing on any field that is not part of it
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StrictTermSyncResult {
+    new_sync_term_sequences: HashMap<String, SchoolEntry>,
+    #[serde(alias = "data")]
+    sync_data: Vec<StrictClassDataSync>,
+    any_has_more: bool,
+}
+
+impl From<StrictTermSyncResult> for TermSyncResult {
+    fn from(result: StrictTermSyncResult) -> Self {
+        TermSyncResult {
+            new_sync_term_sequences: result.new_sync_term_sequences,
+            sync_data: result.sync_data.into_iter().map(Into::into).collect(),
+            any_has_more: result.any_has_more,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Display, EnumString, Debug, Clone, Copy, PartialEq, Eq)]
+#[strum(ascii_case_insensitive)]
+pub enum Season {
+    #[serde(alias = "fall")]
+    Fall,
+    #[serde(alias = "spring")]
+    Spring,
+    #[serde(alias = "summer")]
+    Summer,
+    #[serde(alias = "winter")]
+    Winter,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Term {
     pub id: String,
     pub school_id: String,
@@ -233,8 +959,465 @@ pub struct Term {
     pub still_collecting: bool,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+impl Term {
+    /// Checks the season and year are something a term can actually have returning the
+    /// normalized season
+    pub fn verify(&self) -> Result<Season, Error> {
+        let mut invalid_values = vec![];
+        let season = Season::from_str(&self.season);
+        if season.is_err() {
+            invalid_values.push(format!("season `{}`", self.season));
+        }
+        if !VALID_TERM_YEARS.contains(&self.year) {
+            invalid_values.push(format!("year `{}`", self.year));
+        }
+        match season {
+            Ok(season) if invalid_values.is_empty() => Ok(season),
+            _ => Err(Error::InvalidSchemaValues {
+                message: "Invalid term".to_string(),
+                invalid_values,
+                record: serde_json::to_value(self)?,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub struct School {
     pub id: String,
     pub name: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn tables_in_dependency_order() {
+        let mut tables = vec![
+            TableName::MeetingTimes,
+            TableName::Sections,
+            TableName::Professors,
+            TableName::Courses,
+            TableName::TermCollections,
+            TableName::Schools,
+        ];
+        tables.sort();
+        assert_eq!(
+            tables,
+            vec![
+                TableName::Schools,
+                TableName::Courses,
+                TableName::Professors,
+                TableName::TermCollections,
+                TableName::Sections,
+                TableName::MeetingTimes,
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_in_strict_results() {
+        let all_response = r#"{
+            "new_latest_sync": 1,
+            "latest_sync": 2,
+            "has_more": false,
+            "sync_data": []
+        }"#;
+        let result: AllSyncResult = serde_json::from_str(all_response).unwrap();
+        assert_eq!(result.new_latest_sync, 1);
+        assert!(serde_json::from_str::<StrictAllSyncResult>(all_response).is_err());
+
+        let record_response = r#"{
+            "new_sync_term_sequences": { "marist": 3 },
+            "any_has_more": false,
+            "sync_data": [{
+                "table_name": "schools",
+                "sync_action": "insert",
+                "pk_fields": { "id": "marist" },
+                "relevant_fields": { "name": "Marist" },
+                "changed_at": "2024-01-01"
+            }]
+        }"#;
+        let result: TermSyncResult = serde_json::from_str(record_response).unwrap();
+        assert_eq!(result.len(), 1);
+        assert!(serde_json::from_str::<StrictTermSyncResult>(record_response).is_err());
+
+        let mut known_fields: Value = serde_json::from_str(record_response).unwrap();
+        known_fields["sync_data"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("changed_at");
+        let strict: StrictTermSyncResult = serde_json::from_value(known_fields).unwrap();
+        let result = TermSyncResult::from(strict);
+        assert_eq!(result.records()[0].table_name, TableName::Schools);
+    }
+
+    #[test]
+    fn consistent_exclusions() {
+        let mut select_sync = SelectSync::new();
+        select_sync
+            .add_school_sync("marist".to_string(), 10)
+            .unwrap();
+        select_sync
+            .add_exclusion("marist".to_string(), "202440".to_string(), 20)
+            .unwrap();
+        select_sync
+            .add_term_sync("temple".to_string(), "202422".to_string(), 5)
+            .unwrap();
+        select_sync.validate().unwrap();
+        SelectSync::new().validate().unwrap();
+    }
+
+    #[test]
+    fn common_tables_are_classified() {
+        let classified = [
+            (TableName::MeetingTimes, None),
+            (TableName::Sections, None),
+            (TableName::Professors, Some(CommonTable::Professors)),
+            (TableName::Courses, Some(CommonTable::Courses)),
+            (
+                TableName::TermCollections,
+                Some(CommonTable::TermCollections),
+            ),
+            (TableName::Schools, None),
+            (TableName::Unknown("buildings".to_string()), None),
+        ];
+        for (table, common_table) in classified {
+            assert_eq!(table.common_table(), common_table, "{table:?}");
+            let record = ClassDataSync {
+                table_name: table.clone(),
+                sync_action: SyncAction::Delete,
+                pk_fields: HashMap::from([("id".to_string(), Value::from(1))]),
+                relevant_fields: None,
+            };
+            assert_eq!(record.table(), &table);
+            assert!(matches!(record.action(), SyncAction::Delete));
+            assert_eq!(record.is_common_table(), common_table.is_some());
+            // the names of common tables are the names of their tables
+            if let Some(common_table) = common_table {
+                assert_eq!(common_table.to_string(), table.to_string());
+            }
+        }
+        // unknown tables keep their name through parsing, formatting and serde
+        let buildings = TableName::Unknown("buildings".to_string());
+        assert_eq!("buildings".parse::<TableName>().unwrap(), buildings);
+        assert_eq!(buildings.to_string(), "buildings");
+        assert_eq!(serde_json::to_string(&buildings).unwrap(), r#""buildings""#);
+        assert_eq!(
+            serde_json::from_str::<TableName>(r#""buildings""#).unwrap(),
+            buildings
+        );
+        assert_eq!(
+            serde_json::from_str::<TableName>(r#""meeting_times""#).unwrap(),
+            TableName::MeetingTimes
+        );
+        assert!(serde_json::from_str::<TableName>("1").is_err());
+        assert_eq!(
+            buildings.cmp(&TableName::Unknown("rooms".to_string())),
+            std::cmp::Ordering::Less
+        );
+    }
+
+    #[test]
+    fn reconcile_keeps_requested_sequences() {
+        let mut select_sync = SelectSync::new();
+        select_sync
+            .add_school_sync("marist".to_string(), 5)
+            .unwrap();
+        select_sync
+            .add_exclusion("marist".to_string(), "202440".to_string(), 20)
+            .unwrap();
+        select_sync
+            .add_term_sync("temple".to_string(), "202422".to_string(), 7)
+            .unwrap();
+        select_sync
+            .add_term_sync("temple".to_string(), "202510".to_string(), 7)
+            .unwrap();
+        let new_sequences =
+            serde_json::from_str(r#"{ "marist": { "202440": 25 }, "temple": { "202510": 12 } }"#)
+                .unwrap();
+
+        let reached = select_sync.reconcile(&new_sequences);
+        assert_eq!(reached["marist"], SchoolEntry::Sequence(5));
+        assert_eq!(
+            reached["temple"],
+            SchoolEntry::TermToSequence(IndexMap::from([
+                ("202422".to_string(), 7),
+                ("202510".to_string(), 12),
+            ]))
+        );
+    }
+
+    #[test]
+    fn pages_with_more_make_progress() {
+        let mut page: AllSyncResult =
+            serde_json::from_str(&fs::read_to_string("test-syncs/maristfall2024/02.json").unwrap())
+                .unwrap();
+        page.has_more = true;
+        assert!(page.check_progress(6303).is_ok());
+        assert!(matches!(
+            page.check_progress(7000),
+            Err(Error::InvalidPagination { .. })
+        ));
+        let records = std::mem::take(&mut page.sync_data);
+        assert!(matches!(
+            page.check_progress(6303),
+            Err(Error::InvalidPagination { .. })
+        ));
+        // the last page can be empty
+        page.has_more = false;
+        assert!(page.check_progress(6303).is_ok());
+
+        let mut page = TermSyncResult {
+            new_sync_term_sequences: HashMap::from([(
+                "marist".to_string(),
+                SchoolEntry::TermToSequence(IndexMap::from([("202440".to_string(), 100)])),
+            )]),
+            sync_data: records,
+            any_has_more: true,
+        };
+        let mut request = SelectSync::new();
+        request
+            .add_term_sync("marist".to_string(), "202440".to_string(), 50)
+            .unwrap();
+        assert!(page.check_progress(&request).is_ok());
+        let mut ahead = SelectSync::new();
+        ahead
+            .add_term_sync("marist".to_string(), "202440".to_string(), 150)
+            .unwrap();
+        assert!(matches!(
+            page.check_progress(&ahead),
+            Err(Error::InvalidPagination { .. })
+        ));
+        page.sync_data.clear();
+        assert!(matches!(
+            page.check_progress(&request),
+            Err(Error::InvalidPagination { .. })
+        ));
+
+        // the last page is not checked for progress but can still be behind
+        page.any_has_more = false;
+        assert!(page.check_progress(&ahead).is_ok());
+        assert!(page.sequence_skew(&ahead).is_some());
+        assert!(page.sequence_skew(&request).is_none());
+    }
+
+    #[test]
+    fn contradictory_exclusions() {
+        let contradictions = [
+            // already covered by the school's sync
+            ("marist", "202440", 10),
+            // the school is synced by term
+            ("temple", "202422", 20),
+            // the school is not synced at all
+            ("vassar", "202440", 20),
+        ];
+        for (school_id, term_collection_id, sequence) in contradictions {
+            let mut select_sync = SelectSync::new();
+            select_sync
+                .add_school_sync("marist".to_string(), 10)
+                .unwrap();
+            select_sync
+                .add_term_sync("temple".to_string(), "202410".to_string(), 5)
+                .unwrap();
+            select_sync
+                .add_exclusion(
+                    school_id.to_string(),
+                    term_collection_id.to_string(),
+                    sequence,
+                )
+                .unwrap();
+            assert!(matches!(
+                select_sync.validate(),
+                Err(Error::InconsistentSelectSync { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn flattened_exclusions() {
+        let mut select_sync = SelectSync::new();
+        select_sync
+            .add_exclusion("marist".to_string(), "202440".to_string(), 10)
+            .unwrap();
+        select_sync
+            .add_exclusion("marist".to_string(), "202540".to_string(), 20)
+            .unwrap();
+        select_sync
+            .add_exclusion("temple".to_string(), "202422".to_string(), 30)
+            .unwrap();
+
+        let mut exclusions: Vec<_> = select_sync.exclusions().collect();
+        exclusions.sort();
+        assert_eq!(
+            exclusions,
+            vec![
+                ("marist", "202440", 10),
+                ("marist", "202540", 20),
+                ("temple", "202422", 30),
+            ]
+        );
+        assert_eq!(SelectSync::new().exclusions().count(), 0);
+    }
+
+    #[test]
+    fn with_capacity_is_empty() {
+        let select_sync = SelectSync::with_capacity(100);
+        assert!(select_sync.get_schools().is_empty());
+        assert!(select_sync.get_schools().capacity() >= 100);
+        assert_eq!(select_sync, SelectSync::new());
+        assert_eq!(
+            serde_json::to_string(&select_sync).unwrap(),
+            serde_json::to_string(&SelectSync::new()).unwrap()
+        );
+    }
+
+    /// What the derived `Deserialize` parsed records as before it was written by hand
+    #[derive(Deserialize, Serialize)]
+    struct DerivedSync {
+        table_name: TableName,
+        sync_action: SyncAction,
+        pk_fields: HashMap<String, Value>,
+        relevant_fields: Option<HashMap<String, Value>>,
+    }
+
+    #[derive(Deserialize)]
+    struct DerivedPage {
+        sync_data: Vec<DerivedSync>,
+    }
+
+    #[test]
+    fn empty_records_are_rejected() {
+        for action in ["insert", "update", "delete"] {
+            for relevant_fields in [
+                "",
+                r#", "relevant_fields": {}"#,
+                r#", "relevant_fields": null"#,
+            ] {
+                let record: ClassDataSync = serde_json::from_str(&format!(
+                    r#"{{ "table_name": "schools", "sync_action": "{action}", "pk_fields": {{}}{relevant_fields} }}"#
+                ))
+                .unwrap();
+                assert!(
+                    matches!(record.verify_record(), Err(Error::EmptyRecord { .. })),
+                    "{action} {relevant_fields}"
+                );
+            }
+            let keyed: ClassDataSync = serde_json::from_str(&format!(
+                r#"{{ "table_name": "schools", "sync_action": "{action}", "pk_fields": {{ "id": "marist" }} }}"#
+            ))
+            .unwrap();
+            assert!(keyed.verify_record().is_ok());
+        }
+    }
+
+    #[test]
+    fn keyless_updates_and_deletes_are_rejected() {
+        for (action, is_ok) in [("insert", true), ("update", false), ("delete", false)] {
+            let record: ClassDataSync = serde_json::from_str(&format!(
+                r#"{{ "table_name": "schools", "sync_action": "{action}", "pk_fields": {{}}, "relevant_fields": {{ "name": "Marist" }} }}"#
+            ))
+            .unwrap();
+            let result = record.verify_record();
+            assert_eq!(result.is_ok(), is_ok, "{action}");
+            if !is_ok {
+                assert!(matches!(result, Err(Error::EmptyRecord { .. })), "{action}");
+            }
+        }
+    }
+
+    #[test]
+    fn records_parse_as_derived() {
+        let pages = [
+            "test-syncs/maristfall2024/01.json",
+            "test-syncs/maristfall2024/05.json",
+            "test-syncs/maristterms/202440.json",
+        ];
+        for page in pages {
+            let json = fs::read_to_string(page).unwrap();
+            let parsed: Vec<ClassDataSync> = if page.contains("maristterms") {
+                serde_json::from_str::<TermSyncResult>(&json)
+                    .unwrap()
+                    .sync_data
+            } else {
+                serde_json::from_str::<AllSyncResult>(&json)
+                    .unwrap()
+                    .sync_data
+            };
+            let derived = serde_json::from_str::<DerivedPage>(&json)
+                .unwrap()
+                .sync_data;
+            assert_eq!(
+                serde_json::to_value(&parsed).unwrap(),
+                serde_json::to_value(&derived).unwrap(),
+                "{page} parsed differently"
+            );
+        }
+
+        let reordered: ClassDataSync = serde_json::from_str(
+            r#"{"pk_fields": {"id": "marist"}, "relevant_fields": null, "sequence": 1,
+                "sync_action": "delete", "table_name": "schools"}"#,
+        )
+        .unwrap();
+        assert_eq!(reordered.table_name, TableName::Schools);
+        assert!(reordered.relevant_fields.is_none());
+
+        let missing = serde_json::from_str::<ClassDataSync>(
+            r#"{"table_name": "schools", "sync_action": "delete"}"#,
+        )
+        .unwrap_err();
+        assert!(missing.to_string().contains("missing field `pk_fields`"));
+        let duplicate = serde_json::from_str::<ClassDataSync>(
+            r#"{"table_name": "schools", "table_name": "courses"}"#,
+        )
+        .unwrap_err();
+        assert!(
+            duplicate
+                .to_string()
+                .contains("duplicate field `table_name`")
+        );
+    }
+
+    #[test]
+    fn legacy_field_names() {
+        let record = r#"{
+            "table_name": "schools",
+            "sync_action": "insert",
+            "pk_fields": { "id": "marist" },
+            "relevant_fields": { "name": "Marist" }
+        }"#;
+        for (latest, data) in [
+            ("new_latest_sync", "sync_data"),
+            ("last_update", "data"),
+            ("last_update", "sync_data"),
+        ] {
+            let response =
+                format!(r#"{{ "{latest}": 7, "{data}": [{record}], "has_more": false }}"#);
+            let all: AllSyncResult = serde_json::from_str(&response).unwrap();
+            assert_eq!((all.new_latest_sync, all.len()), (7, 1));
+            let strict: AllSyncResult = serde_json::from_str::<StrictAllSyncResult>(&response)
+                .unwrap()
+                .into();
+            assert_eq!((strict.new_latest_sync, strict.len()), (7, 1));
+        }
+
+        for data in ["sync_data", "data"] {
+            let response = format!(
+                r#"{{ "new_sync_term_sequences": {{ "marist": 7 }}, "{data}": [{record}], "any_has_more": false }}"#
+            );
+            let term: TermSyncResult = serde_json::from_str(&response).unwrap();
+            assert_eq!(term.len(), 1);
+            let strict: TermSyncResult = serde_json::from_str::<StrictTermSyncResult>(&response)
+                .unwrap()
+                .into();
+            assert_eq!(strict.len(), 1);
+        }
+
+        // both names at once is ambiguous
+        let both =
+            r#"{ "new_latest_sync": 7, "last_update": 7, "sync_data": [], "has_more": false }"#;
+        assert!(serde_json::from_str::<AllSyncResult>(both).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use crate::argument_parser::SyncResources;
+use crate::data_stores::replicate_datastore::{
+    Datastore, DatastoreInfo, QueryPlan, QueryRows, RecordHook, SyncMode,
+};
+use crate::data_stores::sync_report::{SyncReport, Warning};
+use crate::data_stores::sync_requests::{
+    AllSyncResult, ClassDataSync, School, SelectSync, SyncOptions, TableName, Term, TermSyncResult,
+};
+use crate::errors::DataStoreError;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// A datastore that writes to several stores keeping them in sync such as a primary database
+/// and an audit copy
+///
+/// Sync options, reads and descriptions come from the primary so the other stores should start
+/// from the same place as it. Every write goes to every store and fails if any of them fails.
+/// Applied pages are held with `begin_sync` so a page that fails on one store is rolled back on
+/// all of them when the stores support it, other writes cannot be undone.
+pub struct TeeDatastore {
+    /// the primary followed by the other stores
+    stores: Vec<Box<dyn Datastore>>,
+    /// whether the caller began a sync so pages are not held separately
+    syncing: bool,
+}
+
+impl TeeDatastore {
+    pub fn new(primary: Box<dyn Datastore>, others: Vec<Box<dyn Datastore>>) -> TeeDatastore {
+        let mut stores = vec![primary];
+        stores.extend(others);
+        TeeDatastore {
+            stores,
+            syncing: false,
+        }
+    }
+
+    fn primary(&mut self) -> &mut dyn Datastore {
+        &mut *self.stores[0]
+    }
+
+    /// Runs the write on every store returning what the primary returned
+    fn write_all<T>(
+        &mut self,
+        mut write: impl FnMut(&mut dyn Datastore) -> Result<T, DataStoreError>,
+    ) -> Result<T, DataStoreError> {
+        let mut results = self
+            .stores
+            .iter_mut()
+            .map(|store| write(&mut **store))
+            .collect::<Result<Vec<T>, DataStoreError>>()?;
+        Ok(results.swap_remove(0))
+    }
+
+    /// Applies a page to every store rolling all of them back when any of them fails
+    fn apply_page(
+        &mut self,
+        mut apply: impl FnMut(&mut dyn Datastore) -> Result<SyncReport, DataStoreError>,
+    ) -> Result<SyncReport, DataStoreError> {
+        if self.syncing {
+            return self.write_all(apply);
+        }
+        self.begin_sync()?;
+        let result = self.write_all(&mut apply);
+        self.finish_sync(result.is_ok())?;
+        result
+    }
+}
+
+impl Datastore for TeeDatastore {
+    fn set_request_sync_resources(
+        &mut self,
+        resources: SyncResources,
+    ) -> Result<Vec<Warning>, DataStoreError> {
+        self.write_all(|store| store.set_request_sync_resources(resources.clone()))
+    }
+
+    fn unset_request_sync_resources(
+        &mut self,
+        resources: SyncResources,
+    ) -> Result<(), DataStoreError> {
+        self.write_all(|store| store.unset_request_sync_resources(resources.clone()))
+    }
+
+    fn generate_sync_options(&mut self) -> Result<SyncOptions, DataStoreError> {
+        self.primary().generate_sync_options()
+    }
+
+    fn sync_mode(&mut self) -> Result<SyncMode, DataStoreError> {
+        self.primary().sync_mode()
+    }
+
+    fn count_pending_strategies(&mut self) -> Result<usize, DataStoreError> {
+        self.primary().count_pending_strategies()
+    }
+
+    fn begin_sync(&mut self) -> Result<(), DataStoreError> {
+        for (begun, store) in self.stores.iter_mut().enumerate() {
+            if let Err(err) = store.begin_sync() {
+                for store in &mut self.stores[..begun] {
+                    let _ = store.finish_sync(false);
+                }
+                return Err(err);
+            }
+        }
+        self.syncing = true;
+        Ok(())
+    }
+
+    fn finish_sync(&mut self, commit: bool) -> Result<(), DataStoreError> {
+        self.syncing = false;
+        // every store is finished even when one of them fails
+        let results: Vec<_> = self
+            .stores
+            .iter_mut()
+            .map(|store| store.finish_sync(commit))
+            .collect();
+        results.into_iter().collect()
+    }
+
+    fn execute_all_request_sync(
+        &mut self,
+        all_sync_response: AllSyncResult,
+    ) -> Result<SyncReport, DataStoreError> {
+        self.apply_page(|store| store.execute_all_request_sync(all_sync_response.clone()))
+    }
+
+    fn execute_select_request_sync(
+        &mut self,
+        select_sync_request: SelectSync,
+        select_sync_response: TermSyncResult,
+    ) -> Result<SyncReport, DataStoreError> {
+        self.apply_page(|store| {
+            store.execute_select_request_sync(
+                select_sync_request.clone(),
+                select_sync_response.clone(),
+            )
+        })
+    }
+
+    fn add_schools(&mut self, schools: Vec<School>) -> Result<Vec<String>, DataStoreError> {
+        self.write_all(|store| store.add_schools(schools.clone()))
+    }
+
+    fn add_terms(&mut self, terms: Vec<Term>) -> Result<Vec<(String, String)>, DataStoreError> {
+        self.write_all(|store| store.add_terms(terms.clone()))
+    }
+
+    fn prune_before(&mut self, sequence: u64) -> Result<usize, DataStoreError> {
+        self.write_all(|store| store.prune_before(sequence))
+    }
+
+    fn resync_table(&mut self, table: TableName) -> Result<(), DataStoreError> {
+        self.write_all(|store| store.resync_table(table.clone()))
+    }
+
+    /// The hook is called with the records the primary applied
+    fn set_record_hook(&mut self, hook: Option<RecordHook>) -> Result<(), DataStoreError> {
+        self.primary().set_record_hook(hook)
+    }
+
+    fn get_row(
+        &mut self,
+        table: TableName,
+        pk: &HashMap<String, Value>,
+    ) -> Result<Option<HashMap<String, Value>>, DataStoreError> {
+        self.primary().get_row(table, pk)
+    }
+
+    fn read_only_query(&mut self, sql: &str) -> Result<QueryRows, DataStoreError> {
+        self.primary().read_only_query(sql)
+    }
+
+    fn explain(&mut self, records: Vec<ClassDataSync>) -> Result<Vec<QueryPlan>, DataStoreError> {
+        self.primary().explain(records)
+    }
+
+    fn bookkeeping(&mut self) -> Result<Vec<(String, QueryRows)>, DataStoreError> {
+        self.primary().bookkeeping()
+    }
+
+    fn schema_version(&mut self) -> Result<u32, DataStoreError> {
+        self.primary().schema_version()
+    }
+
+    fn expected_schema_version(&mut self) -> Result<u32, DataStoreError> {
+        self.primary().expected_schema_version()
+    }
+
+    fn is_persistent(&mut self) -> Result<bool, DataStoreError> {
+        self.primary().is_persistent()
+    }
+
+    fn describe(&mut self) -> Result<DatastoreInfo, DataStoreError> {
+        self.primary().describe()
+    }
+
+    fn integrity_check(&mut self) -> Result<bool, DataStoreError> {
+        let mut sound = true;
+        for store in &mut self.stores {
+            sound &= store.integrity_check()?;
+        }
+        Ok(sound)
+    }
+
+    fn flush(&mut self) -> Result<(), DataStoreError> {
+        for store in &mut self.stores {
+            store.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::data_stores::sqlite::{Sqlite, storage::SqliteConfig};
+    use std::fs;
+
+    fn first_page() -> AllSyncResult {
+        serde_json::from_str(&fs::read_to_string("test-syncs/maristfall2024/01.json").unwrap())
+            .unwrap()
+    }
+
+    fn temp_db(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "classy-sync-tee-{name}-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn file_store(db_path: &str) -> Sqlite {
+        Sqlite::new(SqliteConfig {
+            db_path: Some(db_path.to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn sync_lands_in_every_store() {
+        let db_path = temp_db("every-store");
+        let mut tee = TeeDatastore::new(
+            Box::new(Sqlite::new(SqliteConfig::default()).unwrap()),
+            vec![Box::new(file_store(&db_path))],
+        );
+        tee.set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let page = first_page();
+        let course = page
+            .sync_data
+            .iter()
+            .find(|sync| sync.table_name == TableName::Courses)
+            .unwrap()
+            .pk_fields
+            .clone();
+        let report = tee.execute_all_request_sync(page).unwrap();
+        assert!(report.skipped.is_empty());
+        assert!(tee.get_row(TableName::Courses, &course).unwrap().is_some());
+        drop(tee);
+
+        let mut file = file_store(&db_path);
+        assert!(file.get_row(TableName::Courses, &course).unwrap().is_some());
+        assert_eq!(file.last_all_sync_sequence().unwrap(), 6303);
+        drop(file);
+        let _ = fs::remove_file(&db_path);
+    }
+
+    #[test]
+    fn failed_store_rolls_back_every_store() {
+        let db_path = temp_db("rolled-back");
+        let mut file = file_store(&db_path);
+        file.set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        // the page was already applied so its inserts fail on this store
+        file.execute_all_request_sync(first_page()).unwrap();
+        drop(file);
+        let mut primary = Sqlite::new(SqliteConfig::default()).unwrap();
+        primary
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let mut tee = TeeDatastore::new(Box::new(primary), vec![Box::new(file_store(&db_path))]);
+
+        assert!(tee.execute_all_request_sync(first_page()).is_err());
+        let SyncOptions::All(all_sync) = tee.generate_sync_options().unwrap() else {
+            panic!("expected all sync options");
+        };
+        assert_eq!(all_sync.last_sync, 0);
+        drop(tee);
+        let _ = fs::remove_file(&db_path);
+    }
+}
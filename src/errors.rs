@@ -12,15 +12,47 @@ pub enum Error {
     #[error("Invalid input: {message}")]
     InputParseError { message: String },
 
+    #[error("Invalid sync configuration: {message}")]
+    InvalidConfig { message: String },
+
     #[error("Invalid select sync addition: {message}")]
     DuplicateSyncAddition { message: String },
 
+    #[error("Inconsistent select sync: {message}")]
+    InconsistentSelectSync { message: String },
+
+    #[error("Invalid pagination from the server: {message}")]
+    InvalidPagination { message: String },
+
+    #[error(
+        "The server is behind the datastore: {message}, reset the datastore if the server was rolled back"
+    )]
+    SequenceSkew { message: String },
+
+    #[error("The server sent sequences that were not requested: {message}")]
+    UnrequestedSchools { message: String },
+
+    #[error("Conflicting sync mode: {message}")]
+    ConflictingSyncMode { message: String },
+
+    #[error("Expected a JSON response but got `{content_type}`: {snippet}")]
+    UnexpectedContentType {
+        content_type: String,
+        snippet: String,
+    },
+
+    #[error("Could not read file: {0}")]
+    FileError(#[from] std::io::Error),
+
     #[error("Failed to parse input {0}")]
     JsonParseError(#[from] serde_json::Error),
 
     #[error(transparent)]
     DataStoreError(#[from] DataStoreError),
 
+    #[error("Record has no primary key columns to match or no fields to apply: {record}")]
+    EmptyRecord { record: Value },
+
     #[error("Record with invalid schema {message}: {values}\n{record}", values=invalid_values.join(", "))]
     InvalidSchemaValues {
         message: String,
@@ -34,4 +66,33 @@ pub enum DataStoreError {
     #[error("Sqlite Error: {0}")]
     #[cfg(feature = "sqlite")]
     SqliteError(#[from] SqliteError),
+
+    /// an error of a backend without its own variant such as one implemented outside this crate
+    #[error("Datastore error: {0}")]
+    Other(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl DataStoreError {
+    /// Wraps any error or message of a backend without its own variant
+    pub fn other(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> DataStoreError {
+        DataStoreError::Other(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn other_datastore_errors() {
+        let err = DataStoreError::other("no backend is available");
+        assert_eq!(err.to_string(), "Datastore error: no backend is available");
+
+        let err = Error::from(DataStoreError::other(std::io::Error::other("disk is full")));
+        assert_eq!(err.to_string(), "Datastore error: disk is full");
+        let source = err.source().unwrap();
+        assert!(source.downcast_ref::<std::io::Error>().is_some());
+        assert!(source.source().is_none());
+    }
 }
@@ -1,3 +1,9 @@
+// every datastore backend enabled at compile time should be listed here
+#[cfg(not(any(feature = "sqlite")))]
+compile_error!(
+    "classy-sync needs a datastore backend feature, enable one of the available backends: `sqlite`"
+);
+
 pub mod argument_parser;
 pub mod data_stores;
 pub mod errors;
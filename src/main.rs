@@ -1,17 +1,49 @@
 #![allow(dead_code)]
+use chrono::Utc;
+use clap::CommandFactory;
 use clap::Parser;
 use clap::Subcommand;
-use clap::command;
-use classy_sync::argument_parser::SyncResources;
-use classy_sync::data_stores::sync_requests::School;
+use classy_sync::argument_parser::{
+    SelectSyncOptions, SyncResources, YearSeasonTermIds, parse_table,
+};
 use classy_sync::data_stores::{
-    replicate_datastore, replicate_datastore::Datastore, sync_requests,
+    apply::{SkewPolicy, UnrequestedPolicy},
+    replicate_datastore,
+    replicate_datastore::{Backend, Datastore, DatastoreOptions, QueryRows, SyncMode},
+    sync_report::{SyncReport, Warning},
+    sync_requests,
 };
-use classy_sync::errors::{DataStoreError, Error};
+use classy_sync::errors::Error;
 use dotenv::dotenv;
-use reqwest::blocking::Client;
+use indexmap::IndexMap;
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use reqwest::Url;
+use reqwest::blocking::{Client, Request, RequestBuilder, Response};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue, PROXY_AUTHORIZATION};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+use strum_macros::{Display, EnumString};
 
 const CLASSY_URI: &str = "http://localhost:3000";
+/// how many fetched records may wait to be applied when streaming which is two full pages
+const DEFAULT_APPLY_BUFFER: usize = 20_000;
+/// The header a select sync sends its `idempotency_key` in
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// How many times a select sync page is sent before its failure is returned
+const SELECT_ATTEMPTS: usize = 3;
+/// The header a server advertises the most records it returns in a page with
+const MAX_RECORDS_HEADER: &str = "x-max-records";
+/// How much of an unexpected response body is kept in the error
+const BODY_SNIPPET_CHARS: usize = 200;
 
 // TODO: eventually this file will also be responsible for
 //   - authentication?
@@ -19,23 +51,242 @@ const CLASSY_URI: &str = "http://localhost:3000";
 
 pub struct SyncConfig {
     pub uri: String,
+    pub user_agent: String,
+    /// fetch the next page while the current one is being applied
+    pub stream_apply: bool,
+    /// prefix for every route for apis mounted under a path such as `/api/v1`
+    pub base_path: String,
+    /// a client to use instead of building one, e.g. for proxies, custom certificates or mutual tls
+    ///
+    /// timeouts and default headers (including the user agent) then come from this client
+    pub client: Option<Client>,
+    /// the least amount of time between the start of consecutive requests of a sync
+    pub min_request_interval: Duration,
+    /// how many fetched records may wait to be applied when streaming before fetching blocks
+    ///
+    /// a page with more records than this is still fetched once every earlier page is applied
+    pub apply_buffer: usize,
+    /// stop after this many pages even when the server has more
+    pub page_limit: Option<usize>,
+    /// keep the pages of a sync only once every one of them has been applied
+    pub atomic: bool,
+    /// end the sync at a page that fails to apply keeping the pages before it instead of failing
+    ///
+    /// the next page cannot be requested without the sequences of the discarded one so the pages
+    /// after it are left for the next sync
+    pub continue_on_error: bool,
+    /// fail on sync responses with fields this version does not know instead of ignoring them
+    pub strict_responses: bool,
+    /// write every request and response of a sync to this directory to attach to bug reports
+    pub capture_dir: Option<PathBuf>,
+    /// append the body of every response a sync receives to this JSON lines file for audits
+    pub audit_log: Option<PathBuf>,
+    /// move the audit log to the first unused `<audit_log>.<n>` before it grows past this many
+    /// bytes, the moved logs are never removed so they have to be pruned by hand
+    pub audit_max_bytes: Option<u64>,
+    /// what to do when the server is at an earlier sequence than the datastore
+    pub on_sequence_skew: SkewPolicy,
+    /// what to do when a select sync response has sequences for schools that were not requested
+    pub on_unrequested_school: UnrequestedPolicy,
+    /// which redirects of the server are followed, an injected `client` keeps its own policy
+    pub redirects: RedirectPolicy,
+    /// send an `Idempotency-Key` with each select sync page, the same for every retry of it, so a
+    /// server can tell the same request delivered more than once from a new one
+    pub idempotency_keys: bool,
+    /// only sync these of the schools and terms set to be synced leaving the rest for later syncs
+    pub only_targets: Option<SelectSyncOptions>,
+    /// response bodies larger than this many bytes are written to a temp file and decoded from it
+    /// instead of being kept in memory
+    ///
+    /// this cannot be combined with `capture_dir` or `audit_log` which keep the whole body in memory
+    /// to write it out
+    pub max_body_in_memory: Option<usize>,
+}
+
+/// What a sync applied and the sequences its last page reached
+#[derive(Debug)]
+pub enum SyncOutcome {
+    All {
+        new_sequence: u64,
+        report: SyncReport,
+    },
+    Select {
+        /// the sequence of each synced school or of each of its synced terms
+        sequences: IndexMap<String, sync_requests::SchoolEntry>,
+        report: SyncReport,
+    },
+}
+
+impl SyncOutcome {
+    fn new(reached: sync_requests::SyncOptions, report: SyncReport) -> SyncOutcome {
+        match reached {
+            sync_requests::SyncOptions::All(all_sync) => SyncOutcome::All {
+                new_sequence: all_sync.last_sync,
+                report,
+            },
+            sync_requests::SyncOptions::Select(select_sync) => SyncOutcome::Select {
+                sequences: select_sync.get_schools().clone(),
+                report,
+            },
+        }
+    }
+
+    pub fn report(&self) -> &SyncReport {
+        match self {
+            SyncOutcome::All { report, .. } | SyncOutcome::Select { report, .. } => report,
+        }
+    }
+
+    pub fn into_report(self) -> SyncReport {
+        match self {
+            SyncOutcome::All { report, .. } | SyncOutcome::Select { report, .. } => report,
+        }
+    }
+}
+
+/// Which redirects are followed
+///
+/// reqwest drops the `Authorization` and cookie headers of a redirect to another host or port
+/// whichever is chosen
+#[derive(Debug, Default, Display, EnumString, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum RedirectPolicy {
+    /// fail on every redirect
+    None,
+    /// follow redirects to the same host and port failing on any other
+    #[default]
+    SameHost,
+    /// follow every redirect like a browser
+    Follow,
+}
+
+/// How many redirects are followed before failing like reqwest's default policy
+const MAX_REDIRECTS: usize = 10;
+
+impl RedirectPolicy {
+    /// The policy to build a client with which is also what an injected `client` can be built with
+    pub fn policy(self) -> reqwest::redirect::Policy {
+        match self {
+            RedirectPolicy::None => reqwest::redirect::Policy::custom(|attempt| {
+                let to = attempt.url().to_string();
+                attempt.error(format!("redirects are not followed but got one to `{to}`"))
+            }),
+            RedirectPolicy::SameHost => reqwest::redirect::Policy::custom(|attempt| {
+                let from = &attempt.previous()[0];
+                let to = attempt.url();
+                if to.host_str() != from.host_str()
+                    || to.port_or_known_default() != from.port_or_known_default()
+                {
+                    let to = to.to_string();
+                    attempt.error(format!("redirected to `{to}` on another host"))
+                } else if attempt.previous().len() > MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else {
+                    attempt.follow()
+                }
+            }),
+            RedirectPolicy::Follow => reqwest::redirect::Policy::limited(MAX_REDIRECTS),
+        }
+    }
 }
 
 impl SyncConfig {
+    fn get_base(&self) -> String {
+        let base_path = self.base_path.trim_matches('/');
+        if base_path.is_empty() {
+            self.uri.trim_end_matches('/').to_string()
+        } else {
+            format!("{}/{base_path}", self.uri.trim_end_matches('/'))
+        }
+    }
+
     fn get_sync_all(&self) -> String {
-        format!("{}/sync/all", self.uri)
+        format!("{}/sync/all", self.get_base())
     }
 
     fn get_sync_select(&self) -> String {
-        format!("{}/sync/schools", self.uri)
+        format!("{}/sync/schools", self.get_base())
     }
 
     fn get_schools(&self) -> String {
-        format!("{}/get", self.uri)
+        format!("{}/get", self.get_base())
     }
 
     fn get_terms(&self, school: &str) -> String {
-        format!("{}/get/{school}", self.uri)
+        format!("{}/get/{school}", self.get_base())
+    }
+
+    /// Checks what would otherwise only fail part way through a sync with a less clear error
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::InvalidConfig { message });
+        let uri = match Url::parse(&self.get_base()) {
+            Ok(uri) => uri,
+            Err(err) => return invalid(format!("`{}` is not a valid uri: {err}", self.uri)),
+        };
+        if !matches!(uri.scheme(), "http" | "https") {
+            return invalid(format!(
+                "`{}` must use http or https instead of `{}`",
+                self.uri,
+                uri.scheme()
+            ));
+        }
+        if !uri.has_host() {
+            return invalid(format!("`{}` has no host", self.uri));
+        }
+        if uri.query().is_some() || uri.fragment().is_some() {
+            return invalid(format!(
+                "`{}` cannot have a query or fragment since routes are added to it",
+                self.uri
+            ));
+        }
+        if self.client.is_none() && HeaderValue::from_str(&self.user_agent).is_err() {
+            return invalid(format!(
+                "user agent `{}` is not a valid header value",
+                self.user_agent
+            ));
+        }
+        if self.page_limit == Some(0) {
+            return invalid("the page limit must allow at least one page".to_string());
+        }
+        if self.max_body_in_memory.is_some()
+            && (self.capture_dir.is_some() || self.audit_log.is_some())
+        {
+            return invalid(
+                "the max body in memory cannot be combined with a capture dir or audit log which \
+                 keep every body in memory"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// The sync options narrowed to the `only_targets`
+    fn target_options(
+        &self,
+        options: sync_requests::SyncOptions,
+    ) -> Result<sync_requests::SyncOptions, Error> {
+        let Some(targets) = &self.only_targets else {
+            return Ok(options);
+        };
+        match options {
+            sync_requests::SyncOptions::Select(mut select_sync) => {
+                select_sync.narrow_to(targets)?;
+                Ok(sync_requests::SyncOptions::Select(select_sync))
+            }
+            sync_requests::SyncOptions::All(_) => Err(Error::ConflictingSyncMode {
+                message: "only select syncs can be narrowed to targets".to_string(),
+            }),
+        }
+    }
+
+    fn build_client(&self) -> Result<Client, Error> {
+        if let Some(client) = &self.client {
+            return Ok(client.clone());
+        }
+        Ok(Client::builder()
+            .user_agent(&self.user_agent)
+            .redirect(self.redirects.policy())
+            .build()?)
     }
 }
 
@@ -43,6 +294,25 @@ impl Default for SyncConfig {
     fn default() -> Self {
         SyncConfig {
             uri: CLASSY_URI.to_string(),
+            user_agent: format!("classy-sync/{}", env!("CARGO_PKG_VERSION")),
+            stream_apply: false,
+            base_path: String::new(),
+            client: None,
+            min_request_interval: Duration::ZERO,
+            apply_buffer: DEFAULT_APPLY_BUFFER,
+            page_limit: None,
+            atomic: false,
+            continue_on_error: false,
+            strict_responses: false,
+            capture_dir: None,
+            audit_log: None,
+            audit_max_bytes: None,
+            on_sequence_skew: SkewPolicy::default(),
+            on_unrequested_school: UnrequestedPolicy::default(),
+            redirects: RedirectPolicy::default(),
+            idempotency_keys: true,
+            only_targets: None,
+            max_body_in_memory: None,
         }
     }
 }
@@ -52,6 +322,108 @@ impl Default for SyncConfig {
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
+
+    /// Skip records that fail to apply instead of aborting the sync
+    #[arg(long, global = true, overrides_with = "fail_fast")]
+    continue_on_error: bool,
+
+    /// Abort the sync on the first record that fails to apply (default)
+    #[arg(long, global = true)]
+    fail_fast: bool,
+
+    /// Ignore delete records keeping rows the server no longer has
+    #[arg(long, global = true, overrides_with = "include_deletes")]
+    no_deletes: bool,
+
+    /// Apply delete records like every other record (default)
+    #[arg(long, global = true)]
+    include_deletes: bool,
+
+    /// Fail records that change an unexpected number of rows instead of warning about them
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Fail on records of tables this version does not know instead of skipping them
+    #[arg(long, global = true)]
+    strict_tables: bool,
+
+    /// Least number of milliseconds between consecutive requests of a sync
+    #[arg(long, global = true, default_value_t = 0)]
+    rate_limit_ms: u64,
+
+    /// Print every underlying cause of a failure instead of only the top-level message
+    #[arg(long, global = true)]
+    verbose_errors: bool,
+
+    /// Sqlite database file overriding `SQLITE_DB_PATH`, `:memory:` for a database that is not
+    /// kept
+    #[arg(long, global = true)]
+    db_path: Option<String>,
+
+    /// Apply only the next page of the sync and roll it back to see what it would change
+    #[arg(long, global = true)]
+    no_commit: bool,
+
+    /// Keep the synced pages only if every page of the sync applies
+    #[arg(long, global = true)]
+    atomic: bool,
+
+    /// Fail on sync responses with fields this version does not know
+    #[arg(long, global = true)]
+    strict_responses: bool,
+
+    /// Write every request and response of a sync as JSON files to this directory for bug reports
+    #[arg(long, global = true)]
+    capture_dir: Option<PathBuf>,
+
+    /// Append the body of every response a sync receives to this JSON lines file to keep what the
+    /// server sent
+    #[arg(long, global = true)]
+    audit_log: Option<PathBuf>,
+
+    /// Rotate the audit log to the first unused `<audit log>.<n>` before it grows past this many
+    /// bytes, rotated logs are kept until they are removed by hand
+    #[arg(long, global = true, requires = "audit_log")]
+    audit_max_bytes: Option<u64>,
+
+    /// Stop syncing terms that are no longer collecting after each select sync
+    #[arg(long, global = true)]
+    unset_finished_terms: bool,
+
+    /// Build the lookup indexes after the first all sync into an empty database instead of
+    /// keeping them up to date while it loads
+    #[arg(long, global = true)]
+    defer_indexes: bool,
+
+    /// Whether a server behind the datastore such as after a rollback is a warning or an error
+    #[arg(long, global = true, default_value_t = SkewPolicy::default())]
+    on_sequence_skew: SkewPolicy,
+
+    /// Whether sequences the server sends for schools that were not requested are a warning or an
+    /// error, they are never kept either way
+    #[arg(long, global = true, default_value_t = UnrequestedPolicy::default())]
+    on_unrequested_school: UnrequestedPolicy,
+
+    /// Write response bodies larger than this many bytes to a temp file instead of keeping them in
+    /// memory while they are decoded, which cannot be combined with a capture dir or audit log
+    #[arg(long, global = true, conflicts_with_all = ["capture_dir", "audit_log"])]
+    max_body_in_memory: Option<usize>,
+
+    /// Which redirects of the server to follow (`none`, `same-host` or `follow`)
+    #[arg(long, global = true, default_value_t = RedirectPolicy::default())]
+    redirects: RedirectPolicy,
+
+    /// Kind of datastore to sync into (`sqlite` or `memory`) out of those enabled at compile time
+    #[arg(long, global = true, default_value_t = Backend::default())]
+    backend: Backend,
+
+    /// Do not take the lock file that keeps other runs from using the database at the same time
+    #[arg(long, global = true)]
+    no_lock: bool,
+
+    /// Prefix for the name of every table so the database can be shared with other data
+    #[arg(long, global = true, default_value = "")]
+    table_prefix: String,
 }
 
 #[derive(Subcommand, Debug)]
@@ -71,107 +443,2601 @@ enum Commands {
     },
     #[command(subcommand)]
     Add(AddCommands),
+    Fsck,
+    Status,
+    /// Check for common misconfigurations printing how to fix them
+    Doctor,
+    Validate {
+        file: PathBuf,
+        /// Print the sql that would apply the response with its values written in as literals
+        /// instead of checking it, only run the result for responses that are trusted
+        #[cfg(feature = "sqlite")]
+        #[arg(long)]
+        print_sql_only: bool,
+        /// Write the sql to this file instead of stdout
+        #[cfg(feature = "sqlite")]
+        #[arg(long, requires = "print_sql_only")]
+        output: Option<PathBuf>,
+        /// Print how the datastore would run the statement of each distinct shape of record in the
+        /// response instead of checking it
+        #[arg(long)]
+        explain: bool,
+    },
+    /// Add the schools of a JSON file of `{ "id", "name" }` records
+    SchoolsAdd {
+        file: PathBuf,
+    },
+    /// Add the terms of a JSON file of term records
+    TermsAdd {
+        file: PathBuf,
+    },
+    Prune {
+        /// Sync sequence to remove older bookkeeping before
+        #[arg(long)]
+        before: u64,
+    },
+    /// Clear a table so the next sync fetches it again, only supported for all syncs
+    ResyncTable {
+        table: String,
+    },
+    Sync {
+        /// Set the resources to sync (`all[:tables]` or `select:<sync instructions>`) if none are
+        /// set yet
+        #[arg(long)]
+        init: Option<String>,
+        /// Only sync these of the schools and terms that are set (`marist,202440;temple`) leaving
+        /// the others for later syncs
+        #[arg(long)]
+        only_target: Option<String>,
+    },
+    /// Print the bookkeeping behind the sequences each sync requests
+    Sequences {
+        /// Print each table as an array of JSON objects instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the shell completion script for this command
+    Completions {
+        shell: clap_complete::Shell,
+    },
+    /// Run read only SQL (SELECT or PRAGMA) against the datastore, reading `;` terminated
+    /// statements from stdin when no command is given
+    Shell {
+        /// The statement to run
+        #[arg(short = 'c')]
+        command: Option<String>,
+        /// Print each row as a JSON object instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn main() {
+    dotenv().ok();
+    env_logger::init();
+    let cli = Cli::parse();
+    #[cfg(feature = "sqlite")]
+    if let Some(Commands::Validate {
+        file,
+        print_sql_only: true,
+        output,
+        ..
+    }) = &cli.command
+    {
+        let result = fs::read_to_string(file)
+            .map_err(Error::from)
+            .and_then(|text| write_sql_script(&text, &cli.table_prefix, output.as_deref()));
+        if let Err(err) = result {
+            eprintln!("{}", describe_error(&err, cli.verbose_errors));
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Commands::Validate {
+        file,
+        explain: false,
+        ..
+    }) = &cli.command
+    {
+        match validate_file(file) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(err) => {
+                eprintln!("{}", describe_error(&err, cli.verbose_errors));
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(Commands::Completions { shell }) = &cli.command {
+        write_completions(*shell, &mut std::io::stdout());
+        return;
+    }
+    if let Err(err) = run(&cli) {
+        eprintln!("{}", describe_error(&err, cli.verbose_errors));
+        std::process::exit(1);
+    }
+}
+
+/// Runs every command that uses the datastore
+fn run(cli: &Cli) -> Result<(), Error> {
+    let mut data_store = replicate_datastore::get_datastore(DatastoreOptions {
+        backend: cli.backend,
+        unset_finished_terms: cli.unset_finished_terms,
+        defer_indexes: cli.defer_indexes,
+        continue_on_error: cli.continue_on_error,
+        skip_deletes: cli.no_deletes,
+        is_strict: cli.strict,
+        strict_tables: cli.strict_tables,
+        table_prefix: cli.table_prefix.clone(),
+        no_commit: cli.no_commit,
+        db_path: cli.db_path.clone(),
+        // commands that only read can run alongside a sync
+        lock: !cli.no_lock
+            && !matches!(
+                cli.command,
+                Some(
+                    Commands::Status
+                        | Commands::Fsck
+                        | Commands::Doctor
+                        | Commands::Shell { .. }
+                        | Commands::Sequences { .. }
+                        | Commands::Validate { .. }
+                )
+            ),
+        read_only: matches!(
+            cli.command,
+            Some(Commands::Shell { .. } | Commands::Sequences { .. } | Commands::Validate { .. })
+        ),
+    })?;
+    if let Some(command @ (Commands::Set { .. } | Commands::Unset { .. })) = &cli.command
+        && let Some(warning) = unpersisted_warning(&mut *data_store, command)?
+    {
+        eprintln!("{warning}");
+    }
+    match &cli.command {
+        Some(Commands::Set { sync_instructions }) => {
+            let sync_options = SyncResources::from_input(sync_instructions)?;
+            for warning in data_store.set_request_sync_resources(sync_options)? {
+                eprintln!("Warning: {warning}");
+            }
+        }
+        Some(Commands::Unset { sync_instructions }) => {
+            let sync_options = SyncResources::from_input(sync_instructions)?;
+            data_store.unset_request_sync_resources(sync_options)?;
+        }
+        Some(Commands::Add(flush_command)) => {
+            match flush_command {
+                AddCommands::Schools => {
+                    add_schools(SyncConfig::default(), &mut *data_store)?;
+                }
+                AddCommands::Terms { school_names } => {
+                    let _schools: Vec<String> =
+                        school_names.split(",").map(|s| s.to_string()).collect();
+                    todo!();
+                }
+            }
+            return Ok(());
+        }
+        Some(Commands::SchoolsAdd { file }) => {
+            let (added, existing) = add_schools_from_file(file, &mut *data_store)?;
+            println!("Added {added} schools");
+            for school_id in existing {
+                println!("School `{school_id}` already existed");
+            }
+            return Ok(());
+        }
+        Some(Commands::TermsAdd { file }) => {
+            let (added, existing) = add_terms_from_file(file, &mut *data_store)?;
+            println!("Added {added} terms");
+            for (school_id, term_id) in existing {
+                println!("Term `{term_id}` of `{school_id}` already existed");
+            }
+            return Ok(());
+        }
+        Some(Commands::Status) => {
+            println!("{}", data_store.describe()?);
+            println!("Sync mode: {}", data_store.sync_mode()?);
+            println!(
+                "Pending strategies: {}",
+                data_store.count_pending_strategies()?
+            );
+            return Ok(());
+        }
+        Some(Commands::Completions { .. }) => {
+            unreachable!("completions do not use the datastore")
+        }
+        Some(Commands::Validate { file, .. }) => {
+            let (_, records) = response_records(&fs::read_to_string(file)?)?;
+            for plan in data_store.explain(records)? {
+                println!("{plan}");
+            }
+            return Ok(());
+        }
+        Some(Commands::Sync {
+            init: Some(init), ..
+        }) => {
+            let resources = parse_init(init)?;
+            init_sync_resources(&mut *data_store, resources)?;
+        }
+        Some(Commands::Sync { init: None, .. }) => {}
+        Some(Commands::Prune { before }) => {
+            let pruned = data_store.prune_before(*before)?;
+            println!("Pruned {pruned} bookkeeping rows");
+            return Ok(());
+        }
+        Some(Commands::ResyncTable { table }) => {
+            let table = parse_table(table)?;
+            data_store.resync_table(table.clone())?;
+            println!("Cleared `{table}` to be fetched again by the next sync");
+            return Ok(());
+        }
+        Some(Commands::Doctor) => {
+            let checks = doctor_checks(&mut *data_store, &SyncConfig::default())?;
+            for check in &checks {
+                println!("{check}");
+            }
+            if checks.iter().any(|check| check.critical && !check.passed) {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Commands::Sequences { json }) => {
+            print!("{}", format_bookkeeping(&data_store.bookkeeping()?, *json));
+            return Ok(());
+        }
+        Some(Commands::Shell { command, json }) => {
+            let statements = match command {
+                Some(command) => vec![command.clone()],
+                None => read_statements(std::io::stdin().lock())?,
+            };
+            for sql in statements {
+                let rows = shell_query(&mut *data_store, &sql)?;
+                if *json {
+                    println!("{}", rows_json(&rows));
+                } else {
+                    print!("{}", format_rows(&rows));
+                }
+            }
+            return Ok(());
+        }
+        Some(Commands::Fsck) => {
+            println!("Schema version: {}", data_store.schema_version()?);
+            if data_store.integrity_check()? {
+                println!("Datastore is sound");
+                return Ok(());
+            }
+            eprintln!("Datastore reported integrity problems");
+            std::process::exit(1);
+        }
+        None => {}
+    }
+
+    let only_targets = match &cli.command {
+        Some(Commands::Sync {
+            only_target: Some(targets),
+            ..
+        }) => Some(SelectSyncOptions::from_input_with(
+            targets,
+            &YearSeasonTermIds::default(),
+        )?),
+        _ => None,
+    };
+    let config = SyncConfig {
+        min_request_interval: Duration::from_millis(cli.rate_limit_ms),
+        only_targets,
+        // the rolled back page would be requested again so later pages cannot be inspected
+        page_limit: cli.no_commit.then_some(1),
+        atomic: cli.atomic,
+        continue_on_error: cli.continue_on_error,
+        strict_responses: cli.strict_responses,
+        capture_dir: cli.capture_dir.clone(),
+        audit_log: cli.audit_log.clone(),
+        audit_max_bytes: cli.audit_max_bytes,
+        on_sequence_skew: cli.on_sequence_skew,
+        on_unrequested_school: cli.on_unrequested_school,
+        redirects: cli.redirects,
+        max_body_in_memory: cli.max_body_in_memory,
+        ..Default::default()
+    };
+    let outcome = sync(config, &mut *data_store)?;
+    // the sync is committed already so it is still reported when it cannot be made durable yet
+    if let Err(e) = data_store.flush() {
+        eprintln!("Warning: could not flush the datastore: {e}");
+    }
+    let report = outcome.report();
+    for skipped in &report.skipped {
+        eprintln!("Skipped {:?}: {}", skipped.record, skipped.reason);
+    }
+    for warning in &report.warnings {
+        eprintln!("Warning: {warning}");
+    }
+    println!(
+        "Applied {} records affecting {} rows, skipped {}, filtered {}",
+        report.applied,
+        report.affected,
+        report.skipped.len(),
+        report.filtered
+    );
+    for school_id in &report.new_schools {
+        println!("New school `{school_id}`");
+    }
+    for (school_id, term_id) in &report.new_terms {
+        println!("New term `{term_id}` of `{school_id}`");
+    }
+    if report.ignored_deletes > 0 {
+        println!("Ignored {} delete records", report.ignored_deletes);
+    }
+    if cli.no_commit {
+        println!("Rolled back without committing");
+    }
+    Ok(())
+}
+
+/// The `;` terminated statements of the input with a trailing unterminated one
+fn read_statements(input: impl BufRead) -> Result<Vec<String>, Error> {
+    let mut statements = vec![];
+    let mut statement = String::new();
+    for line in input.lines() {
+        let line = line?;
+        statement.push_str(&line);
+        statement.push('\n');
+        if line.trim_end().ends_with(';') {
+            statements.push(std::mem::take(&mut statement));
+        }
+    }
+    if !statement.trim().is_empty() {
+        statements.push(statement);
+    }
+    Ok(statements)
+}
+
+/// Runs a statement of the shell which only allows SELECT and PRAGMA statements
+fn shell_query(data_store: &mut dyn Datastore, sql: &str) -> Result<QueryRows, Error> {
+    let keyword = sql
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    if !matches!(keyword.as_str(), "SELECT" | "PRAGMA") {
+        return Err(Error::InputParseError {
+            message: format!(
+                "only SELECT and PRAGMA statements can be run but got `{}`",
+                sql.trim()
+            ),
+        });
+    }
+    Ok(data_store.read_only_query(sql)?)
+}
+
+fn shell_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "NULL".to_string(),
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// The rows as a table with a column for each of their columns
+fn format_rows(rows: &QueryRows) -> String {
+    let cells: Vec<Vec<String>> = rows
+        .rows
+        .iter()
+        .map(|row| row.iter().map(shell_value).collect())
+        .collect();
+    let widths: Vec<usize> = rows
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            cells
+                .iter()
+                .map(|row| row[i].chars().count())
+                .chain([column.chars().count()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let line = |values: &[String]| -> String {
+        let padded: Vec<String> = values
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{value:width$}"))
+            .collect();
+        padded.join(" | ").trim_end().to_string()
+    };
+    let mut table = String::new();
+    table.push_str(&line(&rows.columns));
+    table.push('\n');
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    table.push_str(&separator.join("-+-"));
+    table.push('\n');
+    for row in &cells {
+        table.push_str(&line(row));
+        table.push('\n');
+    }
+    table.push_str(&format!("({} rows)\n", rows.rows.len()));
+    table
+}
+
+/// Each bookkeeping table under its name or a JSON object of them
+fn format_bookkeeping(tables: &[(String, QueryRows)], json: bool) -> String {
+    if json {
+        let tables: serde_json::Map<_, _> = tables
+            .iter()
+            .map(|(table, rows)| (table.clone(), rows_json(rows)))
+            .collect();
+        return format!("{}\n", serde_json::Value::from(tables));
+    }
+    tables
+        .iter()
+        .map(|(table, rows)| format!("{table}\n{}", format_rows(rows)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The rows as a JSON array of objects from column to value
+fn rows_json(rows: &QueryRows) -> serde_json::Value {
+    rows.rows
+        .iter()
+        .map(|row| {
+            rows.columns
+                .iter()
+                .cloned()
+                .zip(row.iter().cloned())
+                .collect::<serde_json::Map<_, _>>()
+        })
+        .collect()
+}
+
+/// The error's message followed by each error that caused it when verbose
+fn describe_error(err: &dyn std::error::Error, verbose: bool) -> String {
+    let mut message = err.to_string();
+    if verbose {
+        let mut source = err.source();
+        while let Some(cause) = source {
+            message.push_str(&format!("\n  caused by: {cause}"));
+            source = cause.source();
+        }
+    }
+    message
+}
+
+pub fn add_schools(config: SyncConfig, data_store: &mut dyn Datastore) -> Result<(), Error> {
+    let client = config.build_client()?;
+    let response: Vec<sync_requests::School> = decode_json(
+        client.get(config.get_schools()).send()?,
+        config.max_body_in_memory,
+    )?;
+    data_store.add_schools(response)?;
+    Ok(())
+}
+
+/// Adds the schools of a JSON file returning how many were added and the ids that already existed
+pub fn add_schools_from_file(
+    path: &Path,
+    data_store: &mut dyn Datastore,
+) -> Result<(usize, Vec<String>), Error> {
+    let schools: Vec<sync_requests::School> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let count = schools.len();
+    let existing = data_store.add_schools(schools)?;
+    Ok((count - existing.len(), existing))
+}
+
+/// Adds the terms of a JSON file returning how many were added and the `(school_id, id)` of those
+/// that already existed
+pub fn add_terms_from_file(
+    path: &Path,
+    data_store: &mut dyn Datastore,
+) -> Result<(usize, Vec<(String, String)>), Error> {
+    let terms: Vec<sync_requests::Term> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let count = terms.len();
+    let existing = data_store.add_terms(terms)?;
+    Ok((count - existing.len(), existing))
+}
+
+/// Parses the `--init` resources which are either `all[:tables]` or `select:<sync instructions>`
+pub fn parse_init(input: &str) -> Result<SyncResources, Error> {
+    match input.split_once(':') {
+        _ if input == "all" || input.starts_with("all:") => SyncResources::from_input(input),
+        Some(("select", sync_instructions)) => Ok(SyncResources::Select(
+            SelectSyncOptions::from_input(sync_instructions)?,
+        )),
+        _ => Err(Error::InputParseError {
+            message: format!("`{input}` should be `all` or `select:<sync instructions>`"),
+        }),
+    }
+}
+
+/// Warns that a command's changes are only kept for the sync in the same run when the datastore is
+/// not persistent such as an in memory database
+fn unpersisted_warning(
+    data_store: &mut dyn Datastore,
+    command: &Commands,
+) -> Result<Option<String>, Error> {
+    if data_store.is_persistent()? {
+        return Ok(None);
+    }
+    let name = match command {
+        Commands::Set { .. } => "set",
+        Commands::Unset { .. } => "unset",
+        _ => "this command",
+    };
+    Ok(Some(format!(
+        "WARNING: the datastore is in memory so `{name}` is lost when this run exits, \
+         give it a file with --db-path or SQLITE_DB_PATH to keep it for later runs"
+    )))
+}
+
+/// The outcome of one `doctor` check
+struct DoctorCheck {
+    name: &'static str,
+    passed: bool,
+    /// whether a sync cannot work until the check passes
+    critical: bool,
+    /// how to make the check pass
+    hint: String,
+}
+
+impl DoctorCheck {
+    fn new(name: &'static str, passed: bool, critical: bool, hint: impl Into<String>) -> Self {
+        DoctorCheck {
+            name,
+            passed,
+            critical,
+            hint: hint.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for DoctorCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.passed, self.critical) {
+            (true, _) => write!(f, "[PASS] {}", self.name),
+            (false, true) => write!(f, "[FAIL] {}: {}", self.name, self.hint),
+            (false, false) => write!(f, "[WARN] {}: {}", self.name, self.hint),
+        }
+    }
+}
+
+/// Checks the datastore and the server for the misconfigurations new users run into most
+fn doctor_checks(
+    data_store: &mut dyn Datastore,
+    config: &SyncConfig,
+) -> Result<Vec<DoctorCheck>, Error> {
+    let mut checks = vec![DoctorCheck::new(
+        "persistent datastore",
+        data_store.is_persistent()?,
+        false,
+        "the datastore is in memory so nothing is kept after the run, \
+         give it a file with --db-path or SQLITE_DB_PATH",
+    )];
+    let (version, expected) = (
+        data_store.schema_version()?,
+        data_store.expected_schema_version()?,
+    );
+    checks.push(DoctorCheck::new(
+        "schema migrations",
+        version == expected,
+        true,
+        format!(
+            "the schema is at version {version} but this version of classy-sync expects \
+             {expected}, upgrade classy-sync or sync into a new database"
+        ),
+    ));
+    checks.push(DoctorCheck::new(
+        "datastore integrity",
+        data_store.integrity_check()?,
+        true,
+        "the datastore is corrupt, run `fsck` for details and sync into a new database",
+    ));
+    let mode = data_store.sync_mode()?;
+    checks.push(DoctorCheck::new(
+        "sync strategy",
+        !matches!(mode, SyncMode::Unset | SyncMode::Dirty),
+        true,
+        match mode {
+            SyncMode::Dirty => "the datastore is set up for both all and select syncs, \
+                                sync into a new database"
+                .to_string(),
+            _ => {
+                "nothing is set to sync, run `set <sync instructions>` or `sync --init`".to_string()
+            }
+        },
+    ));
+    // any response means the server can be reached even when it is an error
+    let reachable = config
+        .build_client()?
+        .get(config.get_schools())
+        .timeout(Duration::from_secs(5))
+        .send()
+        .is_ok();
+    checks.push(DoctorCheck::new(
+        "server reachable",
+        reachable,
+        true,
+        format!("could not connect to classy at `{}`", config.uri),
+    ));
+    Ok(checks)
 }
 
-fn main() {
-    dotenv().ok();
-    env_logger::init();
-    let cli = Cli::parse();
-    let mut data_store = replicate_datastore::get_datastore().unwrap();
-    match &cli.command {
-        Some(Commands::Set { sync_instructions }) => {
-            let sync_options = SyncResources::from_input(sync_instructions);
-            data_store.set_request_sync_resources(sync_options).unwrap();
-        }
-        Some(Commands::Unset { sync_instructions }) => {
-            let sync_options = SyncResources::from_input(sync_instructions);
-            data_store
-                .unset_request_sync_resources(sync_options)
-                .unwrap();
-        }
-        Some(Commands::Add(flush_command)) => {
-            match flush_command {
-                AddCommands::Schools => {
-                    add_schools(SyncConfig::default(), &mut *data_store)
-                        .expect("Failed to add schools");
-                }
-                AddCommands::Terms { school_names } => {
-                    let schools: Vec<String> =
-                        school_names.split(",").map(|s| s.to_string()).collect();
-                    todo!();
-                }
+/// Sets the resources to sync for a datastore that has none set yet
+///
+/// A datastore that is already set to the same mode is left as is
+pub fn init_sync_resources(
+    data_store: &mut dyn Datastore,
+    resources: SyncResources,
+) -> Result<(), Error> {
+    match (data_store.sync_mode()?, &resources) {
+        (SyncMode::Unset, _) => {
+            data_store.set_request_sync_resources(resources)?;
+        }
+        (SyncMode::All, SyncResources::Everything(_)) => {}
+        (SyncMode::Select, SyncResources::Select(_)) => {}
+        (mode, _) => {
+            return Err(Error::ConflictingSyncMode {
+                message: format!("datastore is already set up for `{mode}` syncs"),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResponseShape {
+    All,
+    Select,
+}
+
+/// Checks a saved server response deserializes and that each of its records can be safely applied
+/// returning the shape it matched and the index of every invalid record
+pub fn validate_response(text: &str) -> Result<(ResponseShape, Vec<(usize, Error)>), Error> {
+    let (shape, records) = response_records(text)?;
+    let invalid_records = records
+        .iter()
+        .enumerate()
+        .filter_map(|(index, record)| record.verify_record().err().map(|err| (index, err)))
+        .collect();
+    Ok((shape, invalid_records))
+}
+
+/// Prints the shape a saved server response matched and each of its invalid records returning
+/// whether every record is valid
+fn validate_file(file: &Path) -> Result<bool, Error> {
+    let text = fs::read_to_string(file)?;
+    let (shape, invalid_records) = validate_response(&text)?;
+    println!("Matched {shape:?} sync result");
+    for (index, err) in &invalid_records {
+        eprintln!("Invalid record {index}: {err}");
+    }
+    Ok(invalid_records.is_empty())
+}
+
+/// The records of a saved server response of either shape
+fn response_records(
+    text: &str,
+) -> Result<(ResponseShape, Vec<sync_requests::ClassDataSync>), Error> {
+    Ok(
+        match serde_json::from_str::<sync_requests::AllSyncResult>(text) {
+            Ok(result) => (ResponseShape::All, result.sync_data),
+            Err(_) => (
+                ResponseShape::Select,
+                serde_json::from_str::<sync_requests::TermSyncResult>(text)?.sync_data,
+            ),
+        },
+    )
+}
+
+/// Writes the completion script of the shell for every command and option of the cli
+fn write_completions(shell: clap_complete::Shell, out: &mut dyn Write) {
+    clap_complete::generate(shell, &mut Cli::command(), env!("CARGO_BIN_NAME"), out);
+}
+
+/// Writes the sql that would apply a saved server response to stdout or the output file
+#[cfg(feature = "sqlite")]
+fn write_sql_script(text: &str, table_prefix: &str, output: Option<&Path>) -> Result<(), Error> {
+    use classy_sync::data_stores::sqlite::{Sqlite, storage::SqliteConfig};
+    use classy_sync::errors::DataStoreError;
+
+    let (_, records) = response_records(text)?;
+    let config = SqliteConfig {
+        table_prefix: table_prefix.to_string(),
+        ..Default::default()
+    };
+    let script = Sqlite::sql_script(&config, records).map_err(DataStoreError::from)?;
+    match output {
+        Some(path) => fs::write(path, script)?,
+        None => print!("{script}"),
+    }
+    Ok(())
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        skip_all,
+        fields(
+            mode = tracing::field::Empty,
+            applied = tracing::field::Empty,
+            skipped = tracing::field::Empty,
+        )
+    )
+)]
+pub fn sync(config: SyncConfig, data_store: &mut dyn Datastore) -> Result<SyncOutcome, Error> {
+    config.validate()?;
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("mode", tracing::field::display(data_store.sync_mode()?));
+    let atomic = config.atomic;
+    if atomic {
+        data_store.begin_sync()?;
+    }
+    let result = sync_pages(config, data_store);
+    if atomic {
+        data_store.finish_sync(result.is_ok())?;
+    }
+    let outcome = result?;
+    #[cfg(feature = "tracing")]
+    {
+        let span = tracing::Span::current();
+        span.record("applied", outcome.report().applied);
+        span.record("skipped", outcome.report().skipped.len());
+    }
+    Ok(outcome)
+}
+
+fn sync_pages(config: SyncConfig, data_store: &mut dyn Datastore) -> Result<SyncOutcome, Error> {
+    let client = config.build_client()?;
+    let capture = config
+        .capture_dir
+        .as_deref()
+        .map(PageCapture::new)
+        .transpose()?;
+    let capture = capture.as_ref();
+    if config.stream_apply {
+        return sync_streamed(&config, &client, capture, data_store);
+    }
+    let mut report = SyncReport::default();
+    let mut pacer = RequestPacer::new(config.min_request_interval);
+    let mut page_size = PageSizeLimit::default();
+    // each page is applied before the options for the next one are generated so the datastore's
+    // bookkeeping always reflects what has been applied
+    for page in 1.. {
+        debug!("Syncing page {page}");
+        #[cfg(feature = "tracing")]
+        let _page = tracing::info_span!("sync_page", page).entered();
+        pacer.wait();
+        let options = config.target_options(data_store.generate_sync_options()?)?;
+        let fetched = match page_size.clamp(options) {
+            sync_requests::SyncOptions::All(all_sync) => {
+                let response =
+                    fetch_all_page(&client, &config, &all_sync, capture, &mut page_size)?;
+                SyncPage::All(all_sync, response)
+            }
+            sync_requests::SyncOptions::Select(select_sync) => {
+                let response =
+                    fetch_select_page(&client, &config, &select_sync, capture, &mut page_size)?;
+                SyncPage::Select(select_sync, response)
+            }
+        };
+        let has_more = fetched.has_more();
+        let (requested, reached) = (fetched.requested(), fetched.reached());
+        match fetched.apply(&config, &mut report, data_store) {
+            Err(err) if config.continue_on_error => {
+                warn!("Discarding page {page} which failed to apply: {err}");
+                report.warnings.push(Warning::DiscardedPage {
+                    page,
+                    reason: err.to_string(),
+                });
+                return Ok(SyncOutcome::new(requested, report));
+            }
+            result => result?,
+        }
+        if !has_more || config.page_limit == Some(page) {
+            return Ok(SyncOutcome::new(reached, report));
+        }
+    }
+    unreachable!("pages are synced until there are no more")
+}
+
+/// Spaces out the requests of a sync by sleeping off whatever is left of the interval
+struct RequestPacer {
+    interval: Duration,
+    last_request: Option<Instant>,
+}
+
+impl RequestPacer {
+    fn new(interval: Duration) -> RequestPacer {
+        RequestPacer {
+            interval,
+            last_request: None,
+        }
+    }
+
+    fn wait(&mut self) {
+        if let Some(last_request) = self.last_request {
+            let remaining = self.interval.saturating_sub(last_request.elapsed());
+            if !remaining.is_zero() {
+                thread::sleep(remaining);
+            }
+        }
+        self.last_request = Some(Instant::now());
+    }
+}
+
+/// The most records a page is requested with once the server said it returns no more
+#[derive(Debug, Default)]
+struct PageSizeLimit(Option<u16>);
+
+impl PageSizeLimit {
+    /// Lowers the limit to what the `MAX_RECORDS_HEADER` of a response says for the rest of the sync
+    fn observe(&mut self, headers: &HeaderMap) {
+        let Some(max_records) = headers
+            .get(MAX_RECORDS_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u16>().ok())
+            .filter(|max_records| *max_records > 0)
+        else {
+            return;
+        };
+        if self.0.is_none_or(|limit| max_records < limit) {
+            info!("The server returns at most {max_records} records per page, requesting no more");
+            self.0 = Some(max_records);
+        }
+    }
+
+    fn clamp(&self, options: sync_requests::SyncOptions) -> sync_requests::SyncOptions {
+        let Some(limit) = self.0 else {
+            return options;
+        };
+        let clamped = |max_records: Option<u16>| Some(max_records.map_or(limit, |m| m.min(limit)));
+        match options {
+            sync_requests::SyncOptions::All(mut all_sync) => {
+                all_sync.max_records_count = clamped(all_sync.max_records_count);
+                sync_requests::SyncOptions::All(all_sync)
+            }
+            sync_requests::SyncOptions::Select(mut select_sync) => {
+                select_sync.set_max_records(clamped(select_sync.get_max_records()));
+                sync_requests::SyncOptions::Select(select_sync)
+            }
+        }
+    }
+}
+
+fn fetch_all_page(
+    client: &Client,
+    config: &SyncConfig,
+    all_sync: &sync_requests::AllSync,
+    capture: Option<&PageCapture>,
+    page_size: &mut PageSizeLimit,
+) -> Result<sync_requests::AllSyncResult, Error> {
+    let request = client.get(config.get_sync_all()).query(all_sync);
+    let requested = json!(all_sync.last_sync);
+    let result: sync_requests::AllSyncResult = if config.strict_responses {
+        fetch_json::<sync_requests::StrictAllSyncResult>(
+            client, config, request, requested, capture, page_size,
+        )?
+        .into()
+    } else {
+        fetch_json(client, config, request, requested, capture, page_size)?
+    };
+    result.check_progress(all_sync.last_sync)?;
+    Ok(result)
+}
+
+fn fetch_select_page(
+    client: &Client,
+    config: &SyncConfig,
+    select_sync: &sync_requests::SelectSync,
+    capture: Option<&PageCapture>,
+    page_size: &mut PageSizeLimit,
+) -> Result<sync_requests::TermSyncResult, Error> {
+    select_sync.validate()?;
+    let requested = serde_json::to_value(select_sync)?;
+    // the key is made once for the page so a server can tell its retries from new requests
+    let key = config.idempotency_keys.then(idempotency_key).transpose()?;
+    for attempt in 1.. {
+        let mut request = client.post(config.get_sync_select()).json(select_sync);
+        if let Some(key) = &key {
+            request = request.header(IDEMPOTENCY_KEY, key);
+        }
+        let fetched = if config.strict_responses {
+            fetch_json::<sync_requests::StrictTermSyncResult>(
+                client,
+                config,
+                request,
+                requested.clone(),
+                capture,
+                page_size,
+            )
+            .map(Into::into)
+        } else {
+            fetch_json(
+                client,
+                config,
+                request,
+                requested.clone(),
+                capture,
+                page_size,
+            )
+        };
+        match fetched {
+            Err(err) if attempt < SELECT_ATTEMPTS && is_retryable(&err) => {
+                warn!("Retrying select sync page after attempt {attempt} failed: {err}");
+                thread::sleep(config.min_request_interval);
+            }
+            fetched => {
+                let result: sync_requests::TermSyncResult = fetched?;
+                result.check_progress(select_sync)?;
+                return Ok(result);
+            }
+        }
+    }
+    unreachable!("the last attempt is returned")
+}
+
+/// Whether a request failed in a way that sending it again may fix
+fn is_retryable(err: &Error) -> bool {
+    match err {
+        Error::NetworkError(err) => {
+            err.is_timeout()
+                || err.is_connect()
+                || err.status().is_some_and(|status| status.is_server_error())
+        }
+        _ => false,
+    }
+}
+
+/// A random version 4 UUID identifying the request of a single select sync page
+///
+/// Every retry of the page sends the same key. It is not derived from the request since a later
+/// run asking for the same sequences has to get the data the server has by then instead of a
+/// response it cached for the earlier run
+fn idempotency_key() -> Result<String, Error> {
+    let mut bytes = [0; 16];
+    getrandom::fill(&mut bytes).map_err(std::io::Error::other)?;
+    let random = u128::from_be_bytes(bytes);
+    let uuid = random & !(0xf << 76) & !(0b11 << 62) | (0x4 << 76) | (0b10 << 62);
+    let hex = format!("{uuid:032x}");
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+/// Sends a request decoding its JSON response and capturing both when a capture is given
+///
+/// The sequences it was `requested` from are only kept in the audit log
+fn fetch_json<T: DeserializeOwned>(
+    client: &Client,
+    config: &SyncConfig,
+    request: RequestBuilder,
+    requested: serde_json::Value,
+    capture: Option<&PageCapture>,
+    page_size: &mut PageSizeLimit,
+) -> Result<T, Error> {
+    if capture.is_none() && config.audit_log.is_none() {
+        let response = request.send()?.error_for_status()?;
+        page_size.observe(response.headers());
+        return decode_json(response, config.max_body_in_memory);
+    }
+    let request = request.build()?;
+    let page = capture
+        .map(|capture| capture.write_request(&request))
+        .transpose()?;
+    let url = request.url().to_string();
+    let response = client.execute(request)?;
+    // the body of a failed request is captured before the failure is returned
+    let failed = response.error_for_status_ref().err();
+    let status = response.status();
+    let headers = response.headers().clone();
+    page_size.observe(&headers);
+    let content_type = content_type(&response);
+    let mut body = response.text()?;
+    if let (Some(capture), Some(page)) = (capture, page) {
+        capture.write_response(page, status, &headers, &body)?;
+    }
+    if let Some(audit_log) = &config.audit_log {
+        let entry = AuditEntry {
+            fetched_at: Utc::now().to_rfc3339(),
+            url,
+            requested,
+            status: status.as_u16(),
+            body,
+        };
+        append_audit(audit_log, config.audit_max_bytes, &entry)?;
+        body = entry.body;
+    }
+    if let Some(err) = failed {
+        return Err(err.into());
+    }
+    if !is_json(&content_type) {
+        return Err(unexpected_content_type(content_type, &body));
+    }
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// A response kept in the audit log exactly as the server sent it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub fetched_at: String,
+    pub url: String,
+    /// the sequences the request started from, the `last_sync` of an all sync or the select sync
+    /// as it was sent
+    pub requested: serde_json::Value,
+    pub status: u16,
+    /// the body as it was received which has the sequences the page reached
+    pub body: String,
+}
+
+/// Appends a response to the audit log as one line rotating the log first when it would grow past
+/// `max_bytes`
+///
+/// A rotated log is moved to the first `<path>.<n>` that is not taken so none are ever replaced
+fn append_audit(path: &Path, max_bytes: Option<u64>, entry: &AuditEntry) -> Result<(), Error> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    if let Some(max_bytes) = max_bytes
+        && let Ok(metadata) = fs::metadata(path)
+        && metadata.len() > 0
+        && metadata.len() + line.len() as u64 > max_bytes
+    {
+        let rotated = (1..)
+            .map(|n| {
+                let mut rotated = path.as_os_str().to_owned();
+                rotated.push(format!(".{n}"));
+                PathBuf::from(rotated)
+            })
+            .find(|rotated| !rotated.exists())
+            .expect("some rotation is not taken");
+        fs::rename(path, rotated)?;
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Every response kept in an audit log in the order they were received
+pub fn read_audit_log(path: &Path) -> Result<Vec<AuditEntry>, Error> {
+    BufReader::new(fs::File::open(path)?)
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// Decodes a JSON body or reports what the server sent instead such as a proxy's HTML page
+///
+/// Bodies larger than `max_in_memory` bytes are decoded from a temp file
+fn decode_json<T: DeserializeOwned>(
+    response: Response,
+    max_in_memory: Option<usize>,
+) -> Result<T, Error> {
+    let content_type = content_type(&response);
+    if !is_json(&content_type) {
+        return Err(unexpected_content_type(content_type, &response.text()?));
+    }
+    match max_in_memory {
+        Some(max_in_memory) => decode_spilled(response, max_in_memory),
+        None => Ok(response.json()?),
+    }
+}
+
+/// Reads a body into memory until it is larger than `max_in_memory` bytes at which point the
+/// rest of it is written to a temp file after what was read
+fn decode_spilled<T: DeserializeOwned>(
+    mut response: Response,
+    max_in_memory: usize,
+) -> Result<T, Error> {
+    let mut body = Vec::new();
+    (&mut response)
+        .take(max_in_memory as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() <= max_in_memory {
+        return Ok(serde_json::from_slice(&body)?);
+    }
+    let (_spilled, mut file) = SpilledBody::create()?;
+    let mut writer = BufWriter::new(&mut file);
+    writer.write_all(&body)?;
+    drop(body);
+    std::io::copy(&mut response, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    file.seek(SeekFrom::Start(0))?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+/// A temp file holding a response body that is removed once dropped
+struct SpilledBody {
+    path: PathBuf,
+}
+
+impl SpilledBody {
+    /// A new file with a random name in the temp dir opened for reading and writing
+    ///
+    /// The temp dir is shared so the file must not exist yet, which keeps a file or link put at
+    /// the name by someone else from being written through
+    fn create() -> Result<(SpilledBody, fs::File), Error> {
+        let mut random = [0; 8];
+        getrandom::fill(&mut random).map_err(std::io::Error::other)?;
+        let path = std::env::temp_dir().join(format!(
+            "classy-sync-body-{}-{:016x}.json",
+            std::process::id(),
+            u64::from_be_bytes(random)
+        ));
+        debug!("Spilling a response body to {}", path.display());
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok((SpilledBody { path }, file))
+    }
+}
+
+impl Drop for SpilledBody {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn content_type(response: &Response) -> String {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn is_json(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime == "application/json" || mime.ends_with("+json")
+}
+
+fn unexpected_content_type(content_type: String, body: &str) -> Error {
+    Error::UnexpectedContentType {
+        content_type,
+        snippet: body.chars().take(BODY_SNIPPET_CHARS).collect(),
+    }
+}
+
+/// Writes the requests and responses of a sync as numbered JSON files to attach to bug reports
+///
+/// Credentials in the headers are redacted but the bodies are written as they were sent
+struct PageCapture {
+    dir: PathBuf,
+    pages: AtomicUsize,
+}
+
+impl PageCapture {
+    fn new(dir: &Path) -> Result<PageCapture, Error> {
+        fs::create_dir_all(dir)?;
+        Ok(PageCapture {
+            dir: dir.to_path_buf(),
+            pages: AtomicUsize::new(0),
+        })
+    }
+
+    /// Writes `page-<n>-request.json` returning the page number the response should be written with
+    fn write_request(&self, request: &Request) -> Result<usize, Error> {
+        let page = self.pages.fetch_add(1, Ordering::SeqCst) + 1;
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(|bytes| captured_body(&String::from_utf8_lossy(bytes)));
+        self.write(
+            page,
+            "request",
+            json!({
+                "page": page,
+                "timestamp": Utc::now().to_rfc3339(),
+                "method": request.method().as_str(),
+                "url": request.url().as_str(),
+                "headers": captured_headers(request.headers()),
+                "body": body,
+            }),
+        )?;
+        Ok(page)
+    }
+
+    fn write_response(
+        &self,
+        page: usize,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &str,
+    ) -> Result<(), Error> {
+        self.write(
+            page,
+            "response",
+            json!({
+                "page": page,
+                "timestamp": Utc::now().to_rfc3339(),
+                "status": status.as_u16(),
+                "headers": captured_headers(headers),
+                "body": captured_body(body),
+            }),
+        )
+    }
+
+    fn write(&self, page: usize, kind: &str, capture: serde_json::Value) -> Result<(), Error> {
+        let path = self.dir.join(format!("page-{page:04}-{kind}.json"));
+        fs::write(path, serde_json::to_string_pretty(&capture)?)?;
+        Ok(())
+    }
+}
+
+fn captured_headers(headers: &HeaderMap) -> serde_json::Value {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value =
+                if name == AUTHORIZATION || name == PROXY_AUTHORIZATION || value.is_sensitive() {
+                    "<redacted>"
+                } else {
+                    value.to_str().unwrap_or("<not text>")
+                };
+            (name.to_string(), json!(value))
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// The body as JSON when it is JSON so the capture is readable and as text otherwise
+fn captured_body(body: &str) -> serde_json::Value {
+    serde_json::from_str(body).unwrap_or_else(|_| json!(body))
+}
+
+enum SyncPage {
+    All(sync_requests::AllSync, sync_requests::AllSyncResult),
+    Select(sync_requests::SelectSync, sync_requests::TermSyncResult),
+}
+
+impl SyncPage {
+    fn len(&self) -> usize {
+        match self {
+            SyncPage::All(_, response) => response.len(),
+            SyncPage::Select(_, response) => response.len(),
+        }
+    }
+
+    fn has_more(&self) -> bool {
+        match self {
+            SyncPage::All(_, response) => response.has_more,
+            SyncPage::Select(_, response) => response.any_has_more,
+        }
+    }
+
+    /// The options the page was requested with which is where the datastore is without it
+    fn requested(&self) -> sync_requests::SyncOptions {
+        match self {
+            SyncPage::All(all_sync, _) => sync_requests::SyncOptions::All(sync_requests::AllSync {
+                last_sync: all_sync.last_sync,
+                max_records_count: all_sync.max_records_count,
+            }),
+            SyncPage::Select(select_sync, _) => {
+                sync_requests::SyncOptions::Select(select_sync.clone())
+            }
+        }
+    }
+
+    fn reached(&self) -> sync_requests::SyncOptions {
+        match self {
+            SyncPage::All(all_sync, response) => {
+                sync_requests::SyncOptions::All(sync_requests::AllSync {
+                    last_sync: response.new_latest_sync,
+                    max_records_count: all_sync.max_records_count,
+                })
+            }
+            SyncPage::Select(select_sync, response) => {
+                let mut next = select_sync.clone();
+                next.advance(&response.new_sync_term_sequences);
+                sync_requests::SyncOptions::Select(next)
+            }
+        }
+    }
+
+    /// Applies the response once it is checked against the request it answers
+    fn apply(
+        self,
+        config: &SyncConfig,
+        report: &mut SyncReport,
+        data_store: &mut dyn Datastore,
+    ) -> Result<(), Error> {
+        match self {
+            SyncPage::All(all_sync, response) => {
+                config
+                    .on_sequence_skew
+                    .check(response.sequence_skew(all_sync.last_sync), report)?;
+                report.merge(data_store.execute_all_request_sync(response)?);
+            }
+            SyncPage::Select(select_sync, response) => {
+                config
+                    .on_sequence_skew
+                    .check(response.sequence_skew(&select_sync), report)?;
+                config
+                    .on_unrequested_school
+                    .check(response.unrequested_schools(&select_sync), report)?;
+                report.merge(data_store.execute_select_request_sync(select_sync, response)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bounds how many fetched records wait to be applied when streaming
+struct RecordBudget {
+    limit: usize,
+    state: Mutex<BudgetState>,
+    released: Condvar,
+}
+
+struct BudgetState {
+    in_flight: usize,
+    closed: bool,
+}
+
+impl RecordBudget {
+    fn new(limit: usize) -> RecordBudget {
+        RecordBudget {
+            limit,
+            state: Mutex::new(BudgetState {
+                in_flight: 0,
+                closed: false,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until the records fit in the budget returning false once applying has stopped
+    fn reserve(&self, records: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed && state.in_flight > 0 && state.in_flight + records > self.limit {
+            state = self.released.wait(state).unwrap();
+        }
+        state.in_flight += records;
+        !state.closed
+    }
+
+    fn release(&self, records: usize) {
+        self.state.lock().unwrap().in_flight -= records;
+        self.released.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.released.notify_all();
+    }
+}
+
+/// Fetches pages on another thread while the datastore applies the previous ones
+///
+/// The next request is derived from each response instead of the datastore's bookkeeping so the
+/// fetch thread can run ahead. Pages are still applied one at a time in the order they were
+/// fetched and a failed fetch is returned once the pages before it have been applied. Fetching
+/// blocks while `apply_buffer` records are waiting to be applied.
+fn sync_streamed(
+    config: &SyncConfig,
+    client: &Client,
+    capture: Option<&PageCapture>,
+    data_store: &mut dyn Datastore,
+) -> Result<SyncOutcome, Error> {
+    let mut options = config.target_options(data_store.generate_sync_options()?)?;
+    let (sender, receiver) = mpsc::channel::<Result<SyncPage, Error>>();
+    let budget = RecordBudget::new(config.apply_buffer);
+    let budget = &budget;
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let mut pacer = RequestPacer::new(config.min_request_interval);
+            let mut page_size = PageSizeLimit::default();
+            for fetched_pages in 1.. {
+                pacer.wait();
+                let fetched = match page_size.clamp(options) {
+                    sync_requests::SyncOptions::All(all_sync) => {
+                        fetch_all_page(client, config, &all_sync, capture, &mut page_size)
+                            .map(|response| SyncPage::All(all_sync, response))
+                    }
+                    sync_requests::SyncOptions::Select(select_sync) => {
+                        fetch_select_page(client, config, &select_sync, capture, &mut page_size)
+                            .map(|response| SyncPage::Select(select_sync, response))
+                    }
+                }
+                .map(|page| {
+                    let next = page.has_more().then(|| page.reached());
+                    (page, next)
+                });
+                match fetched {
+                    Ok((page, next)) => {
+                        // applying only stops early when it failed
+                        if !budget.reserve(page.len()) || sender.send(Ok(page)).is_err() {
+                            return;
+                        }
+                        match next {
+                            Some(next) if config.page_limit != Some(fetched_pages) => {
+                                options = next
+                            }
+                            _ => return,
+                        }
+                    }
+                    Err(err) => {
+                        let _ = sender.send(Err(err));
+                        return;
+                    }
+                }
+            }
+        });
+
+        let applied = apply_streamed_pages(receiver, budget, config, data_store);
+        // unblocks the fetch thread when applying failed so the scope can finish
+        budget.close();
+        applied
+    })
+}
+
+fn apply_streamed_pages(
+    receiver: mpsc::Receiver<Result<SyncPage, Error>>,
+    budget: &RecordBudget,
+    config: &SyncConfig,
+    data_store: &mut dyn Datastore,
+) -> Result<SyncOutcome, Error> {
+    let mut report = SyncReport::default();
+    let mut reached = None;
+    for (page, fetched) in (1..).zip(receiver) {
+        debug!("Applying streamed page {page}");
+        #[cfg(feature = "tracing")]
+        let _page = tracing::info_span!("sync_page", page).entered();
+        let fetched = fetched?;
+        let records = fetched.len();
+        let requested = fetched.requested();
+        reached = Some(fetched.reached());
+        match fetched.apply(config, &mut report, data_store) {
+            Err(err) if config.continue_on_error => {
+                warn!("Discarding streamed page {page} which failed to apply: {err}");
+                report.warnings.push(Warning::DiscardedPage {
+                    page,
+                    reason: err.to_string(),
+                });
+                return Ok(SyncOutcome::new(requested, report));
+            }
+            result => result?,
+        }
+        budget.release(records);
+    }
+    let reached = reached.expect("the fetch thread sends at least one page or error");
+    Ok(SyncOutcome::new(reached, report))
+}
+
+#[cfg(test)]
+mod sync_tests {
+    use super::*;
+    #[cfg(feature = "sqlite")]
+    use classy_sync::data_stores::sqlite::{Sqlite, storage::SqliteConfig};
+    use classy_sync::data_stores::{
+        replicate_datastore::get_datastore,
+        sync_requests::{
+            AllSyncResult, SchoolEntry, SelectSync, SyncOptions, TableName, TermSyncResult,
+        },
+    };
+    use classy_sync::errors::DataStoreError;
+    use serde_json::from_str;
+
+    fn load_all_sync_data(path: &str) -> String {
+        let updates_text = fs::read_to_string(path).expect("Could not access test json");
+        let _: AllSyncResult = from_str(&updates_text)
+            .expect("Test json is not the correct shape for all sync result");
+        updates_text
+    }
+
+    fn load_select_sync_data(path: &str) -> String {
+        let updates_text = fs::read_to_string(path).expect("Could not access test json");
+        let _: TermSyncResult = from_str(&updates_text)
+            .expect("Test json is not the correct shape for term sync result");
+        updates_text
+    }
+
+    fn last_all_sync_page(sequence: u64) -> String {
+        serde_json::to_string(&AllSyncResult {
+            new_latest_sync: sequence,
+            sync_data: vec![],
+            has_more: false,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn bash_completions_have_the_subcommands() {
+        let mut script = Vec::new();
+        write_completions(clap_complete::Shell::Bash, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        for subcommand in ["sync", "status", "sequences", "shell", "completions"] {
+            assert!(script.contains(subcommand), "no `{subcommand}` in {script}");
+        }
+    }
+
+    #[test]
+    fn validate_fixtures() {
+        let (shape, invalid) =
+            validate_response(&load_all_sync_data("test-syncs/maristfall2024/01.json")).unwrap();
+        assert_eq!(shape, ResponseShape::All);
+        assert!(invalid.is_empty());
+
+        let (shape, invalid) =
+            validate_response(&load_select_sync_data("test-syncs/maristterms/202440.json"))
+                .unwrap();
+        assert_eq!(shape, ResponseShape::Select);
+        assert!(invalid.is_empty());
+
+        let (shape, invalid) = validate_response(
+            &fs::read_to_string("test-syncs/malformed/invalid_column.json").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(shape, ResponseShape::All);
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].0, 1);
+
+        assert!(validate_response(r#"{"sync_data": []}"#).is_err());
+
+        // the command fails with an error instead of panicking on a file it cannot validate
+        assert!(validate_file(Path::new("test-syncs/maristfall2024/01.json")).unwrap());
+        assert!(!validate_file(Path::new("test-syncs/malformed/invalid_column.json")).unwrap());
+        assert!(validate_file(Path::new("test-syncs/missing.json")).is_err());
+        assert!(validate_file(Path::new("Cargo.toml")).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn config_is_validated() {
+        let config = |uri: &str| SyncConfig {
+            uri: uri.to_string(),
+            ..Default::default()
+        };
+        assert!(config("http://localhost:3000").validate().is_ok());
+        assert!(config("https://classy.example.com/").validate().is_ok());
+        for uri in [
+            "localhost:3000",
+            "classy.example.com",
+            "ftp://classy.example.com",
+            "http://localhost:3000?page=1",
+            "http://",
+        ] {
+            assert!(
+                matches!(config(uri).validate(), Err(Error::InvalidConfig { .. })),
+                "{uri} should be invalid"
+            );
+        }
+        let no_pages = SyncConfig {
+            page_limit: Some(0),
+            ..Default::default()
+        };
+        assert!(no_pages.validate().is_err());
+        let audited_spill = SyncConfig {
+            max_body_in_memory: Some(1024),
+            audit_log: Some(PathBuf::from("audit.jsonl")),
+            ..Default::default()
+        };
+        assert!(audited_spill.validate().is_err());
+
+        let mut data_store = get_datastore(DatastoreOptions::default()).unwrap();
+        data_store
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        assert!(matches!(
+            sync(config("localhost:3000"), &mut *data_store),
+            Err(Error::InvalidConfig { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn server_rollback_is_detected() {
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(&mut server, 0, last_all_sync_page(8221));
+        // the server was restored from a backup taken at 6303
+        mock_all_sync_page(&mut server, 8221, last_all_sync_page(6303));
+
+        let mut sqlite_datastore = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let synced = |sqlite_datastore: &mut Sqlite, on_sequence_skew| {
+            sync(
+                SyncConfig {
+                    uri: server.url(),
+                    on_sequence_skew,
+                    ..Default::default()
+                },
+                sqlite_datastore,
+            )
+            .map(SyncOutcome::into_report)
+        };
+        let report = synced(&mut sqlite_datastore, SkewPolicy::Error).unwrap();
+        assert!(report.warnings.is_empty());
+        assert_eq!(sqlite_datastore.last_all_sync_sequence().unwrap(), 8221);
+
+        assert!(matches!(
+            synced(&mut sqlite_datastore, SkewPolicy::Error),
+            Err(Error::SequenceSkew { .. })
+        ));
+        assert_eq!(sqlite_datastore.last_all_sync_sequence().unwrap(), 8221);
+
+        let report = synced(&mut sqlite_datastore, SkewPolicy::Warn).unwrap();
+        assert!(matches!(
+            report.warnings.as_slice(),
+            [Warning::SequenceSkew { .. }]
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sqlite_full_sync() {
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(
+            &mut server,
+            0,
+            load_all_sync_data("test-syncs/maristfall2024/01.json"),
+        );
+        mock_all_sync_page(&mut server, 6303, last_all_sync_page(6303));
+
+        let mut sqlite_datastore = Sqlite::new(SqliteConfig {
+            is_strict: false,
+            ..Default::default()
+        })
+        .expect("Could not get sqlite data store");
+
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        assert_eq!(
+            sqlite_datastore.last_all_sync_sequence().unwrap(),
+            0,
+            "Expected sequence 0"
+        );
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                ..Default::default()
+            },
+            &mut sqlite_datastore,
+        )
+        .expect("Sync failed");
+        assert_eq!(
+            sqlite_datastore.last_all_sync_sequence().unwrap(),
+            6303,
+            "Expected sequence 6303"
+        );
+        assert_eq!(sqlite_datastore.registered_strategies().unwrap(), vec![]);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sync_sends_user_agent() {
+        let mut server = mockito::Server::new();
+        let user_agent = format!("classy-sync/{}", env!("CARGO_PKG_VERSION"));
+        let default_mock = server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::Any)
+            .match_header("user-agent", user_agent.as_str())
+            .with_header("content-type", "application/json")
+            .with_body(last_all_sync_page(0))
+            .create();
+
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed");
+        default_mock.assert();
+
+        let custom_mock = server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::Any)
+            .match_header("user-agent", "my-replica/1.0")
+            .with_header("content-type", "application/json")
+            .with_body(last_all_sync_page(0))
+            .create();
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                user_agent: "my-replica/1.0".to_string(),
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed");
+        custom_mock.assert();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn large_bodies_are_spilled_to_disk() {
+        let mut server = mockito::Server::new();
+        let mut page: AllSyncResult =
+            from_str(&load_all_sync_data("test-syncs/maristfall2024/01.json")).unwrap();
+        page.has_more = false;
+        let record_count = page.len();
+        let body = serde_json::to_string(&page).unwrap();
+        let spilled_files = || {
+            let prefix = format!("classy-sync-body-{}-", std::process::id());
+            fs::read_dir(std::env::temp_dir())
+                .unwrap()
+                .filter(|entry| {
+                    entry
+                        .as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(&prefix)
+                })
+                .count()
+        };
+        let spilled = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let mut synced = vec![];
+        for max_body_in_memory in [None, Some(1024)] {
+            let mock = server
+                .mock("GET", "/sync/all")
+                .match_query(mockito::Matcher::Any)
+                .with_header("content-type", "application/json");
+            let mock = match max_body_in_memory {
+                None => mock.with_body(&body),
+                // the rest of the body is held back until the part already sent is seen in a temp
+                // file
+                Some(_) => {
+                    let body = body.clone();
+                    let spilled = spilled.clone();
+                    mock.with_chunked_body(move |writer| {
+                        let (head, rest) = body.as_bytes().split_at(4096);
+                        writer.write_all(head)?;
+                        let deadline = Instant::now() + Duration::from_secs(10);
+                        while spilled_files() == 0 && Instant::now() < deadline {
+                            thread::sleep(Duration::from_millis(10));
+                        }
+                        spilled.store(spilled_files() > 0, Ordering::Relaxed);
+                        writer.write_all(rest)
+                    })
+                }
+            }
+            .expect(1)
+            .create();
+            let mut sqlite_datastore = get_datastore(DatastoreOptions::default()).unwrap();
+            sqlite_datastore
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            let report = sync(
+                SyncConfig {
+                    uri: server.url(),
+                    max_body_in_memory,
+                    ..Default::default()
+                },
+                &mut *sqlite_datastore,
+            )
+            .expect("Sync failed")
+            .into_report();
+            assert_eq!(report.applied, record_count);
+            mock.assert();
+            mock.remove();
+            synced.push(sqlite_datastore.generate_sync_options().unwrap());
+        }
+        assert!(spilled.load(Ordering::Relaxed));
+        assert_eq!(synced[0], synced[1]);
+        assert_eq!(spilled_files(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn redirects_follow_the_policy() {
+        let mut server = mockito::Server::new();
+        let mut other_host = mockito::Server::new();
+        // the same port on another name is another host to the redirect policy
+        let other_url = other_host.url().replace("127.0.0.1", "localhost");
+        server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::Any)
+            .with_status(301)
+            .with_header("location", "/moved/sync/all")
+            .create();
+        let moved = server
+            .mock("GET", "/moved/sync/all")
+            .with_status(302)
+            .with_header("location", &format!("{other_url}/sync/all"))
+            .create();
+        let redirected = other_host
+            .mock("GET", "/sync/all")
+            .match_header("authorization", mockito::Matcher::Missing)
+            .with_header("content-type", "application/json")
+            .with_body(last_all_sync_page(0))
+            .create();
+
+        let synced = |redirects: RedirectPolicy, client: Option<Client>| {
+            let mut sqlite_datastore = Sqlite::new(SqliteConfig::default()).unwrap();
+            sqlite_datastore
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            sync(
+                SyncConfig {
+                    uri: server.url(),
+                    redirects,
+                    client,
+                    ..Default::default()
+                },
+                &mut sqlite_datastore,
+            )
+        };
+        for redirects in [RedirectPolicy::None, RedirectPolicy::SameHost] {
+            let Err(Error::NetworkError(err)) = synced(redirects, None) else {
+                panic!("expected {redirects} to fail on the redirect");
+            };
+            assert!(err.is_redirect());
+        }
+        // only the same host redirect was followed before the one to another host failed
+        moved.expect(1).assert();
+        assert!(!redirected.matched());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer secret-token".parse().unwrap());
+        let client = Client::builder()
+            .default_headers(headers)
+            .redirect(RedirectPolicy::Follow.policy())
+            .build()
+            .unwrap();
+        synced(RedirectPolicy::Follow, Some(client)).expect("Sync failed");
+        redirected.assert();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sync_with_injected_client() {
+        let mut server = mockito::Server::new();
+        let with_header = server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::Any)
+            .match_header("x-replica", "audit")
+            .with_header("content-type", "application/json")
+            .with_body(last_all_sync_page(0))
+            .create();
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-replica", "audit".parse().unwrap());
+        let client = Client::builder().default_headers(headers).build().unwrap();
+
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                client: Some(client),
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed");
+        with_header.assert();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sync_requests_are_rate_limited() {
+        let mut server = mockito::Server::new();
+        let requested_at = std::sync::Arc::new(Mutex::new(Vec::new()));
+        // pages of at most one record are applied right away so the gap between the requests is
+        // the pacing
+        let mut mock_page = |last_sync: u64, body: String| {
+            let requested_at = requested_at.clone();
+            server
+                .mock("GET", "/sync/all")
+                .match_query(mockito::Matcher::UrlEncoded(
+                    "last_sync".to_string(),
+                    last_sync.to_string(),
+                ))
+                .with_header("content-type", "application/json")
+                .with_body_from_request(move |_| {
+                    requested_at.lock().unwrap().push(Instant::now());
+                    body.clone().into_bytes()
+                })
+                .expect(1)
+                .create()
+        };
+        let first_page = mock_page(
+            0,
+            r#"{
+                "new_latest_sync": 10,
+                "has_more": true,
+                "sync_data": [
+                  {
+                    "table_name": "schools",
+                    "sync_action": "insert",
+                    "pk_fields": { "id": "vassar" },
+                    "relevant_fields": { "name": "Vassar" }
+                  }
+                ]
+            }"#
+            .to_string(),
+        );
+        let last_page = mock_page(10, last_all_sync_page(10));
+
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let interval = Duration::from_millis(300);
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                min_request_interval: interval,
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed");
+        first_page.assert();
+        last_page.assert();
+        let requested_at = requested_at.lock().unwrap();
+        let [first, second] = requested_at.as_slice() else {
+            panic!("expected two requests but got {}", requested_at.len());
+        };
+        // the first request reaches the server a little after it was sent since it also opens the
+        // connection, which is far less than the pages would take without pacing
+        let slack = Duration::from_millis(20);
+        assert!(
+            second.duration_since(*first) >= interval - slack,
+            "the second request came {:?} after the first",
+            second.duration_since(*first)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sync_keeps_responses_in_the_audit_log() {
+        let audit_dir =
+            std::env::temp_dir().join(format!("classy-sync-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&audit_dir);
+        fs::create_dir_all(&audit_dir).unwrap();
+        let audit_log = audit_dir.join("audit.jsonl");
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(&mut server, 0, last_all_sync_page(42));
+        mock_all_sync_page(&mut server, 42, last_all_sync_page(42));
+
+        let mut sqlite_datastore = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let config = |audit_max_bytes| SyncConfig {
+            uri: server.url(),
+            audit_log: Some(audit_log.clone()),
+            audit_max_bytes,
+            ..Default::default()
+        };
+        sync(config(None), &mut sqlite_datastore).expect("Sync failed");
+
+        let entries = read_audit_log(&audit_log).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].status, 200);
+        assert!(entries[0].url.contains("last_sync=0"));
+        assert_eq!(entries[0].requested, json!(0));
+        assert_eq!(entries[0].body, last_all_sync_page(42));
+        assert!(chrono::DateTime::parse_from_rfc3339(&entries[0].fetched_at).is_ok());
+
+        // the log is rotated instead of growing past the limit
+        sync(config(Some(1)), &mut sqlite_datastore).expect("Sync failed");
+        let entries = read_audit_log(&audit_log).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].requested, json!(42));
+        let rotated = read_audit_log(&audit_dir.join("audit.jsonl.1")).unwrap();
+        assert_eq!(rotated[0].requested, json!(0));
+
+        // earlier rotations are kept rather than replaced
+        sync(config(Some(1)), &mut sqlite_datastore).expect("Sync failed");
+        let rotated = read_audit_log(&audit_dir.join("audit.jsonl.1")).unwrap();
+        assert_eq!(rotated[0].requested, json!(0));
+        let rotated = read_audit_log(&audit_dir.join("audit.jsonl.2")).unwrap();
+        assert_eq!(rotated[0].requested, json!(42));
+        assert_eq!(read_audit_log(&audit_log).unwrap().len(), 1);
+        let _ = fs::remove_dir_all(&audit_dir);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sync_captures_pages() {
+        let capture_dir =
+            std::env::temp_dir().join(format!("classy-sync-capture-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&capture_dir);
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(&mut server, 0, last_all_sync_page(42));
+
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                capture_dir: Some(capture_dir.clone()),
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed");
+
+        let read = |name: &str| -> serde_json::Value {
+            from_str(&fs::read_to_string(capture_dir.join(name)).unwrap()).unwrap()
+        };
+        assert_eq!(fs::read_dir(&capture_dir).unwrap().count(), 2);
+        let request = read("page-0001-request.json");
+        assert_eq!(request["page"], 1);
+        assert_eq!(request["method"], "GET");
+        assert!(request["url"].as_str().unwrap().contains("last_sync=0"));
+        assert!(request["timestamp"].is_string());
+        let response = read("page-0001-response.json");
+        assert_eq!(response["page"], 1);
+        assert_eq!(response["status"], 200);
+        assert_eq!(response["body"]["new_latest_sync"], 42);
+
+        // credentials are never written
+        let capture = PageCapture::new(&capture_dir).unwrap();
+        let request = Client::new()
+            .get(server.url())
+            .bearer_auth("secret-token")
+            .build()
+            .unwrap();
+        capture.write_request(&request).unwrap();
+        let request = read("page-0001-request.json");
+        assert_eq!(request["headers"]["authorization"], "<redacted>");
+        let _ = fs::remove_dir_all(&capture_dir);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sync_with_base_path() {
+        let mut server = mockito::Server::new();
+        let prefixed = server
+            .mock("GET", "/api/v1/sync/all")
+            .match_query(mockito::Matcher::Any)
+            .with_header("content-type", "application/json")
+            .with_body(last_all_sync_page(0))
+            .create();
+
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                base_path: "/api/v1/".to_string(),
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed");
+        prefixed.assert();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sqlite_init_then_sync() {
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(&mut server, 0, last_all_sync_page(0));
+
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        assert!(sync(SyncConfig::default(), &mut *sqlite_datastore).is_err());
+
+        init_sync_resources(&mut *sqlite_datastore, parse_init("all").unwrap()).unwrap();
+        assert_eq!(sqlite_datastore.sync_mode().unwrap(), SyncMode::All);
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed");
+
+        // initializing again with the same mode is fine but not with a conflicting one
+        init_sync_resources(&mut *sqlite_datastore, parse_init("all").unwrap()).unwrap();
+        let conflicting = parse_init("select:marist,202440").unwrap();
+        assert!(matches!(
+            init_sync_resources(&mut *sqlite_datastore, conflicting),
+            Err(Error::ConflictingSyncMode { .. })
+        ));
+        assert!(parse_init("some").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn verbose_errors_print_sources() {
+        let sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        let sqlite_err = sqlite
+            .raw_query_scalar::<u64>("SELECT missing_column FROM schools")
+            .unwrap_err();
+        let err = Error::from(DataStoreError::from(sqlite_err));
+
+        assert_eq!(describe_error(&err, false), err.to_string());
+        let verbose = describe_error(&err, true);
+        let causes: Vec<&str> = verbose.lines().skip(1).collect();
+        assert!(
+            causes
+                .iter()
+                .all(|cause| cause.starts_with("  caused by: "))
+        );
+        // the rusqlite error the sqlite query failed with is reached through the chain
+        assert!(
+            causes
+                .iter()
+                .any(|cause| cause.starts_with("  caused by: no such column: missing_column"))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn in_memory_set_warns() {
+        let set = Commands::Set {
+            sync_instructions: "marist".to_string(),
+        };
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        let warning = unpersisted_warning(&mut sqlite, &set).unwrap().unwrap();
+        assert!(warning.contains("`set` is lost"));
+
+        let db_path = std::env::temp_dir().join(format!(
+            "classy-sync-persisted-{}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&db_path);
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            db_path: Some(db_path.to_string_lossy().into_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(unpersisted_warning(&mut sqlite, &set).unwrap().is_none());
+        drop(sqlite);
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sequences_reflect_a_sync() {
+        let mut server = mockito::Server::new();
+        server
+            .mock("POST", "/sync/schools")
+            .with_header("content-type", "application/json")
+            .with_body(load_select_sync_data("test-syncs/maristterms/202440.json"))
+            .create();
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            table_prefix: "classy_".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        let before = sqlite.bookkeeping().unwrap();
+        assert_eq!(before.len(), 4);
+        assert!(before[2].1.rows.is_empty());
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                ..Default::default()
+            },
+            &mut sqlite,
+        )
+        .expect("Sync failed");
+
+        let tables = sqlite.bookkeeping().unwrap();
+        let json =
+            serde_json::from_str::<serde_json::Value>(&format_bookkeeping(&tables, true)).unwrap();
+        assert_eq!(json["_previous_all_collections"], json!([]));
+        assert_eq!(json["_previous_school_collections"], json!([]));
+        let terms = &json["_previous_term_collections"];
+        assert_eq!(terms.as_array().unwrap().len(), 1);
+        assert_eq!(terms[0]["school_id"], "marist");
+        assert_eq!(terms[0]["term_collection_id"], "202440");
+        assert_eq!(terms[0]["synced_at"], 6929);
+        assert_eq!(
+            json["_school_strategies"],
+            json!([{ "school_id": "marist", "term_collection_id": "202440" }])
+        );
+        let table = format_bookkeeping(&tables, false);
+        assert!(table.starts_with("_previous_all_collections\nsynced_at | created_at\n"));
+        assert!(table.contains("_school_strategies\nschool_id | term_collection_id\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn shell_runs_read_only_queries() {
+        let db_path =
+            std::env::temp_dir().join(format!("classy-sync-shell-{}.sqlite", std::process::id()));
+        let _ = fs::remove_file(&db_path);
+        let mut sqlite = Sqlite::new(SqliteConfig {
+            db_path: Some(db_path.to_string_lossy().into_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let result: AllSyncResult =
+            from_str(&load_all_sync_data("test-syncs/maristfall2024/01.json")).unwrap();
+        sqlite.execute_all_request_sync(result).unwrap();
+        let schools = sqlite
+            .read_only_query("SELECT COUNT(*) FROM schools;")
+            .unwrap()
+            .rows[0][0]
+            .as_i64()
+            .unwrap();
+        assert!(schools > 0);
+
+        let statements =
+            read_statements("SELECT COUNT(*) AS n\nFROM schools;\nPRAGMA user_version".as_bytes())
+                .unwrap();
+        assert_eq!(statements.len(), 2);
+        let rows = shell_query(&mut sqlite, &statements[0]).unwrap();
+        assert_eq!(rows.columns, vec!["n"]);
+        assert_eq!(rows.rows, vec![vec![json!(schools)]]);
+        assert_eq!(
+            format_rows(&rows),
+            format!(
+                "n\n{}\n{schools}\n(1 rows)\n",
+                "-".repeat(schools.to_string().len().max(1))
+            )
+        );
+        assert_eq!(rows_json(&rows), json!([{ "n": schools }]));
+
+        assert!(matches!(
+            shell_query(&mut sqlite, "DELETE FROM schools;"),
+            Err(Error::InputParseError { .. })
+        ));
+        // the keyword check is not all that keeps the connection from writing
+        assert!(shell_query(&mut sqlite, "PRAGMA user_version = 3;").is_err());
+        assert_eq!(sqlite.schema_version().unwrap(), 6);
+        drop(sqlite);
+        fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn doctor_finds_misconfigurations() {
+        let server = mockito::Server::new();
+        let config = SyncConfig {
+            uri: server.url(),
+            ..Default::default()
+        };
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        let failed = |checks: &[DoctorCheck]| -> Vec<(&str, bool)> {
+            checks
+                .iter()
+                .filter(|check| !check.passed)
+                .map(|check| (check.name, check.critical))
+                .collect()
+        };
+
+        let checks = doctor_checks(&mut sqlite, &config).unwrap();
+        assert_eq!(
+            failed(&checks),
+            vec![("persistent datastore", false), ("sync strategy", true)]
+        );
+        assert!(
+            checks[0]
+                .to_string()
+                .starts_with("[WARN] persistent datastore")
+        );
+        assert!(checks[3].to_string().contains("sync --init"));
+
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let checks = doctor_checks(&mut sqlite, &config).unwrap();
+        assert_eq!(failed(&checks), vec![("persistent datastore", false)]);
+
+        let unreachable = SyncConfig {
+            uri: "http://127.0.0.1:1".to_string(),
+            ..Default::default()
+        };
+        let checks = doctor_checks(&mut sqlite, &unreachable).unwrap();
+        assert!(failed(&checks).contains(&("server reachable", true)));
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn seed_schools_and_terms_from_files() {
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        let schools = Path::new("test-syncs/seed/schools.json");
+        let terms = Path::new("test-syncs/seed/terms.json");
+        assert_eq!(
+            add_schools_from_file(schools, &mut sqlite).unwrap(),
+            (2, vec![])
+        );
+        assert_eq!(
+            add_terms_from_file(terms, &mut sqlite).unwrap(),
+            (3, vec![])
+        );
+        let school_count: u64 = sqlite
+            .raw_query_scalar("SELECT COUNT(*) FROM schools")
+            .unwrap();
+        let term_count: u64 = sqlite
+            .raw_query_scalar("SELECT COUNT(*) FROM term_collections")
+            .unwrap();
+        assert_eq!((school_count, term_count), (2, 3));
+
+        // seeding again is a no op reporting what already existed
+        let (added, mut existing) = add_schools_from_file(schools, &mut sqlite).unwrap();
+        existing.sort();
+        assert_eq!(
+            (added, existing),
+            (0, vec!["marist".to_string(), "temple".to_string()])
+        );
+        let (added, existing) = add_terms_from_file(terms, &mut sqlite).unwrap();
+        assert_eq!((added, existing.len()), (0, 3));
+
+        // schools are not terms
+        assert!(matches!(
+            add_terms_from_file(schools, &mut sqlite),
+            Err(Error::JsonParseError(_))
+        ));
+        assert!(matches!(
+            add_schools_from_file(Path::new("test-syncs/seed/missing.json"), &mut sqlite),
+            Err(Error::FileError(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sync_rejects_non_json_response() {
+        let mut server = mockito::Server::new();
+        let login_page = format!("<html><body>{}</body></html>", "Sign in ".repeat(100));
+        server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::Any)
+            .with_header("content-type", "text/html; charset=utf-8")
+            .with_body(&login_page)
+            .create();
+
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let err = sync(
+            SyncConfig {
+                uri: server.url(),
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .unwrap_err();
+        match err {
+            Error::UnexpectedContentType {
+                content_type,
+                snippet,
+            } => {
+                assert_eq!(content_type, "text/html; charset=utf-8");
+                assert_eq!(snippet.chars().count(), BODY_SNIPPET_CHARS);
+                assert!(login_page.starts_with(&snippet));
             }
-            return;
+            err => panic!("expected an unexpected content type error, got {err}"),
         }
-        None => {}
     }
 
-    sync(SyncConfig::default(), &mut *data_store).expect("Failed to sync");
-}
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sqlite_resume_interrupted_sync() {
+        let mut server = mockito::Server::new();
+        let first_page = server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "last_sync".to_string(),
+                "0".to_string(),
+            ))
+            .with_header("content-type", "application/json")
+            .with_body(load_all_sync_data("test-syncs/maristfall2024/01.json"))
+            .expect(1)
+            .create();
+        // the process "crashes" while fetching the second page
+        let failed_page = server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "last_sync".to_string(),
+                "6303".to_string(),
+            ))
+            .with_status(500)
+            .expect(1)
+            .create();
 
-pub fn add_schools(config: SyncConfig, data_store: &mut dyn Datastore) -> Result<(), Error> {
-    let client = Client::new();
-    let response: Vec<sync_requests::School> = client.get(config.get_schools()).send()?.json()?;
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let uri = server.url();
+        let config = || SyncConfig {
+            uri: uri.clone(),
+            ..Default::default()
+        };
+        assert!(sync(config(), &mut *sqlite_datastore).is_err());
+        // the bookkeeping committed with the first page is where the next run starts from
+        let SyncOptions::All(next) = sqlite_datastore.generate_sync_options().unwrap() else {
+            panic!("expected all sync options");
+        };
+        assert_eq!(next.last_sync, 6303);
+        failed_page.assert();
+        failed_page.remove();
 
-    Ok(())
-}
+        let resumed_page = server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "last_sync".to_string(),
+                "6303".to_string(),
+            ))
+            .with_header("content-type", "application/json")
+            .with_body(last_all_sync_page(6303))
+            .expect(1)
+            .create();
+        sync(config(), &mut *sqlite_datastore).expect("Sync failed");
+        first_page.assert();
+        resumed_page.assert();
+    }
 
-pub fn sync(config: SyncConfig, data_store: &mut dyn Datastore) -> Result<(), Error> {
-    let client = Client::new();
-    let request_options = data_store.generate_sync_options().unwrap();
-    match request_options {
-        sync_requests::SyncOptions::All(all_sync) => {
-            let response: sync_requests::AllSyncResult = client
-                .get(config.get_sync_all())
-                .query(&all_sync)
-                .send()?
-                .json()?;
-            data_store.execute_all_request_sync(response)?;
-        }
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn failed_pages_are_discarded_under_continue_on_error() {
+        let mut failing_page: AllSyncResult = from_str(&load_all_sync_data(
+            "test-syncs/malformed/unknown_table.json",
+        ))
+        .unwrap();
+        failing_page.has_more = true;
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(
+            &mut server,
+            0,
+            load_all_sync_data("test-syncs/maristfall2024/01.json"),
+        );
+        mock_all_sync_page(
+            &mut server,
+            6303,
+            serde_json::to_string(&failing_page).unwrap(),
+        );
+        let url = server.url();
+        let sync_with = |continue_on_error, stream_apply| {
+            let mut sqlite_datastore = Sqlite::new(SqliteConfig {
+                strict_tables: true,
+                ..Default::default()
+            })
+            .unwrap();
+            sqlite_datastore
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            let outcome = sync(
+                SyncConfig {
+                    uri: url.clone(),
+                    atomic: true,
+                    continue_on_error,
+                    stream_apply,
+                    ..Default::default()
+                },
+                &mut sqlite_datastore,
+            );
+            let schools: u64 = sqlite_datastore
+                .raw_query_scalar("SELECT COUNT(*) FROM schools")
+                .unwrap();
+            (outcome, schools)
+        };
 
-        sync_requests::SyncOptions::Select(select_sync) => {
-            let response: sync_requests::TermSyncResult = client
-                .post(config.get_sync_select())
-                .json(&select_sync)
-                .send()?
-                .json()?;
-            data_store.execute_select_request_sync(select_sync, response)?;
+        // streamed pages are discarded the same way
+        for stream_apply in [false, true] {
+            let (outcome, schools) = sync_with(true, stream_apply);
+            let outcome = outcome.expect("Sync failed");
+            let SyncOutcome::All { new_sequence, .. } = &outcome else {
+                panic!("expected an all sync outcome but got {outcome:?}");
+            };
+            assert_eq!(*new_sequence, 6303);
+            let [Warning::DiscardedPage { page, .. }] = outcome.report().warnings.as_slice() else {
+                panic!("expected a discarded page but got {:?}", outcome.report());
+            };
+            assert_eq!(*page, 2);
+            // the first page is kept without the discarded one
+            assert_eq!(schools, 1);
+
+            let (outcome, schools) = sync_with(false, stream_apply);
+            assert!(outcome.is_err());
+            assert_eq!(schools, 0);
         }
     }
-    Ok(())
-}
 
-#[cfg(test)]
-mod sync_tests {
-    use std::fs;
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn unknown_tables_are_skipped_unless_strict() {
+        let page = load_all_sync_data("test-syncs/malformed/unknown_table.json");
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(&mut server, 0, page);
+        let sync_with = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["classy-sync"].iter().chain(args)).unwrap();
+            let mut data_store = get_datastore(DatastoreOptions {
+                strict_tables: cli.strict_tables,
+                ..Default::default()
+            })
+            .unwrap();
+            data_store
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            sync(
+                SyncConfig {
+                    uri: server.url(),
+                    ..Default::default()
+                },
+                &mut *data_store,
+            )
+        };
 
-    use super::*;
-    use classy_sync::data_stores::{
-        replicate_datastore::get_datastore,
-        sync_requests::{AllSyncResult, SelectSync, SyncOptions, TermSyncResult},
-    };
-    use serde_json::from_str;
+        let outcome = sync_with(&["sync"]).expect("Sync failed");
+        assert_eq!(
+            outcome.report().warnings,
+            vec![Warning::UnknownTable {
+                table: "buildings".to_string()
+            }]
+        );
+        let err = sync_with(&["--strict-tables", "sync"]).unwrap_err();
+        assert!(err.to_string().contains("`buildings`"), "{err}");
+    }
 
-    fn load_all_sync_data(path: &str) -> String {
-        let updates_text = fs::read_to_string(path).expect("Could not access test json");
-        let _: AllSyncResult = from_str(&updates_text)
-            .expect("Test json is not the correct shape for all sync result");
-        updates_text
+    #[test]
+    fn resync_table_rejects_unknown_tables() {
+        for table in ["unknown", "buildings", "Sections"] {
+            assert!(matches!(
+                parse_table(table),
+                Err(Error::InputParseError { .. })
+            ));
+        }
+        assert_eq!(parse_table("sections").unwrap(), TableName::Sections);
+        assert!(SyncResources::from_input("all:unknown").is_err());
     }
 
-    fn load_select_sync_data(path: &str) -> String {
-        let updates_text = fs::read_to_string(path).expect("Could not access test json");
-        let _: TermSyncResult = from_str(&updates_text)
-            .expect("Test json is not the correct shape for term sync result");
-        updates_text
+    fn mock_all_sync_page(
+        server: &mut mockito::Server,
+        last_sync: u64,
+        body: String,
+    ) -> mockito::Mock {
+        server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "last_sync".to_string(),
+                last_sync.to_string(),
+            ))
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .create()
     }
 
     #[test]
     #[cfg(feature = "sqlite")]
-    fn sqlite_full_sync() {
+    fn server_page_size_limits_later_pages() {
         let mut server = mockito::Server::new();
-
-        let updates_text = load_all_sync_data("test-syncs/maristfall2024/01.json");
+        let first_page = load_all_sync_data("test-syncs/maristfall2024/01.json");
+        let first_sequence = from_str::<AllSyncResult>(&first_page)
+            .unwrap()
+            .new_latest_sync;
         server
             .mock("GET", "/sync/all")
             .match_query(mockito::Matcher::UrlEncoded(
@@ -179,25 +3045,162 @@ mod sync_tests {
                 "0".to_string(),
             ))
             .with_header("content-type", "application/json")
-            .with_body(updates_text)
+            .with_header(MAX_RECORDS_HEADER, "500")
+            .with_body(first_page)
+            .create();
+        let limited = server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("last_sync".to_string(), first_sequence.to_string()),
+                mockito::Matcher::UrlEncoded("max_records_count".to_string(), "500".to_string()),
+            ]))
+            .with_header("content-type", "application/json")
+            .with_body(last_all_sync_page(first_sequence))
+            .expect(2)
             .create();
 
-        let mut sqlite_datastore = get_datastore().expect("Could not get sqlite data store");
+        for stream_apply in [false, true] {
+            let mut sqlite_datastore = Sqlite::new(SqliteConfig::default()).unwrap();
+            sqlite_datastore
+                .set_request_sync_resources(SyncResources::Everything(None))
+                .unwrap();
+            sync(
+                SyncConfig {
+                    uri: server.url(),
+                    stream_apply,
+                    ..Default::default()
+                },
+                &mut sqlite_datastore,
+            )
+            .expect("Sync failed");
+            assert_eq!(
+                sqlite_datastore.last_all_sync_sequence().unwrap(),
+                first_sequence
+            );
+        }
+        limited.assert();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sqlite_streamed_sync() {
+        let mut server = mockito::Server::new();
+        let mut last_sync = 0;
+        let mut record_count = 0;
+        for page in ["01", "02", "03", "04", "05"] {
+            let body = load_all_sync_data(&format!("test-syncs/maristfall2024/{page}.json"));
+            let response: AllSyncResult = from_str(&body).unwrap();
+            mock_all_sync_page(&mut server, last_sync, body);
+            last_sync = response.new_latest_sync;
+            record_count += response.len();
+        }
+        mock_all_sync_page(&mut server, last_sync, last_all_sync_page(last_sync));
 
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
         sqlite_datastore
-            .set_request_sync_resources(SyncResources::Everything)
+            .set_request_sync_resources(SyncResources::Everything(None))
             .unwrap();
+        let report = sync(
+            SyncConfig {
+                uri: server.url(),
+                stream_apply: true,
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed")
+        .into_report();
+        assert_eq!(report.applied, record_count);
         match sqlite_datastore.generate_sync_options().unwrap() {
-            SyncOptions::All(all_sync) => {
-                assert_eq!(all_sync.last_sync, 0, "Expected sequence 0");
-            }
+            SyncOptions::All(all_sync) => assert_eq!(all_sync.last_sync, last_sync),
             SyncOptions::Select(_) => panic!("Expected all sync"),
         }
-        sync(SyncConfig { uri: server.url() }, &mut *sqlite_datastore).expect("Sync failed");
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sqlite_streamed_sync_small_buffer() {
+        let mut server = mockito::Server::new();
+        let mut last_sync = 0;
+        let mut record_count = 0;
+        let mut pages = vec![];
+        for page in ["01", "02", "03", "04", "05"] {
+            let body = load_all_sync_data(&format!("test-syncs/maristfall2024/{page}.json"));
+            let response: AllSyncResult = from_str(&body).unwrap();
+            pages.push(mock_all_sync_page(&mut server, last_sync, body));
+            last_sync = response.new_latest_sync;
+            record_count += response.len();
+        }
+        pages.push(mock_all_sync_page(
+            &mut server,
+            last_sync,
+            last_all_sync_page(last_sync),
+        ));
+
+        let mut sqlite = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let report = sync(
+            SyncConfig {
+                uri: server.url(),
+                stream_apply: true,
+                apply_buffer: 1,
+                ..Default::default()
+            },
+            &mut sqlite,
+        )
+        .expect("Sync failed")
+        .into_report();
+        assert_eq!(report.applied, record_count);
+        // each page is requested once with the sequence of the one applied before it
+        for page in &pages {
+            page.assert();
+        }
+        assert_eq!(sqlite.last_all_sync_sequence().unwrap(), last_sync);
+        // only the latest sequence is kept however many pages were applied
+        let kept_sequences: String = sqlite
+            .raw_query_scalar("SELECT group_concat(synced_at) FROM _previous_all_collections")
+            .unwrap();
+        assert_eq!(kept_sequences, last_sync.to_string());
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn sqlite_streamed_sync_fetch_error() {
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(
+            &mut server,
+            0,
+            load_all_sync_data("test-syncs/maristfall2024/01.json"),
+        );
+        server
+            .mock("GET", "/sync/all")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "last_sync".to_string(),
+                "6303".to_string(),
+            ))
+            .with_status(500)
+            .create();
+
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let result = sync(
+            SyncConfig {
+                uri: server.url(),
+                stream_apply: true,
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        );
+        assert!(matches!(result, Err(Error::NetworkError(_))));
+        // the page before the failure was still applied
         match sqlite_datastore.generate_sync_options().unwrap() {
-            SyncOptions::All(all_sync) => {
-                assert_eq!(all_sync.last_sync, 6303, "Expected sequence 6303")
-            }
+            SyncOptions::All(all_sync) => assert_eq!(all_sync.last_sync, 6303),
             SyncOptions::Select(_) => panic!("Expected all sync"),
         }
     }
@@ -214,7 +3217,9 @@ mod sync_tests {
 
         server
             .mock("POST", "/sync/schools")
-            .match_body(serde_json::to_string(&select_sync).unwrap().as_str())
+            .match_body(mockito::Matcher::Json(
+                serde_json::to_value(&select_sync).unwrap(),
+            ))
             .with_header("content-type", "application/json")
             .with_body(load_select_sync_data("test-syncs/maristterms/202440.json"))
             .create();
@@ -230,15 +3235,18 @@ mod sync_tests {
 
         server
             .mock("POST", "/sync/schools")
-            .match_body(serde_json::to_string(&select_sync).unwrap().as_str())
+            .match_body(mockito::Matcher::Json(
+                serde_json::to_value(&select_sync).unwrap(),
+            ))
             .with_header("content-type", "application/json")
             .with_body(load_select_sync_data("test-syncs/maristterms/202540.json"))
             .create();
 
-        let mut sqlite_datastore = get_datastore().expect("Could not get sqlite data store");
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
 
         sqlite_datastore
-            .set_request_sync_resources(SyncResources::from_input("marist,202440"))
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
             .unwrap();
         let expected_sync_options: SelectSync = serde_json::from_str(
             r#"
@@ -262,9 +3270,16 @@ mod sync_tests {
                 assert_eq!(options, expected_sync_options)
             }
         }
-        sync(SyncConfig { uri: server.url() }, &mut *sqlite_datastore).expect("Sync failed");
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed");
         sqlite_datastore
-            .set_request_sync_resources(SyncResources::from_input("marist,202540"))
+            .set_request_sync_resources(SyncResources::from_input("marist,202540").unwrap())
             .unwrap();
 
         let expected_sync_options: SelectSync = serde_json::from_str(
@@ -290,6 +3305,391 @@ mod sync_tests {
                 assert_eq!(options, expected_sync_options)
             }
         }
-        sync(SyncConfig { uri: server.url() }, &mut *sqlite_datastore).expect("Sync failed");
+        sync(
+            SyncConfig {
+                uri: server.url(),
+                ..Default::default()
+            },
+            &mut *sqlite_datastore,
+        )
+        .expect("Sync failed");
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn outcome_has_the_reached_sequences() {
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(&mut server, 0, last_all_sync_page(6303));
+        server
+            .mock("POST", "/sync/schools")
+            .with_header("content-type", "application/json")
+            .with_body(load_select_sync_data("test-syncs/maristterms/202440.json"))
+            .create();
+        let mut all_datastore = Sqlite::new(SqliteConfig::default()).unwrap();
+        all_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+        let config = || SyncConfig {
+            uri: server.url(),
+            ..Default::default()
+        };
+        match sync(config(), &mut all_datastore).expect("Sync failed") {
+            SyncOutcome::All {
+                new_sequence,
+                report,
+            } => {
+                assert_eq!(new_sequence, 6303);
+                assert_eq!(report.applied, 0);
+            }
+            outcome => panic!("Expected an all sync outcome but got {outcome:?}"),
+        }
+
+        let mut select_datastore = Sqlite::new(SqliteConfig::default()).unwrap();
+        select_datastore
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        match sync(config(), &mut select_datastore).expect("Sync failed") {
+            SyncOutcome::Select { sequences, report } => {
+                assert_eq!(
+                    sequences,
+                    IndexMap::from([(
+                        "marist".to_string(),
+                        SchoolEntry::TermToSequence(IndexMap::from([("202440".to_string(), 6929)]))
+                    )])
+                );
+                assert!(report.applied > 0);
+            }
+            outcome => panic!("Expected a select sync outcome but got {outcome:?}"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn idempotency_keys_are_kept_across_retries() {
+        let mut server = mockito::Server::new();
+        let mut first_request = SelectSync::new();
+        first_request
+            .add_term_sync("marist".to_string(), "202440".to_string(), 0)
+            .unwrap();
+        let mut next_request = SelectSync::new();
+        next_request
+            .add_term_sync("marist".to_string(), "202440".to_string(), 6929)
+            .unwrap();
+        let key = idempotency_key().unwrap();
+        assert_ne!(key, idempotency_key().unwrap());
+        assert_eq!(&key[14..15], "4", "{key} is a version 4 uuid");
+        let any_key = || {
+            mockito::Matcher::Regex(
+                "^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$".to_string(),
+            )
+        };
+
+        let keys = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let keeping_key = |body: String| {
+            let keys = keys.clone();
+            move |request: &mockito::Request| {
+                let key = request.header(IDEMPOTENCY_KEY)[0].to_str().unwrap();
+                keys.lock().unwrap().push(key.to_string());
+                body.clone().into_bytes()
+            }
+        };
+
+        let mut first_page: TermSyncResult =
+            from_str(&load_select_sync_data("test-syncs/maristterms/202440.json")).unwrap();
+        first_page.any_has_more = true;
+        let last_page = TermSyncResult {
+            new_sync_term_sequences: first_page.new_sync_term_sequences.clone(),
+            sync_data: vec![],
+            any_has_more: false,
+        };
+        // the first page fails once and is retried with the same key
+        let failed = server
+            .mock("POST", "/sync/schools")
+            .match_header(IDEMPOTENCY_KEY, any_key())
+            .match_body(mockito::Matcher::Json(
+                serde_json::to_value(&first_request).unwrap(),
+            ))
+            .with_status(503)
+            .with_body_from_request(keeping_key(String::new()))
+            .expect(1)
+            .create();
+        let retried = server
+            .mock("POST", "/sync/schools")
+            .match_header(IDEMPOTENCY_KEY, any_key())
+            .match_body(mockito::Matcher::Json(
+                serde_json::to_value(&first_request).unwrap(),
+            ))
+            .with_header("content-type", "application/json")
+            .with_body_from_request(keeping_key(serde_json::to_string(&first_page).unwrap()))
+            .create();
+        let next = server
+            .mock("POST", "/sync/schools")
+            .match_header(IDEMPOTENCY_KEY, any_key())
+            .match_body(mockito::Matcher::Json(
+                serde_json::to_value(&next_request).unwrap(),
+            ))
+            .with_header("content-type", "application/json")
+            .with_body_from_request(keeping_key(serde_json::to_string(&last_page).unwrap()))
+            .create();
+        let mut sqlite_datastore = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        let url = server.url();
+        let config = || SyncConfig {
+            uri: url.clone(),
+            ..Default::default()
+        };
+        sync(config(), &mut sqlite_datastore).expect("Sync failed");
+        failed.assert();
+        retried.assert();
+        next.assert();
+        let keys = keys.lock().unwrap().clone();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+
+        let without_key = server
+            .mock("POST", "/sync/schools")
+            .match_header(IDEMPOTENCY_KEY, mockito::Matcher::Missing)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&last_page).unwrap())
+            .create();
+        sync(
+            SyncConfig {
+                idempotency_keys: false,
+                ..config()
+            },
+            &mut sqlite_datastore,
+        )
+        .expect("Sync failed");
+        without_key.assert();
+
+        // a page that keeps failing is given up on after the last attempt
+        let mut failing_server = mockito::Server::new();
+        let always_failing = failing_server
+            .mock("POST", "/sync/schools")
+            .with_status(503)
+            .expect(SELECT_ATTEMPTS)
+            .create();
+        let failed = sync(
+            SyncConfig {
+                uri: failing_server.url(),
+                ..Default::default()
+            },
+            &mut sqlite_datastore,
+        );
+        assert!(matches!(failed, Err(Error::NetworkError(_))));
+        always_failing.assert();
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn unrequested_schools_are_not_kept() {
+        let mut server = mockito::Server::new();
+        let mut response: TermSyncResult =
+            from_str(&load_select_sync_data("test-syncs/maristterms/202440.json")).unwrap();
+        response.new_sync_term_sequences.insert(
+            "vassar".to_string(),
+            sync_requests::SchoolEntry::Sequence(50),
+        );
+        server
+            .mock("POST", "/sync/schools")
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&response).unwrap())
+            .create();
+        let mut sqlite_datastore = Sqlite::new(SqliteConfig::default()).unwrap();
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::from_input("marist,202440").unwrap())
+            .unwrap();
+        let synced = |sqlite_datastore: &mut Sqlite, on_unrequested_school| {
+            sync(
+                SyncConfig {
+                    uri: server.url(),
+                    on_unrequested_school,
+                    ..Default::default()
+                },
+                sqlite_datastore,
+            )
+            .map(SyncOutcome::into_report)
+        };
+
+        assert!(matches!(
+            synced(&mut sqlite_datastore, UnrequestedPolicy::Error),
+            Err(Error::UnrequestedSchools { message }) if message == "`vassar`"
+        ));
+        let report = synced(&mut sqlite_datastore, UnrequestedPolicy::Warn).unwrap();
+        assert!(matches!(
+            report.warnings.as_slice(),
+            [Warning::UnrequestedSchools { .. }]
+        ));
+        let kept: u64 = sqlite_datastore
+            .raw_query_scalar(
+                "SELECT COUNT(*) FROM _previous_school_collections WHERE school_id = 'vassar'",
+            )
+            .unwrap();
+        assert_eq!(kept, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn only_targeted_terms_are_requested() {
+        let mut server = mockito::Server::new();
+        let mut select_sync = SelectSync::new();
+        select_sync
+            .add_term_sync("marist".to_string(), "202440".to_string(), 0)
+            .unwrap();
+        let targeted = server
+            .mock("POST", "/sync/schools")
+            .match_body(mockito::Matcher::Json(
+                serde_json::to_value(&select_sync).unwrap(),
+            ))
+            .with_header("content-type", "application/json")
+            .with_body(load_select_sync_data("test-syncs/maristterms/202440.json"))
+            .create();
+
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::from_input("marist,202440,202540").unwrap())
+            .unwrap();
+        let targets = |input: &str| SyncConfig {
+            uri: server.url(),
+            only_targets: Some(SelectSyncOptions::from_input(input).unwrap()),
+            ..Default::default()
+        };
+        sync(targets("marist,202440"), &mut *sqlite_datastore).expect("Sync failed");
+        targeted.assert();
+
+        let SyncOptions::Select(options) = sqlite_datastore.generate_sync_options().unwrap() else {
+            panic!("Expected select sync");
+        };
+        let SchoolEntry::TermToSequence(terms) = &options.get_schools()["marist"] else {
+            panic!("Expected marist to be synced by term");
+        };
+        assert_eq!(terms["202440"], 6929);
+        assert_eq!(terms["202540"], 0, "the other term is left for later");
+
+        for not_set in ["marist,201940", "temple"] {
+            assert!(matches!(
+                sync(targets(not_set), &mut *sqlite_datastore),
+                Err(Error::InputParseError { .. })
+            ));
+        }
+    }
+
+    /// Collects every span with its fields so tests can assert on the instrumentation
+    #[cfg(feature = "tracing")]
+    #[derive(Default, Clone)]
+    struct SpanCapture {
+        spans: std::sync::Arc<
+            std::sync::Mutex<Vec<(&'static tracing::Metadata<'static>, SpanFields)>>,
+        >,
+        entered: std::sync::Arc<std::sync::Mutex<Vec<tracing::span::Id>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct SpanFields(std::collections::HashMap<String, String>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for SpanFields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanCapture {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = SpanFields::default();
+            span.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata(), fields));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut spans[span.into_u64() as usize - 1].1);
+        }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, _: &tracing::Event<'_>) {}
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.clone());
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+
+        fn current_span(&self) -> tracing_core::span::Current {
+            match self.entered.lock().unwrap().last() {
+                Some(span) => {
+                    let metadata = self.spans.lock().unwrap()[span.into_u64() as usize - 1].0;
+                    tracing_core::span::Current::new(span.clone(), metadata)
+                }
+                None => tracing_core::span::Current::none(),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "sqlite", feature = "tracing"))]
+    fn sync_is_traced() {
+        let mut server = mockito::Server::new();
+        mock_all_sync_page(
+            &mut server,
+            0,
+            load_all_sync_data("test-syncs/maristfall2024/01.json"),
+        );
+        mock_all_sync_page(&mut server, 6303, last_all_sync_page(6303));
+        let mut sqlite_datastore =
+            get_datastore(DatastoreOptions::default()).expect("Could not get sqlite data store");
+        sqlite_datastore
+            .set_request_sync_resources(SyncResources::Everything(None))
+            .unwrap();
+
+        let capture = SpanCapture::default();
+        let report = tracing::subscriber::with_default(capture.clone(), || {
+            sync(
+                SyncConfig {
+                    uri: server.url(),
+                    ..Default::default()
+                },
+                &mut *sqlite_datastore,
+            )
+        })
+        .expect("Sync failed")
+        .into_report();
+
+        let spans = capture.spans.lock().unwrap();
+        let named = |name: &str| {
+            spans
+                .iter()
+                .filter(|(metadata, _)| metadata.name() == name)
+                .map(|(_, fields)| &fields.0)
+                .collect::<Vec<_>>()
+        };
+        let sync_spans = named("sync");
+        assert_eq!(sync_spans.len(), 1);
+        assert_eq!(sync_spans[0]["mode"], "all");
+        assert_eq!(sync_spans[0]["applied"], report.applied.to_string());
+
+        let pages = named("sync_page");
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[1]["page"], "2");
+
+        let applies = named("execute_all_request_sync");
+        assert_eq!(applies.len(), 2);
+        assert_eq!(applies[0]["records"], "6303");
+        assert_eq!(applies[0]["applied"], report.applied.to_string());
+        assert_eq!(applies[1]["records"], "0");
     }
 }